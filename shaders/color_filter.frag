@@ -0,0 +1,70 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+#include "colormap.glsl"
+in vec4 gl_FragCoord;
+layout(binding = 0) uniform Parameters {
+	// See `ColorFilterMode`
+	int mode;
+	// Luminance threshold for the high contrast mode
+	float threshold;
+};
+layout(binding = 1) uniform sampler2D inputTex;
+layout(location = 0) out vec4 color;
+
+const int MODE_NONE = 0;
+const int MODE_GRAYSCALE = 1;
+const int MODE_INVERTED = 2;
+const int MODE_HIGH_CONTRAST = 3;
+const int MODE_FALSE_COLOR = 4;
+const int MODE_DEUTERANOPIA = 5;
+const int MODE_PROTANOPIA = 6;
+const int MODE_TRITANOPIA = 7;
+
+float luminance(vec3 rgb) {
+	// bt709 luma coefficients, same as the yuyv conversion
+	return dot(rgb, vec3(0.2126, 0.7152, 0.0722));
+}
+
+// Daltonization, see "Analysis of Color Blindness" by Onur Fidaner, Poliang Lin
+// and Nevran Ozguven. We simulate how the image is seen with the given deficiency,
+// and shift the information that is lost into the channels that can still be seen.
+vec3 daltonize(vec3 rgb, int deficiency) {
+	vec3 lms = vec3(
+		dot(rgb, vec3(17.8824, 43.5161, 4.11935)),
+		dot(rgb, vec3(3.45565, 27.1554, 3.86714)),
+		dot(rgb, vec3(0.0299566, 0.184309, 1.46709))
+	);
+	vec3 sim;
+	if (deficiency == MODE_PROTANOPIA) {
+		sim = vec3(2.02344 * lms.y - 2.52581 * lms.z, lms.y, lms.z);
+	} else if (deficiency == MODE_DEUTERANOPIA) {
+		sim = vec3(lms.x, 0.494207 * lms.x + 1.24827 * lms.z, lms.z);
+	} else {
+		sim = vec3(lms.x, lms.y, -0.395913 * lms.x + 0.801109 * lms.y);
+	}
+	vec3 sim_rgb = vec3(
+		dot(sim, vec3(0.0809444479, -0.130504409, 0.116721066)),
+		dot(sim, vec3(-0.0102485335, 0.0540193266, -0.113614708)),
+		dot(sim, vec3(-0.000365296938, -0.00412161469, 0.693511405))
+	);
+	vec3 err = rgb - sim_rgb;
+	vec3 shift = vec3(0.0, 0.7 * err.r + err.g, 0.7 * err.r + err.b);
+	return clamp(rgb + shift, 0.0, 1.0);
+}
+
+void main() {
+	vec4 src = texelFetch(inputTex, ivec2(gl_FragCoord.xy), 0);
+	vec3 rgb = src.rgb;
+	if (mode == MODE_GRAYSCALE) {
+		rgb = vec3(luminance(rgb));
+	} else if (mode == MODE_INVERTED) {
+		rgb = vec3(1.0) - rgb;
+	} else if (mode == MODE_HIGH_CONTRAST) {
+		rgb = vec3(step(threshold, luminance(rgb)));
+	} else if (mode == MODE_FALSE_COLOR) {
+		rgb = turbo(luminance(rgb));
+	} else if (mode >= MODE_DEUTERANOPIA) {
+		rgb = daltonize(rgb, mode);
+	}
+	color = vec4(rgb, src.a);
+}
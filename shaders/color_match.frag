@@ -0,0 +1,16 @@
+#version 450
+// Per-eye colour transform, so both cameras look the same, see `color_match.rs`.
+in vec4 gl_FragCoord;
+layout(binding = 0) uniform Parameters {
+	// Per channel gain and offset of the left and right eye, alpha is unused
+	vec4 gain[2];
+	vec4 offset[2];
+};
+layout(binding = 1) uniform sampler2D inputTex;
+layout(location = 0) out vec4 color;
+
+void main() {
+	int eye = gl_FragCoord.x < float(textureSize(inputTex, 0).x / 2) ? 0 : 1;
+	vec4 c = texelFetch(inputTex, ivec2(gl_FragCoord.xy), 0);
+	color = vec4(clamp(c.rgb * gain[eye].rgb + offset[eye].rgb, 0.0, 1.0), c.a);
+}
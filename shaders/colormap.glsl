@@ -0,0 +1,18 @@
+// Polynomial approximation of the "turbo" colormap, from
+// https://ai.googleblog.com/2019/08/turbo-improved-rainbow-colormap-for.html
+vec3 turbo(float x) {
+	const vec4 kRed4 = vec4(0.13572138, 4.61539260, -42.66032258, 132.13108234);
+	const vec4 kGreen4 = vec4(0.09140261, 2.19418839, 4.84296658, -14.18503333);
+	const vec4 kBlue4 = vec4(0.10667330, 12.64194608, -60.58204836, 110.36276771);
+	const vec2 kRed2 = vec2(-152.94239396, 59.28637943);
+	const vec2 kGreen2 = vec2(4.27729857, 2.82956604);
+	const vec2 kBlue2 = vec2(-89.90310912, 27.34824973);
+	x = clamp(x, 0.0, 1.0);
+	vec4 v4 = vec4(1.0, x, x * x, x * x * x);
+	vec2 v2 = v4.zw * v4.z;
+	return vec3(
+		dot(v4, kRed4) + dot(v2, kRed2),
+		dot(v4, kGreen4) + dot(v2, kGreen2),
+		dot(v4, kBlue4) + dot(v2, kBlue2)
+	);
+}
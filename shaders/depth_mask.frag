@@ -0,0 +1,45 @@
+#version 450
+// Only keep pixels closer than `distance`, by writing the undistorted camera image with
+// an alpha mask based on the estimated depth.
+in vec4 gl_FragCoord;
+layout(binding = 0) uniform sampler2D inputTex;
+layout(binding = 1) uniform sampler2D depthTex;
+layout(binding = 2) uniform Parameters {
+	// Camera space to the rectified camera space of the depth map, 0 = left, 1 = right
+	mat4 cameraToRect[2];
+	// Field-of-view parameters of the undistorted images, left in xy, right in zw
+	vec4 fov;
+	// Field-of-view parameter of the depth map
+	float rectFov;
+	// Pixels further than this are hidden, in meters
+	float distance;
+	// Pixels fade out over this range before `distance`, in meters
+	float fade;
+};
+layout(location = 0) out vec4 color;
+
+void main() {
+	// The output has the same size as the input
+	vec2 coord = gl_FragCoord.xy / vec2(textureSize(inputTex, 0));
+	vec4 src = texelFetch(inputTex, ivec2(gl_FragCoord.xy), 0);
+	// Each eye is a square image
+	int eye = coord.x >= 0.5 ? 1 : 0;
+	vec2 uv = vec2(coord.x * 2.0 - float(eye), coord.y);
+	vec2 f = eye == 0 ? fov.xy : fov.zw;
+	vec3 ray = vec3((uv.x - 0.5) / f.x, (0.5 - uv.y) / f.y, -1.0);
+	vec3 q = (cameraToRect[eye] * vec4(ray, 0.0)).xyz;
+
+	float alpha = 0.0;
+	if (q.z < 0.0) {
+		vec2 rect = vec2(rectFov * q.x / -q.z + 0.5, 0.5 - rectFov * q.y / -q.z);
+		if (all(greaterThanEqual(rect, vec2(0.0))) && all(lessThanEqual(rect, vec2(1.0)))) {
+			float d = texture(depthTex, vec2((rect.x + float(eye)) * 0.5, rect.y)).r;
+			if (d > 0.0) {
+				// Depth is along the rectified Z axis, convert it to distance from the camera
+				float dist = d * length(q) / -q.z;
+				alpha = 1.0 - smoothstep(distance - fade, distance, dist);
+			}
+		}
+	}
+	color = vec4(src.rgb, src.a * alpha);
+}
@@ -0,0 +1,23 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+#include "colormap.glsl"
+in vec4 gl_FragCoord;
+layout(binding = 0) uniform sampler2D depthTex;
+layout(binding = 1) uniform Parameters {
+	// Depth range mapped to the colormap, in meters
+	float near;
+	float far;
+	// Size of the output image, the depth map is smaller
+	vec2 outputSize;
+};
+layout(location = 0) out vec4 color;
+
+void main() {
+	float d = texture(depthTex, gl_FragCoord.xy / outputSize).r;
+	if (d <= 0.0) {
+		color = vec4(0.0, 0.0, 0.0, 1.0);
+	} else {
+		// Near is red, far is blue
+		color = vec4(turbo(1.0 - (d - near) / (far - near)), 1.0);
+	}
+}
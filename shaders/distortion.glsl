@@ -0,0 +1,53 @@
+// Lens distortion models, see `DistortionModel`.
+// Must be kept in sync with `distortion_correction::distort`.
+
+// Equidistant fisheye (FTheta) and Kannala-Brandt
+const int MODEL_FISHEYE = 0;
+// Brown-Conrady radial-tangential, with the optional rational terms
+const int MODEL_PLUMB_BOB = 1;
+// Unified omnidirectional camera model, with radial-tangential distortion
+const int MODEL_UNIFIED = 2;
+
+// Tangential distortion of `p`
+vec2 tangential(vec2 p, float r2, float p1, float p2) {
+	return vec2(
+		2.0 * p1 * p.x * p.y + p2 * (r2 + 2.0 * p.x * p.x),
+		p1 * (r2 + 2.0 * p.y * p.y) + 2.0 * p2 * p.x * p.y
+	);
+}
+
+// Map undistorted normalized image coordinates (x/z, y/z) to distorted normalized
+// image coordinates. `c0` and `c1` are the 8 distortion coefficients.
+vec2 distort(int model, vec4 c0, vec4 c1, vec2 p) {
+	if (model == MODEL_PLUMB_BOB) {
+		// k1, k2, p1, p2, k3, k4, k5, k6
+		float r2 = dot(p, p);
+		float radial = (1.0 + r2 * (c0.x + r2 * (c0.y + r2 * c1.x))) /
+			(1.0 + r2 * (c1.y + r2 * (c1.z + r2 * c1.w)));
+		return p * radial + tangential(p, r2, c0.z, c0.w);
+	} else if (model == MODEL_UNIFIED) {
+		// xi, k1, k2, p1, p2
+		vec2 m = p / (1.0 + c0.x * sqrt(dot(p, p) + 1.0));
+		float r2 = dot(m, m);
+		float radial = 1.0 + r2 * (c0.y + r2 * c0.z);
+		return m * radial + tangential(m, r2, c0.w, c1.x);
+	} else {
+		// k1 ... k8
+		float r = length(p);
+		if (r == 0.0) {
+			return p;
+		}
+		float theta = atan(r);
+		float theta2 = theta * theta;
+		float poly = c1.w;
+		poly = c1.z + theta2 * poly;
+		poly = c1.y + theta2 * poly;
+		poly = c1.x + theta2 * poly;
+		poly = c0.w + theta2 * poly;
+		poly = c0.z + theta2 * poly;
+		poly = c0.y + theta2 * poly;
+		poly = c0.x + theta2 * poly;
+		float thetaD = theta * (1.0 + theta2 * poly);
+		return thetaD / r * p;
+	}
+}
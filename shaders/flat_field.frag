@@ -0,0 +1,31 @@
+#version 450
+// Multiply the side-by-side camera image with the gain map, see `flat_field.rs`.
+in vec4 gl_FragCoord;
+// Gains of both eyes side by side, smaller than the camera image
+layout(binding = 0) uniform sampler2D gainTex;
+layout(binding = 1) uniform sampler2D inputTex;
+layout(location = 0) out vec4 color;
+
+// Bilinear interpolation with texelFetch, float textures are not always filterable
+float gain(vec2 uv) {
+	ivec2 size = textureSize(gainTex, 0);
+	// Don't blend the two eyes at the seam
+	int eye = uv.x < 0.5 ? 0 : 1;
+	int eyeWidth = size.x / 2;
+	vec2 p = uv * vec2(size) - 0.5;
+	ivec2 p0 = ivec2(floor(p));
+	vec2 f = p - vec2(p0);
+	ivec2 lo = ivec2(eye * eyeWidth, 0);
+	ivec2 hi = ivec2(eye * eyeWidth + eyeWidth - 1, size.y - 1);
+	float g00 = texelFetch(gainTex, clamp(p0, lo, hi), 0).r;
+	float g10 = texelFetch(gainTex, clamp(p0 + ivec2(1, 0), lo, hi), 0).r;
+	float g01 = texelFetch(gainTex, clamp(p0 + ivec2(0, 1), lo, hi), 0).r;
+	float g11 = texelFetch(gainTex, clamp(p0 + ivec2(1, 1), lo, hi), 0).r;
+	return mix(mix(g00, g10, f.x), mix(g01, g11, f.x), f.y);
+}
+
+void main() {
+	vec2 uv = gl_FragCoord.xy / vec2(textureSize(inputTex, 0));
+	vec4 c = texelFetch(inputTex, ivec2(gl_FragCoord.xy), 0);
+	color = vec4(c.rgb * gain(uv), c.a);
+}
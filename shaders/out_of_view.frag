@@ -0,0 +1,39 @@
+#version 450
+// Border and arrow drawn over an overlay the cameras can't see enough of, see
+// `OutOfViewAction::Indicator`. The arrow points from the centre of the overlay towards the
+// part the cameras can see.
+layout(binding = 0) uniform Indicator {
+	// In overlay space, y up. No arrow if it is zero.
+	vec2 direction;
+	// Height of the overlay divided by its width
+	float aspect;
+};
+layout(location = 0) in vec2 uv;
+layout(location = 0) out vec4 color;
+
+// All in fractions of the overlay width
+const float BORDER_WIDTH = 0.02;
+const float ARROW_LENGTH = 0.3;
+const float SHAFT_WIDTH = 0.04;
+const float HEAD_SIZE = 0.1;
+
+void main() {
+	// Position relative to the centre of the overlay, y up
+	vec2 p = (uv - 0.5) * vec2(1.0, -aspect);
+	vec2 edge = vec2(0.5, 0.5 * aspect) - abs(p);
+	bool inside = min(edge.x, edge.y) < BORDER_WIDTH;
+	if (length(direction) > 0.0) {
+		vec2 dir = normalize(direction);
+		float along = dot(p, dir);
+		float across = abs(dot(p, vec2(-dir.y, dir.x)));
+		float tip = ARROW_LENGTH / 2.0;
+		float headBase = tip - HEAD_SIZE;
+		bool shaft = along > -tip && along < headBase && across < SHAFT_WIDTH / 2.0;
+		bool head = along >= headBase && along < tip && across < tip - along;
+		inside = inside || shaft || head;
+	}
+	if (!inside) {
+		discard;
+	}
+	color = vec4(1.0, 0.8, 0.2, 1.0);
+}
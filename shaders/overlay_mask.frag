@@ -0,0 +1,39 @@
+#version 450
+// Shape and opacity of the overlay window, see `OverlayMaskConfig`. Drawn over the finished
+// overlay image, the blend state keeps its colour and multiplies its alpha by ours.
+layout(binding = 0) uniform Parameters {
+	// 0: rectangle, 1: circle, 2: rounded rectangle, 3: image
+	int shape;
+	// Width of the soft edge, as a fraction of the overlay width
+	float feather;
+	// Only used by the rounded rectangle, as a fraction of the overlay width
+	float cornerRadius;
+	float opacity;
+	// Height of the overlay divided by its width
+	float aspect;
+};
+layout(binding = 1) uniform sampler2D maskTex;
+layout(location = 0) in vec2 uv;
+layout(location = 0) out vec4 color;
+
+void main() {
+	// Signed distance to the edge of the shape, negative inside. In fractions of the
+	// overlay width, so shapes keep their proportions on non-square overlays.
+	vec2 halfSize = vec2(0.5, 0.5 * aspect);
+	vec2 p = abs(uv - 0.5) * vec2(1.0, aspect);
+	float dist;
+	if (shape == 1) {
+		dist = length(p) - min(halfSize.x, halfSize.y);
+	} else if (shape == 2) {
+		float radius = min(cornerRadius, min(halfSize.x, halfSize.y));
+		vec2 q = p - (halfSize - radius);
+		dist = length(max(q, 0.0)) + min(max(q.x, q.y), 0.0) - radius;
+	} else {
+		dist = max(p.x - halfSize.x, p.y - halfSize.y);
+	}
+	float alpha = feather > 0.0 ? smoothstep(0.0, feather, -dist) : float(dist <= 0.0);
+	if (shape == 3) {
+		alpha *= texture(maskTex, uv).r;
+	}
+	color = vec4(0.0, 0.0, 0.0, alpha * opacity);
+}
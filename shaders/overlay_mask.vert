@@ -0,0 +1,8 @@
+#version 450
+// Covers the viewport, drawn as a 4 vertex triangle strip without a vertex buffer
+layout(location = 0) out vec2 uv;
+
+void main() {
+	uv = vec2(gl_VertexIndex >> 1, gl_VertexIndex & 1);
+	gl_Position = vec4(uv * 2.0 - 1.0, 0, 1);
+}
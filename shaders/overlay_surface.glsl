@@ -0,0 +1,11 @@
+// Position on the overlay surface, in overlay space. `pos` is the position on the
+// overlay image, in meters from its centre, y up. If `radius` is not 0, the overlay is
+// bent into a section of a vertical cylinder with that radius, curving towards the
+// viewer, the same way OpenVR curved overlays and OpenXR cylinder layers are.
+vec3 overlaySurface(vec2 pos, float radius) {
+	if (radius <= 0.0) {
+		return vec3(pos, 0.0);
+	}
+	float angle = pos.x / radius;
+	return vec3(radius * sin(angle), pos.y, radius * (1.0 - cos(angle)));
+}
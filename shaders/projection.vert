@@ -1,18 +1,23 @@
 #version 450
+#extension GL_GOOGLE_include_directive : require
+#include "overlay_surface.glsl"
 layout(location = 0) in vec2 position;
 layout(binding = 0) uniform Transform {
 	mat4 mvp;
-	float overlayWidth;
+	// Width and height of the overlay, in meters
+	vec2 overlaySize;
 	vec2 eyeOffset;
+	// 0 if the overlay is flat, see `overlaySurface`
+	float overlayRadius;
 };
 layout(location = 1) out vec3 texCoord;
 
 void main() {
-	vec2 pos = (position + eyeOffset) * overlayWidth / 2.0;
+	vec2 pos = (position + eyeOffset) * overlaySize / 2.0;
 	// Change coordinate system: mvp is y up, position is y down
 	pos.y = -pos.y;
 
-	vec4 coord = mvp * vec4(pos, 0, 1);
+	vec4 coord = mvp * vec4(overlaySurface(pos, overlayRadius), 1);
 	texCoord = coord.xyz;
 
 	gl_Position = vec4(position, 0, 1);
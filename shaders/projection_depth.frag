@@ -0,0 +1,91 @@
+#version 450
+// Per-pixel reprojection: march along the ray from the eye through the overlay, find
+// where it hits the surface seen by the camera, and sample the camera image there.
+//
+// All calculations are done in the camera space of this eye's camera.
+layout(binding = 1) uniform sampler2D tex;
+layout(binding = 2) uniform DepthInfo {
+	// Camera space to the rectified camera space of the depth map
+	mat4 cameraToRect;
+	// Position of the eye, w is unused
+	vec4 eyePosition;
+	// Field-of-view parameters of the camera image
+	vec2 fov;
+	// Offset of this eye in the side-by-side textures, 0 or 0.5
+	float texOffset;
+	// Field-of-view parameter of the depth map
+	float rectFov;
+};
+// Depth in meters, side-by-side, 0 means unknown. See `StereoDepth`
+layout(binding = 3) uniform sampler2D depthTex;
+layout(location = 0) in vec4 gl_FragCoord;
+layout(location = 1) in noperspective vec3 cameraPosition;
+layout(location = 0) out vec4 color;
+
+// Search range along the ray, in meters
+const float NEAR = 0.1;
+const float FAR = 10.0;
+const int STEPS = 64;
+const int REFINE_STEPS = 6;
+
+// Texture coordinates of a camera space point, in a single eye image with the given
+// field-of-view parameters. Returns false if the point is not in front of the camera.
+bool project(vec3 p, vec2 f, out vec2 uv) {
+	if (p.z >= 0.0) {
+		return false;
+	}
+	uv = vec2(f.x * p.x / -p.z + 0.5, 0.5 - f.y * p.y / -p.z);
+	return all(greaterThanEqual(uv, vec2(0.0))) && all(lessThanEqual(uv, vec2(1.0)));
+}
+
+// How far `p` is behind the surface seen by the camera, in meters. Negative if `p` is in
+// front of the surface. Returns false if the depth at `p` is unknown.
+bool behindSurface(vec3 p, out float distance) {
+	vec3 q = (cameraToRect * vec4(p, 0.0)).xyz;
+	vec2 uv;
+	if (!project(q, vec2(rectFov), uv)) {
+		return false;
+	}
+	float d = texture(depthTex, vec2(uv.x * 0.5 + texOffset, uv.y)).r;
+	if (d <= 0.0) {
+		return false;
+	}
+	distance = -q.z - d;
+	return true;
+}
+
+void main() {
+	vec3 origin = eyePosition.xyz;
+	vec3 dir = normalize(cameraPosition - origin);
+
+	// If no surface is found, assume the scene is on the overlay plane
+	vec3 hit = cameraPosition;
+	// Steps are spaced logarithmically, so we have more precision up close
+	float prev = NEAR;
+	for (int i = 0; i < STEPS; i++) {
+		float t = NEAR * pow(FAR / NEAR, float(i) / float(STEPS - 1));
+		float distance;
+		if (behindSurface(origin + dir * t, distance) && distance >= 0.0) {
+			// Binary search between the last point in front of the surface and this one
+			float lo = prev, hi = t;
+			for (int j = 0; j < REFINE_STEPS; j++) {
+				float mid = (lo + hi) * 0.5;
+				if (behindSurface(origin + dir * mid, distance) && distance >= 0.0) {
+					hi = mid;
+				} else {
+					lo = mid;
+				}
+			}
+			hit = origin + dir * hi;
+			break;
+		}
+		prev = t;
+	}
+
+	vec2 uv;
+	if (!project(hit, fov, uv)) {
+		color = vec4(0.0, 0.0, 0.0, 0.0);
+	} else {
+		color = texture(tex, vec2(uv.x * 0.5 + texOffset, uv.y));
+	}
+}
@@ -0,0 +1,23 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+#include "overlay_surface.glsl"
+layout(location = 0) in vec2 position;
+layout(binding = 0) uniform DepthTransform {
+	// Overlay space to the camera space of this eye's camera
+	mat4 overlayToCamera;
+	// Width and height of the overlay, in meters
+	vec2 overlaySize;
+	// 0 if the overlay is flat, see `overlaySurface`
+	float overlayRadius;
+};
+// Position on the overlay, in camera space
+layout(location = 1) out vec3 cameraPosition;
+
+void main() {
+	vec2 pos = position * overlaySize / 2.0;
+	// Change coordinate system: overlay space is y up, position is y down
+	pos.y = -pos.y;
+
+	cameraPosition = (overlayToCamera * vec4(overlaySurface(pos, overlayRadius), 1)).xyz;
+	gl_Position = vec4(position, 0, 1);
+}
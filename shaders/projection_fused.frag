@@ -0,0 +1,24 @@
+#version 450
+// Like projection.frag, but tex is the camera image before lens correction. The lens
+// correction is done by looking up where to sample tex in the remap texture of
+// `StereoCorrection`, so the camera image is only resampled once.
+layout(binding = 1) uniform sampler2D tex;
+// Texture coordinates into tex, for every pixel of this eye's undistorted image.
+// These already include the offset of this eye in the side-by-side texture.
+layout(binding = 3) uniform sampler2D lutTex;
+layout(location = 0) in vec4 gl_FragCoord;
+layout(location = 1) in noperspective vec3 texCoord;
+layout(location = 0) out vec4 color;
+
+void main() {
+	vec2 tex_coord = texCoord.xy / texCoord.z;
+	tex_coord = tex_coord + vec2(0.25, 0.5);
+
+	if (tex_coord.x < 0 || tex_coord.x > 0.5) {
+		color = vec4(0.0, 0.0, 0.0, 0.0);
+	} else {
+		// Position in this eye's undistorted image
+		vec2 uv = vec2(tex_coord.x * 2.0, 1.0 - tex_coord.y);
+		color = texture(tex, texture(lutTex, uv).xy);
+	}
+}
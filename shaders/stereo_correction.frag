@@ -1,8 +1,11 @@
 #version 450
+#extension GL_GOOGLE_include_directive : require
+#include "distortion.glsl"
 
 layout(binding = 0) uniform Parameters {
     // Distortion coefficients
     vec4 dcoef;
+    vec4 dcoef2;
     // Optical center
     vec2 center;
     // Focal length in terms of focal divided by sensor_width
@@ -11,6 +14,8 @@ layout(binding = 0) uniform Parameters {
     vec2 scale;
     // Pixel size of the sensor_width
     float sensorSize;
+    // Distortion model, see distortion.glsl
+    int model;
     vec2 texOffset;
 };
 layout(binding = 1) uniform sampler2D inputTex;
@@ -22,17 +27,10 @@ in vec4 gl_FragCoord;
 layout(location = 0) in noperspective vec2 coord;
 layout(location = 0) out vec4 outColor;
 void main() {
-    vec2 r = coord * scale / focal;
-    // Also scale the r so the whole circular region will be included
+    // Also scale the r so the whole field of view will be included
     // in the output.
-    float theta = atan(length(r));
-    float theta2 = theta * theta;
-    theta *= 1 + theta2 * (dcoef.x +
-                 theta2 * (dcoef.y +
-                 theta2 * (dcoef.z +
-                 theta2 * dcoef.w)));
-    // Scale r vector to length theta
-    vec2 mapped = theta / length(r) * r;
+    vec2 r = coord * scale / focal;
+    vec2 mapped = distort(model, dcoef, dcoef2, r);
     mapped *= focal;
     // mapped should now be -0.5~0.5, in inputTex coord
     // move mapped so its centered at `center`
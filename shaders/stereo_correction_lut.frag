@@ -0,0 +1,14 @@
+#version 450
+// Lens correction using a precomputed remap texture, see `StereoCorrection::bake_lookup_table`.
+
+// Texture coordinates into inputTex, for each output pixel of this eye
+layout(binding = 0) uniform sampler2D lutTex;
+layout(binding = 1) uniform sampler2D inputTex;
+
+// Input coordinates -0.5 ~ 0.5, same as stereo_correction.frag
+layout(location = 0) in noperspective vec2 coord;
+layout(location = 0) out vec4 outColor;
+void main() {
+    vec2 mapped = texture(lutTex, coord + 0.5).xy;
+    outColor = texture(inputTex, mapped);
+}
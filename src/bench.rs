@@ -0,0 +1,160 @@
+//! Benchmarks for pipeline stages, run on a headless Vulkan device.
+
+use std::{path::Path, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use vulkano::{
+    command_buffer::{
+        allocator::{CommandBufferAllocator, StandardCommandBufferAllocator},
+        ClearColorImageInfo, CommandBufferBeginInfo, CommandBufferLevel, CommandBufferUsage,
+        RecordingCommandBuffer,
+    },
+    descriptor_set::allocator::StandardDescriptorSetAllocator,
+    device::{Device, DeviceCreateInfo, Queue, QueueCreateInfo, QueueFlags},
+    format::{ClearColorValue, Format},
+    image::{Image, ImageCreateInfo, ImageUsage},
+    instance::{Instance, InstanceCreateInfo},
+    memory::allocator::{MemoryAllocator, MemoryTypeFilter},
+    sync::GpuFuture,
+};
+
+use crate::{
+    cli::BenchCorrection,
+    config::{Config, CorrectionConfig},
+    distortion_correction::StereoCorrection,
+    utils::DeviceExt as _,
+    CAMERA_SIZE,
+};
+
+fn headless_device() -> Result<(Arc<Device>, Arc<Queue>)> {
+    let library = vulkano::VulkanLibrary::new()?;
+    let instance = Instance::new(library, InstanceCreateInfo::default())?;
+    let (physical_device, queue_family) = instance
+        .enumerate_physical_devices()?
+        .find_map(|physical_device| {
+            let queue_family = physical_device
+                .queue_family_properties()
+                .iter()
+                .position(|qf| qf.queue_flags.contains(QueueFlags::GRAPHICS))?;
+            Some((physical_device, queue_family as u32))
+        })
+        .context("No Vulkan device with a graphics queue")?;
+    log::info!("Using device: {}", physical_device.properties().device_name);
+    let (device, mut queues) = Device::new(
+        physical_device,
+        DeviceCreateInfo {
+            queue_create_infos: vec![QueueCreateInfo {
+                queue_family_index: queue_family,
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+    )?;
+    Ok((device, queues.next().unwrap()))
+}
+
+fn clear_image(
+    cmdbuf_allocator: Arc<dyn CommandBufferAllocator>,
+    queue: &Arc<Queue>,
+    image: Arc<Image>,
+) -> Result<()> {
+    let mut cmdbuf = RecordingCommandBuffer::new(
+        cmdbuf_allocator,
+        queue.queue_family_index(),
+        CommandBufferLevel::Primary,
+        CommandBufferBeginInfo {
+            usage: CommandBufferUsage::OneTimeSubmit,
+            ..Default::default()
+        },
+    )?;
+    cmdbuf.clear_color_image(ClearColorImageInfo {
+        clear_value: ClearColorValue::Float([0.5; 4]),
+        ..ClearColorImageInfo::image(image)
+    })?;
+    cmdbuf
+        .end()?
+        .execute(queue.clone())?
+        .then_signal_fence_and_flush()?
+        .wait(None)?;
+    Ok(())
+}
+
+/// Time the lens correction stage, evaluating the lens model per pixel vs. using the
+/// lookup table. Each frame is waited for before the next one is submitted, so the times
+/// include the submission overhead, which is the same for both.
+/// `config_dir` is where a relative `camera_calibration` path is looked up.
+pub(crate) fn bench_correction(
+    args: &BenchCorrection,
+    cfg: &Config,
+    config_dir: &Path,
+) -> Result<()> {
+    let camera_config = crate::calibration::resolve(config_dir, cfg, None)?
+        .context("No camera parameters found")?
+        .0;
+    let (device, queue) = headless_device()?;
+    let allocator: Arc<dyn MemoryAllocator> = Arc::new(device.clone().host_to_device_allocator());
+    let descriptor_set_allocator = Arc::new(StandardDescriptorSetAllocator::new(
+        device.clone(),
+        Default::default(),
+    ));
+    let cmdbuf_allocator: Arc<dyn CommandBufferAllocator> = Arc::new(
+        StandardCommandBufferAllocator::new(device.clone(), Default::default()),
+    );
+    let new_image = |usage| {
+        device.clone().new_image(
+            ImageCreateInfo {
+                extent: [CAMERA_SIZE * 2, CAMERA_SIZE, 1],
+                format: Format::R8G8B8A8_UNORM,
+                usage,
+                ..Default::default()
+            },
+            MemoryTypeFilter::PREFER_DEVICE,
+        )
+    };
+    let input = new_image(ImageUsage::SAMPLED | ImageUsage::TRANSFER_DST)?;
+    let output = new_image(ImageUsage::COLOR_ATTACHMENT)?;
+    clear_image(cmdbuf_allocator.clone(), &queue, input.clone())?;
+
+    for (name, lookup_table) in [("per pixel", false), ("lookup table", true)] {
+        let correction = StereoCorrection::new(
+            device.clone(),
+            allocator.clone(),
+            descriptor_set_allocator.clone(),
+            input.clone(),
+            &camera_config,
+            &CorrectionConfig {
+                lookup_table,
+                ..cfg.correction
+            },
+        )?;
+        let run_frame = || -> Result<Duration> {
+            let start = std::time::Instant::now();
+            let future = correction.correct(
+                cmdbuf_allocator.clone(),
+                allocator.clone(),
+                vulkano::sync::now(device.clone()),
+                &queue,
+                output.clone(),
+            )?;
+            future.flush()?;
+            future.then_signal_fence().wait(None)?;
+            Ok(start.elapsed())
+        };
+        // Warm up, this also uploads the lookup table
+        run_frame()?;
+        let mut total = Duration::ZERO;
+        let mut min = Duration::MAX;
+        for _ in 0..args.frames {
+            let elapsed = run_frame()?;
+            total += elapsed;
+            min = min.min(elapsed);
+        }
+        println!(
+            "{name}: mean {:?}, min {:?} over {} frames",
+            total / args.frames.max(1),
+            min,
+            args.frames
+        );
+    }
+    Ok(())
+}
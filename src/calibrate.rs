@@ -0,0 +1,449 @@
+//! `calibrate` command: calibrate the cameras with a printed checkerboard.
+//!
+//! Views where both cameras see the whole board are captured first. Then for each camera,
+//! the fisheye intrinsics and the pose of the board in every view are solved together with
+//! Levenberg-Marquardt, minimizing the reprojection error. Lastly the pose of the right camera
+//! relative to the left one is solved from the views seen by both cameras.
+//!
+//! The solver works in the usual computer vision camera space (x right, y down, z forward),
+//! which is also what the lens correction shader uses. The result is converted to the Steam
+//! convention, where cameras look at -Z, when it is written out.
+
+use std::path::Path;
+
+use anyhow::{anyhow, ensure, Context, Result};
+use nalgebra::{DMatrix, DVector, Matrix3, Rotation3, Vector2, Vector3};
+
+use crate::{
+    calibration,
+    checkerboard::{self, GrayImage},
+    cli::Calibrate,
+    config::Config,
+    distortion_correction::distort,
+    vrapi::{
+        Camera, Distort, DistortionModel, Extrinsics, Intrinsics, StereoCamera, TrackedCamera,
+    },
+    CAMERA_SIZE,
+};
+
+/// A view is only captured if the board moved at least this much since the last one, in
+/// pixels, so the views are not all the same.
+const MIN_MOVEMENT: f64 = 40.0;
+/// Number of intrinsic parameters: fx, fy, cx, cy, k1..k4
+const NUM_INTRINSICS: usize = 8;
+const MAX_ITERATIONS: usize = 100;
+
+/// Checkerboard corners seen by the left and right cameras, in pixels
+struct View {
+    corners: [Vec<Vector2<f64>>; 2],
+}
+
+/// Capture views of the checkerboard from the camera
+fn capture(args: &Calibrate, cfg: &Config) -> Result<Vec<View>> {
+    let camera = crate::open_camera(&cfg.camera_device)?;
+    let mut video_stream =
+        v4l::prelude::MmapStream::with_buffers(&camera, v4l::buffer::Type::VideoCapture, 1)
+            .context("cannot open camera mmap stream")?;
+    println!(
+        "Hold the checkerboard in front of the cameras, so both of them can see all of it. \
+         Move it around slowly, cover the edges of the view, and tilt it in different directions."
+    );
+    let mut views: Vec<View> = Vec::new();
+    while views.len() < args.views {
+        let (frame_data, _) = v4l::io::traits::CaptureStream::next(&mut video_stream)?;
+        let [Some(left), Some(right)] = [0, 1].map(|eye| {
+            let image = GrayImage::from_yuyv_eye(
+                frame_data,
+                CAMERA_SIZE as usize * 2,
+                CAMERA_SIZE as usize,
+                eye,
+            );
+            let corners = checkerboard::find_corners(&image);
+            checkerboard::find_grid(&corners, args.cols, args.rows)
+                .map(|grid| grid.iter().map(|p| p.cast::<f64>()).collect::<Vec<_>>())
+        }) else {
+            continue;
+        };
+        if let Some(last) = views.last() {
+            let movement = left
+                .iter()
+                .zip(&last.corners[0])
+                .map(|(a, b)| (a - b).norm())
+                .sum::<f64>()
+                / left.len() as f64;
+            if movement < MIN_MOVEMENT {
+                continue;
+            }
+        }
+        views.push(View {
+            corners: [left, right],
+        });
+        println!("Captured view {}/{}", views.len(), args.views);
+    }
+    Ok(views)
+}
+
+/// Minimize the sum of squares of `residuals(params)` with Levenberg-Marquardt, only changing
+/// the parameters where `free` is true. The Jacobian is computed numerically.
+///
+/// Returns the RMS reprojection error, assuming the residuals are pairs of pixel offsets.
+fn levenberg_marquardt(
+    params: &mut DVector<f64>,
+    free: &[bool],
+    residuals: impl Fn(&DVector<f64>) -> DVector<f64>,
+) -> f64 {
+    let free: Vec<usize> = (0..params.len()).filter(|&i| free[i]).collect();
+    let mut r = residuals(params);
+    let mut cost = r.norm_squared();
+    let mut lambda = 1e-3;
+    for _ in 0..MAX_ITERATIONS {
+        let mut jacobian = DMatrix::zeros(r.len(), free.len());
+        for (column, &i) in free.iter().enumerate() {
+            let h = 1e-6 * params[i].abs().max(1.0);
+            let mut p = params.clone();
+            p[i] += h;
+            jacobian.set_column(column, &((residuals(&p) - &r) / h));
+        }
+        let jtj = jacobian.transpose() * &jacobian;
+        let jtr = jacobian.transpose() * &r;
+        let mut improved = None;
+        while lambda < 1e10 {
+            let mut a = jtj.clone();
+            for k in 0..free.len() {
+                a[(k, k)] += lambda * jtj[(k, k)].max(1e-12);
+            }
+            let Some(cholesky) = a.cholesky() else {
+                lambda *= 10.0;
+                continue;
+            };
+            let delta = cholesky.solve(&-&jtr);
+            let mut p = params.clone();
+            for (column, &i) in free.iter().enumerate() {
+                p[i] += delta[column];
+            }
+            let new_r = residuals(&p);
+            let new_cost = new_r.norm_squared();
+            if new_cost < cost {
+                improved = Some(cost - new_cost);
+                *params = p;
+                r = new_r;
+                cost = new_cost;
+                lambda = (lambda / 10.0).max(1e-12);
+                break;
+            }
+            lambda *= 10.0;
+        }
+        match improved {
+            Some(gain) if gain > cost * 1e-10 => (),
+            _ => break,
+        }
+    }
+    (cost * 2.0 / r.len() as f64).sqrt()
+}
+
+/// Project a point in camera space to pixels, with `intrinsics` being fx, fy, cx, cy, k1..k4
+fn project(intrinsics: &[f64], p: &Vector3<f64>) -> Vector2<f64> {
+    let distortion = Distort {
+        model: DistortionModel::FTheta,
+        coeffs: [
+            intrinsics[4],
+            intrinsics[5],
+            intrinsics[6],
+            intrinsics[7],
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+        ],
+    };
+    let [x, y] = distort(&distortion, [p.x / p.z, p.y / p.z]);
+    Vector2::new(
+        intrinsics[0] * x + intrinsics[2],
+        intrinsics[1] * y + intrinsics[3],
+    )
+}
+
+/// Rotation vector and translation stored at `offset` in `params`
+fn pose(params: &DVector<f64>, offset: usize) -> (Rotation3<f64>, Vector3<f64>) {
+    (
+        Rotation3::new(params.fixed_rows::<3>(offset).into_owned()),
+        params.fixed_rows::<3>(offset + 3).into_owned(),
+    )
+}
+
+fn set_pose(params: &mut DVector<f64>, offset: usize, rotation: &Rotation3<f64>, t: &Vector3<f64>) {
+    params
+        .fixed_rows_mut::<3>(offset)
+        .copy_from(&rotation.scaled_axis());
+    params.fixed_rows_mut::<3>(offset + 3).copy_from(t);
+}
+
+/// Reprojection errors of one camera's view of the board
+fn view_residuals<'a>(
+    intrinsics: &'a [f64],
+    (rotation, t): (Rotation3<f64>, Vector3<f64>),
+    board: &'a [Vector3<f64>],
+    corners: &'a [Vector2<f64>],
+) -> impl Iterator<Item = f64> + 'a {
+    board.iter().zip(corners).flat_map(move |(p, corner)| {
+        let error = project(intrinsics, &(rotation * p + t)) - corner;
+        [error.x, error.y]
+    })
+}
+
+/// Initial pose of the board, from the homography between the board and the corners
+/// undistorted with the initial intrinsics.
+fn initial_pose(
+    intrinsics: &[f64],
+    board: &[Vector3<f64>],
+    corners: &[Vector2<f64>],
+) -> Option<(Rotation3<f64>, Vector3<f64>)> {
+    let mut a = DMatrix::zeros(board.len() * 2, 9);
+    for (i, (p, corner)) in board.iter().zip(corners).enumerate() {
+        // Undistort with the equidistant model, ignoring the distortion coefficients
+        let d = Vector2::new(
+            (corner.x - intrinsics[2]) / intrinsics[0],
+            (corner.y - intrinsics[3]) / intrinsics[1],
+        );
+        let theta = d.norm().min(1.5);
+        let n = if theta > 0.0 {
+            d * (theta.tan() / d.norm())
+        } else {
+            d
+        };
+        a.row_mut(i * 2).copy_from_slice(&[
+            p.x,
+            p.y,
+            1.0,
+            0.0,
+            0.0,
+            0.0,
+            -n.x * p.x,
+            -n.x * p.y,
+            -n.x,
+        ]);
+        a.row_mut(i * 2 + 1).copy_from_slice(&[
+            0.0,
+            0.0,
+            0.0,
+            p.x,
+            p.y,
+            1.0,
+            -n.y * p.x,
+            -n.y * p.y,
+            -n.y,
+        ]);
+    }
+    let eigen = (a.transpose() * &a).symmetric_eigen();
+    let smallest = eigen.eigenvalues.argmin().0;
+    let h = eigen.eigenvectors.column(smallest);
+    let h = Matrix3::from_row_iterator(h.iter().copied());
+    let (h1, h2, h3) = (h.column(0), h.column(1), h.column(2));
+    let mut scale = 2.0 / (h1.norm() + h2.norm());
+    if !scale.is_finite() {
+        return None;
+    }
+    if h3.z * scale < 0.0 {
+        // The board must be in front of the camera
+        scale = -scale;
+    }
+    let (r1, r2) = (h1 * scale, h2 * scale);
+    let rotation = Rotation3::from_matrix(&Matrix3::from_columns(&[r1, r2, r1.cross(&r2)]));
+    Some((rotation, h3 * scale))
+}
+
+/// Calibrate one camera, returns the intrinsics followed by the board poses, and the RMS
+/// reprojection error.
+fn calibrate_camera(
+    board: &[Vector3<f64>],
+    views: &[&[Vector2<f64>]],
+) -> Result<(DVector<f64>, f64)> {
+    let size = CAMERA_SIZE as f64;
+    let focal = size / std::f64::consts::PI;
+    let mut params = DVector::zeros(NUM_INTRINSICS + views.len() * 6);
+    params
+        .rows_mut(0, 4)
+        .copy_from_slice(&[focal, focal, size / 2.0, size / 2.0]);
+    for (i, corners) in views.iter().enumerate() {
+        let (rotation, t) = initial_pose(params.as_slice(), board, corners)
+            .context("cannot estimate the checkerboard pose")?;
+        set_pose(&mut params, NUM_INTRINSICS + i * 6, &rotation, &t);
+    }
+    let residuals = |params: &DVector<f64>| {
+        let intrinsics = &params.as_slice()[..NUM_INTRINSICS];
+        DVector::from_iterator(
+            board.len() * views.len() * 2,
+            views.iter().enumerate().flat_map(|(i, corners)| {
+                view_residuals(
+                    intrinsics,
+                    pose(params, NUM_INTRINSICS + i * 6),
+                    board,
+                    corners,
+                )
+            }),
+        )
+    };
+    // Without the distortion first, so the poses settle before the distortion can make up
+    // for them being wrong.
+    let mut free = vec![true; params.len()];
+    free[4..NUM_INTRINSICS].fill(false);
+    levenberg_marquardt(&mut params, &free, residuals);
+    free.fill(true);
+    let rms = levenberg_marquardt(&mut params, &free, residuals);
+    Ok((params, rms))
+}
+
+/// Solve the pose of the right camera relative to the left one. Returns the rotation and
+/// translation from left camera space to right camera space, and the RMS reprojection error.
+fn calibrate_stereo(
+    board: &[Vector3<f64>],
+    views: &[View],
+    left: &DVector<f64>,
+    right: &DVector<f64>,
+) -> (Rotation3<f64>, Vector3<f64>, f64) {
+    // Initial guess from averaging the relative pose in every view
+    let (mut rotation_sum, mut t_sum) = (Matrix3::zeros(), Vector3::zeros());
+    for i in 0..views.len() {
+        let (rl, tl) = pose(left, NUM_INTRINSICS + i * 6);
+        let (rr, tr) = pose(right, NUM_INTRINSICS + i * 6);
+        let relative = rr * rl.inverse();
+        rotation_sum += relative.matrix();
+        t_sum += tr - relative * tl;
+    }
+    let rotation = Rotation3::from_matrix(&rotation_sum);
+    let t = t_sum / views.len() as f64;
+
+    // Relative pose, followed by the board poses seen from the left camera
+    let mut params = DVector::zeros(6 + views.len() * 6);
+    set_pose(&mut params, 0, &rotation, &t);
+    params
+        .rows_mut(6, views.len() * 6)
+        .copy_from(&left.rows(NUM_INTRINSICS, views.len() * 6));
+    let (left_intrinsics, right_intrinsics) = (
+        &left.as_slice()[..NUM_INTRINSICS],
+        &right.as_slice()[..NUM_INTRINSICS],
+    );
+    let residuals = |params: &DVector<f64>| {
+        let (relative, relative_t) = pose(params, 0);
+        DVector::from_iterator(
+            board.len() * views.len() * 4,
+            views.iter().enumerate().flat_map(|(i, view)| {
+                let (rl, tl) = pose(params, 6 + i * 6);
+                view_residuals(left_intrinsics, (rl, tl), board, &view.corners[0]).chain(
+                    view_residuals(
+                        right_intrinsics,
+                        (relative * rl, relative * tl + relative_t),
+                        board,
+                        &view.corners[1],
+                    ),
+                )
+            }),
+        )
+    };
+    let free = vec![true; params.len()];
+    let rms = levenberg_marquardt(&mut params, &free, residuals);
+    let (rotation, t) = pose(&params, 0);
+    (rotation, t, rms)
+}
+
+fn intrinsics(params: &DVector<f64>) -> Intrinsics {
+    let size = CAMERA_SIZE as f64;
+    let mut coeffs = [0.0; 8];
+    coeffs[..4].copy_from_slice(&params.as_slice()[4..NUM_INTRINSICS]);
+    Intrinsics {
+        focal_x: params[0],
+        focal_y: params[1],
+        center_x: params[2],
+        center_y: params[3],
+        width: size,
+        height: size,
+        distort: Distort {
+            model: DistortionModel::FTheta,
+            coeffs,
+        },
+    }
+}
+
+fn extrinsics(rotation: &Matrix3<f64>, position: Vector3<f64>) -> Extrinsics {
+    Extrinsics {
+        position: position.into(),
+        plus_x: rotation.column(0).into_owned().into(),
+        plus_z: rotation.column(2).into_owned().into(),
+    }
+}
+
+fn write_calibration(path: &Path, calib: &StereoCamera) -> Result<()> {
+    let content = if path.extension().is_some_and(|ext| ext == "toml") {
+        toml::to_string_pretty(calib)?
+    } else {
+        serde_json::to_string_pretty(calib)?
+    };
+    std::fs::write(path, content)
+        .with_context(|| anyhow!("Cannot write camera calibration to {}", path.display()))
+}
+
+pub(crate) fn calibrate(args: &Calibrate, cfg: &Config) -> Result<()> {
+    ensure!(
+        args.cols >= 3 && args.rows >= 3,
+        "The checkerboard needs at least 3x3 inner corners"
+    );
+    ensure!(args.square_size > 0.0, "The square size must be positive");
+    ensure!(args.views >= 3, "At least 3 views are needed");
+    let board: Vec<_> = (0..args.rows)
+        .flat_map(|r| (0..args.cols).map(move |c| (r, c)))
+        .map(|(r, c)| Vector3::new(c as f64, r as f64, 0.0) * args.square_size)
+        .collect();
+    let views = capture(args, cfg)?;
+
+    println!("Solving...");
+    let [left, right] = [0, 1].map(|eye| {
+        let corners: Vec<&[Vector2<f64>]> =
+            views.iter().map(|view| &view.corners[eye][..]).collect();
+        calibrate_camera(&board, &corners)
+    });
+    let ((left, left_rms), (right, right_rms)) = (left?, right?);
+    println!("Left camera reprojection error: {left_rms:.3} pixels");
+    println!("Right camera reprojection error: {right_rms:.3} pixels");
+    let (relative, relative_t, stereo_rms) = calibrate_stereo(&board, &views, &left, &right);
+    println!("Stereo reprojection error: {stereo_rms:.3} pixels");
+
+    // Convert to the Steam convention, where the camera looks at -Z with Y up
+    let flip = Matrix3::from_diagonal(&Vector3::new(1.0, -1.0, -1.0));
+    let right_to_left = flip * relative.inverse().matrix() * flip;
+    let right_center = flip * -(relative.inverse() * relative_t);
+    println!("Baseline: {:.1} mm", right_center.norm() * 1000.0);
+    // The checkerboard cannot tell where the cameras are on the HMD, keep the left camera
+    // where Steam thinks it is if we know, otherwise put the cameras around the origin.
+    let (left_rotation, left_position) =
+        crate::steam::find_steam_config(cfg.steam_root.as_deref(), cfg.hmd_serial.as_deref())
+            .map(|steam| {
+                let extrinsics = steam.camera.left.extrinsics;
+                (
+                    extrinsics.rotation().cast::<f64>(),
+                    Vector3::from(extrinsics.position),
+                )
+            })
+            .unwrap_or_else(|| (Matrix3::identity(), right_center / 2.0));
+    let calib = StereoCamera {
+        left: TrackedCamera {
+            extrinsics: extrinsics(&left_rotation, left_position),
+            intrinsics: intrinsics(&left),
+            name: Camera::Left,
+        },
+        right: TrackedCamera {
+            extrinsics: extrinsics(
+                &(left_rotation * right_to_left),
+                left_position - left_rotation * right_center,
+            ),
+            intrinsics: intrinsics(&right),
+            name: Camera::Right,
+        },
+    };
+    calibration::validate(&calib).context("Calibration failed")?;
+    write_calibration(&args.output, &calib)?;
+    println!(
+        "Calibration written to {}, set `camera_calibration` in the config file to use it.",
+        args.output.display()
+    );
+    Ok(())
+}
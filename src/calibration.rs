@@ -0,0 +1,279 @@
+//! Finding the camera calibration to use, and checking it for mistakes.
+//!
+//! A user supplied file (see `Config::camera_calibration`) can be a `StereoCamera` in JSON or
+//! TOML, or a Steam lighthouse `config.json`.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+
+use crate::{
+    config::{Backend, Config},
+    distortion_correction::StereoCorrection,
+    steam::LighthouseConfig,
+    vrapi::{Camera, StereoCamera, TrackedCamera},
+    CAMERA_SIZE,
+};
+
+/// An optical center further than this from the image center is suspicious, relative to
+/// the image size
+const MAX_CENTER_OFFSET: f64 = 0.1;
+/// A focal length smaller than this is suspicious, relative to the image size. The Index
+/// cameras have about a third of the image size.
+const MIN_FOCAL: f64 = 0.1;
+
+/// Where the camera calibration came from
+#[derive(Clone, Debug)]
+pub enum CalibrationSource {
+    /// The `camera_calibration` config option
+    File(PathBuf),
+    /// The Steam config of the HMD, found by the serial number from SteamVR or the config
+    HmdSerial { serial: String, path: PathBuf },
+    /// The most recent Steam config found in the Steam directories
+    SteamScan(PathBuf),
+}
+
+impl std::fmt::Display for CalibrationSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "user file {}", path.display()),
+            Self::HmdSerial { serial, path } => {
+                write!(f, "Steam config of HMD {serial}, {}", path.display())
+            }
+            Self::SteamScan(path) => {
+                write!(f, "Steam config found by scanning, {}", path.display())
+            }
+        }
+    }
+}
+
+/// Find the camera calibration to use. In order of preference: the user file
+/// `cfg.camera_calibration`, relative to `config_dir`; the Steam config of the HMD with serial
+/// number `cfg.hmd_serial` or `hmd_serial`; the most recent Steam config that has camera
+/// calibration in it.
+pub fn resolve(
+    config_dir: &Path,
+    cfg: &Config,
+    hmd_serial: Option<&str>,
+) -> Result<Option<(StereoCamera, CalibrationSource)>> {
+    if let Some(file) = &cfg.camera_calibration {
+        let path = config_dir.join(file);
+        let calib = load(&path)?;
+        return Ok(Some((calib, CalibrationSource::File(path))));
+    }
+    let hmd_serial = cfg.hmd_serial.as_deref().or(hmd_serial);
+    let steam_config = crate::steam::find_steam_config(cfg.steam_root.as_deref(), hmd_serial);
+    Ok(steam_config.map(|config| {
+        let source = if hmd_serial.is_some() {
+            CalibrationSource::HmdSerial {
+                serial: config.serial,
+                path: config.path,
+            }
+        } else {
+            CalibrationSource::SteamScan(config.path)
+        };
+        (config.camera, source)
+    }))
+}
+
+/// Load and validate a calibration file. TOML files are recognized by their extension,
+/// everything else is parsed as JSON.
+pub fn load(path: &Path) -> Result<StereoCamera> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| anyhow!("Cannot read camera calibration file {}", path.display()))?;
+    let calib = if path.extension().is_some_and(|ext| ext == "toml") {
+        toml::from_str(&content)
+            .with_context(|| anyhow!("Invalid camera calibration file {}", path.display()))?
+    } else {
+        let json: serde_json::Value = serde_json::from_str(&content)
+            .with_context(|| anyhow!("{} is not valid JSON", path.display()))?;
+        if json.get("tracked_cameras").is_some() {
+            // Steam lighthouse config
+            let lhconfig: LighthouseConfig = serde_json::from_value(json)
+                .with_context(|| anyhow!("Invalid Steam config file {}", path.display()))?;
+            lhconfig
+                .stereo_camera()
+                .with_context(|| anyhow!("Invalid Steam config file {}", path.display()))?
+        } else {
+            serde_json::from_value(json)
+                .with_context(|| anyhow!("Invalid camera calibration file {}", path.display()))?
+        }
+    };
+    validate(&calib)
+        .with_context(|| anyhow!("Invalid camera calibration in {}", path.display()))?;
+    Ok(calib)
+}
+
+fn validate_camera(camera: &TrackedCamera, expected: Camera) -> Result<()> {
+    ensure!(
+        camera.name == expected,
+        "{expected:?} camera is named {:?}",
+        camera.name
+    );
+    let intrinsics = &camera.intrinsics;
+    let extrinsics = &camera.extrinsics;
+    ensure!(
+        [
+            intrinsics.center_x,
+            intrinsics.center_y,
+            intrinsics.focal_x,
+            intrinsics.focal_y,
+            intrinsics.width,
+            intrinsics.height,
+        ]
+        .into_iter()
+        .chain(intrinsics.distort.coeffs)
+        .chain(extrinsics.position)
+        .chain(extrinsics.plus_x)
+        .chain(extrinsics.plus_z)
+        .all(f64::is_finite),
+        "{expected:?} camera has parameters that are not finite numbers"
+    );
+    ensure!(
+        intrinsics.width == CAMERA_SIZE as f64 && intrinsics.height == CAMERA_SIZE as f64,
+        "{expected:?} camera intrinsics are for a {}x{} image, expected {CAMERA_SIZE}x{CAMERA_SIZE}",
+        intrinsics.width,
+        intrinsics.height
+    );
+    ensure!(
+        intrinsics.focal_x > 0.0 && intrinsics.focal_y > 0.0,
+        "{expected:?} camera focal lengths must be positive"
+    );
+    ensure!(
+        (0.0..=intrinsics.width).contains(&intrinsics.center_x)
+            && (0.0..=intrinsics.height).contains(&intrinsics.center_y),
+        "{expected:?} camera optical center ({}, {}) is outside of the image",
+        intrinsics.center_x,
+        intrinsics.center_y
+    );
+    let distort = &intrinsics.distort;
+    if distort.coeffs != distort.model_coeffs() {
+        log::warn!(
+            "{expected:?} camera: the {:?} distortion model only uses {} coefficients, the rest are ignored",
+            distort.model,
+            distort.model.num_coeffs()
+        );
+    }
+    let norm = |v: [f64; 3]| v.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let [x, z] = [extrinsics.plus_x, extrinsics.plus_z];
+    let cross = [
+        x[1] * z[2] - x[2] * z[1],
+        x[2] * z[0] - x[0] * z[2],
+        x[0] * z[1] - x[1] * z[0],
+    ];
+    ensure!(
+        norm(cross) > 1e-3 * norm(x) * norm(z),
+        "{expected:?} camera extrinsics plus_x and plus_z must be non-zero and not parallel"
+    );
+    Ok(())
+}
+
+/// Check the calibration makes sense, so mistakes in hand written files give clear errors
+/// instead of a broken image.
+pub fn validate(calib: &StereoCamera) -> Result<()> {
+    validate_camera(&calib.left, Camera::Left)?;
+    validate_camera(&calib.right, Camera::Right)?;
+    ensure!(
+        calib.left.extrinsics.position != calib.right.extrinsics.position,
+        "the left and right cameras are at the same position"
+    );
+    Ok(())
+}
+
+/// Look for values that are allowed, but probably wrong. Returns a description of each
+/// problem found.
+pub fn diagnose(calib: &StereoCamera) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if let Err(e) = validate(calib) {
+        warnings.push(format!("{e:#}"));
+    }
+    for camera in [&calib.left, &calib.right] {
+        let name = camera.name;
+        let intrinsics = &camera.intrinsics;
+        for (axis, focal, size) in [
+            ("x", intrinsics.focal_x, intrinsics.width),
+            ("y", intrinsics.focal_y, intrinsics.height),
+        ] {
+            if !(size * MIN_FOCAL..=size).contains(&focal) {
+                warnings.push(format!(
+                    "{name:?} camera focal length {axis} is {focal:.1} pixels, expected between \
+                     {:.0} and the sensor size {size:.0}",
+                    size * MIN_FOCAL
+                ));
+            }
+        }
+        let offset = [
+            intrinsics.center_x - intrinsics.width / 2.0,
+            intrinsics.center_y - intrinsics.height / 2.0,
+        ];
+        if offset[0].hypot(offset[1]) > MAX_CENTER_OFFSET * intrinsics.width {
+            warnings.push(format!(
+                "{name:?} camera optical center ({:.1}, {:.1}) is far from the image center",
+                intrinsics.center_x, intrinsics.center_y
+            ));
+        }
+    }
+    if calib.left.intrinsics.distort == calib.right.intrinsics.distort {
+        warnings.push(
+            "the left and right cameras have identical distortion coefficients, they were \
+             probably not calibrated separately"
+                .to_owned(),
+        );
+    }
+    warnings
+}
+
+/// `dump-calibration` command: print the camera calibration that would be used, where it
+/// came from, and what the lens correction makes of it.
+pub(crate) fn dump_calibration(cfg: &Config, config_dir: &Path) -> Result<()> {
+    let hmd_serial = match cfg.backend {
+        Backend::OpenVR => match crate::openvr::VRSystem::init_background() {
+            Ok(sys) => sys.hmd_serial(),
+            Err(e) => {
+                println!("SteamVR is not available ({e:?}), cannot look up the HMD serial number");
+                None
+            }
+        },
+        Backend::OpenXR => None,
+    };
+    if let Some(serial) = &hmd_serial {
+        println!("HMD serial number: {serial}");
+    }
+    println!("Steam directories:");
+    for root in crate::steam::steam_roots(cfg.steam_root.as_deref()) {
+        println!("  {}", root.display());
+    }
+    println!("Camera calibration found in the Steam config:");
+    for config in crate::steam::find_steam_configs(cfg.steam_root.as_deref()) {
+        println!("  HMD {}: {}", config.serial, config.path.display());
+    }
+    let Some((calib, source)) = resolve(config_dir, cfg, hmd_serial.as_deref())? else {
+        bail!("No camera calibration found");
+    };
+    println!("Using camera calibration from {source}:");
+    println!("{}", serde_json::to_string_pretty(&calib)?);
+
+    let degrees = |fov: f32| (0.5 / fov).atan().to_degrees() * 2.0;
+    let scale_fov = StereoCorrection::scale_fov(&calib, CAMERA_SIZE as f64, &cfg.correction)?;
+    for (name, [x, y]) in ["Left", "Right"].into_iter().zip(scale_fov) {
+        println!(
+            "{name} camera: correction scale {:.3} x {:.3}, adjusted FOV {:.3} x {:.3} \
+             ({:.1}° x {:.1}°)",
+            x.0,
+            y.0,
+            x.1,
+            y.1,
+            degrees(x.1),
+            degrees(y.1)
+        );
+    }
+
+    let warnings = diagnose(&calib);
+    if warnings.is_empty() {
+        println!("No problems found");
+    }
+    for warning in warnings {
+        println!("Warning: {warning}");
+    }
+    Ok(())
+}
@@ -0,0 +1,388 @@
+//! Checkerboard corner detection, used by the `calibrate` command.
+//!
+//! Corners are found as saddle points of the blurred image intensity, and verified by looking
+//! for the alternating dark and bright squares around them. They are then linked into a grid
+//! by walking from each corner to its neighbours, predicting the next corner from the last
+//! step taken. Walking in small steps keeps this working under the strong distortion of the
+//! fisheye lenses.
+use std::collections::HashMap;
+
+use nalgebra::{Matrix2, Vector2};
+
+/// A grayscale image, row-major, values between 0 and 1
+pub struct GrayImage {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<f32>,
+}
+
+/// Corners closer than this to the image border are ignored
+const BORDER: usize = 8;
+/// Radius of the circle sampled around a corner to verify it
+const RING_RADIUS: f32 = 5.0;
+const RING_SAMPLES: usize = 24;
+/// Corner response threshold, relative to the strongest response in the image
+const RESPONSE_THRESHOLD: f32 = 0.05;
+/// Minimum intensity difference between the dark and bright squares around a corner
+const MIN_CONTRAST: f32 = 0.08;
+/// How far a corner can be from where it is predicted, relative to the grid step
+const SEARCH_RADIUS: f32 = 0.35;
+
+impl GrayImage {
+    /// Extract one eye from a side-by-side YUYV frame, using the luma channel only.
+    pub fn from_yuyv_eye(frame: &[u8], frame_width: usize, height: usize, eye: usize) -> Self {
+        let width = frame_width / 2;
+        let data = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (y * frame_width + eye * width + x) * 2))
+            .map(|offset| frame[offset] as f32 / 255.0)
+            .collect();
+        Self {
+            width,
+            height,
+            data,
+        }
+    }
+    fn at(&self, x: usize, y: usize) -> f32 {
+        self.data[y * self.width + x]
+    }
+    /// Bilinear interpolation, coordinates are clamped to the image
+    fn sample(&self, x: f32, y: f32) -> f32 {
+        let x = x.clamp(0.0, (self.width - 1) as f32);
+        let y = y.clamp(0.0, (self.height - 1) as f32);
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+        let top = self.at(x0, y0) * (1.0 - fx) + self.at(x1, y0) * fx;
+        let bottom = self.at(x0, y1) * (1.0 - fx) + self.at(x1, y1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+    /// 5-tap binomial blur
+    fn blur(&self) -> Self {
+        const KERNEL: [f32; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+        let convolve = |get: &dyn Fn(isize) -> f32| {
+            KERNEL
+                .iter()
+                .enumerate()
+                .map(|(i, k)| k * get(i as isize - 2))
+                .sum::<f32>()
+        };
+        let (w, h) = (self.width as isize, self.height as isize);
+        let horizontal: Vec<f32> = (0..h)
+            .flat_map(|y| (0..w).map(move |x| (x, y)))
+            .map(|(x, y)| convolve(&|d| self.at((x + d).clamp(0, w - 1) as usize, y as usize)))
+            .collect();
+        let data = (0..h)
+            .flat_map(|y| (0..w).map(move |x| (x, y)))
+            .map(|(x, y)| convolve(&|d| horizontal[((y + d).clamp(0, h - 1) * w + x) as usize]))
+            .collect();
+        Self {
+            width: self.width,
+            height: self.height,
+            data,
+        }
+    }
+    fn gradient(&self, x: usize, y: usize) -> Vector2<f32> {
+        Vector2::new(
+            (self.at(x + 1, y) - self.at(x - 1, y)) / 2.0,
+            (self.at(x, y + 1) - self.at(x, y - 1)) / 2.0,
+        )
+    }
+    fn hessian(&self, x: usize, y: usize) -> Matrix2<f32> {
+        let c = self.at(x, y);
+        let xx = self.at(x + 1, y) + self.at(x - 1, y) - 2.0 * c;
+        let yy = self.at(x, y + 1) + self.at(x, y - 1) - 2.0 * c;
+        let xy = (self.at(x + 1, y + 1) - self.at(x + 1, y - 1) - self.at(x - 1, y + 1)
+            + self.at(x - 1, y - 1))
+            / 4.0;
+        Matrix2::new(xx, xy, xy, yy)
+    }
+}
+
+/// Whether there are two dark and two bright sectors around `p`, like around a corner of a
+/// checkerboard.
+fn is_checkerboard_corner(image: &GrayImage, p: Vector2<f32>) -> bool {
+    let ring: Vec<f32> = (0..RING_SAMPLES)
+        .map(|i| {
+            let angle = i as f32 / RING_SAMPLES as f32 * std::f32::consts::TAU;
+            image.sample(
+                p.x + RING_RADIUS * angle.cos(),
+                p.y + RING_RADIUS * angle.sin(),
+            )
+        })
+        .collect();
+    let (min, max) = ring.iter().fold((f32::MAX, f32::MIN), |(min, max), &v| {
+        (min.min(v), max.max(v))
+    });
+    if max - min < MIN_CONTRAST {
+        return false;
+    }
+    let mid = (min + max) / 2.0;
+    let changes = (0..RING_SAMPLES)
+        .filter(|&i| (ring[i] > mid) != (ring[(i + 1) % RING_SAMPLES] > mid))
+        .count();
+    changes == 4
+}
+
+/// Find checkerboard corner candidates, in pixel coordinates. The center of the top left
+/// pixel is (0.5, 0.5).
+pub fn find_corners(image: &GrayImage) -> Vec<Vector2<f32>> {
+    let (w, h) = (image.width, image.height);
+    if w <= BORDER * 2 || h <= BORDER * 2 {
+        return Vec::new();
+    }
+    let sharp = image.blur();
+    let smooth = sharp.blur();
+    // Saddle points have a negative Hessian determinant
+    let mut response = vec![0.0f32; w * h];
+    for y in BORDER..h - BORDER {
+        for x in BORDER..w - BORDER {
+            response[y * w + x] = -smooth.hessian(x, y).determinant();
+        }
+    }
+    let max = response.iter().copied().fold(0.0, f32::max);
+    if max <= 0.0 {
+        return Vec::new();
+    }
+    let threshold = max * RESPONSE_THRESHOLD;
+    const NMS_RADIUS: usize = 3;
+    let mut corners = Vec::new();
+    for y in BORDER..h - BORDER {
+        for x in BORDER..w - BORDER {
+            let r = response[y * w + x];
+            if r < threshold {
+                continue;
+            }
+            let is_max = (y - NMS_RADIUS..=y + NMS_RADIUS).all(|ny| {
+                (x - NMS_RADIUS..=x + NMS_RADIUS).all(|nx| {
+                    let other = response[ny * w + nx];
+                    // Break ties by position, so plateaus give one corner
+                    other < r || (other == r && (ny, nx) >= (y, x))
+                })
+            });
+            if !is_max {
+                continue;
+            }
+            // The saddle point of a quadratic fit of the intensity
+            let offset = smooth
+                .hessian(x, y)
+                .try_inverse()
+                .map(|inv| -(inv * smooth.gradient(x, y)))
+                .unwrap_or_else(Vector2::zeros);
+            if offset.amax() > 1.0 {
+                continue;
+            }
+            let p = Vector2::new(x as f32, y as f32) + offset;
+            if is_checkerboard_corner(&sharp, p) {
+                corners.push(p.add_scalar(0.5));
+            }
+        }
+    }
+    corners
+}
+
+const DIRECTIONS: [(i32, i32); 4] = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+
+/// Corners linked into a grid, indexed by grid coordinates
+struct Grid<'a> {
+    corners: &'a [Vector2<f32>],
+    nodes: HashMap<(i32, i32), usize>,
+    used: Vec<bool>,
+    /// Grid steps along the two axes around the seed
+    basis: [Vector2<f32>; 2],
+}
+
+impl Grid<'_> {
+    fn pos(&self, node: (i32, i32)) -> Option<Vector2<f32>> {
+        self.nodes.get(&node).map(|&i| self.corners[i])
+    }
+    fn insert(&mut self, node: (i32, i32), corner: usize) {
+        self.nodes.insert(node, corner);
+        self.used[corner] = true;
+    }
+    /// Expected step from `node` in direction `d`
+    fn step(&self, node: (i32, i32), d: (i32, i32)) -> Vector2<f32> {
+        let p = self.pos(node).unwrap();
+        if let Some(prev) = self.pos((node.0 - d.0, node.1 - d.1)) {
+            return p - prev;
+        }
+        // Use the step taken from a neighbour in the perpendicular direction
+        for side in [(d.1, d.0), (-d.1, -d.0)] {
+            let m = (node.0 + side.0, node.1 + side.1);
+            if let (Some(a), Some(b)) = (self.pos(m), self.pos((m.0 + d.0, m.1 + d.1))) {
+                return b - a;
+            }
+        }
+        let axis = if d.0 != 0 { 0 } else { 1 };
+        self.basis[axis] * (d.0 + d.1) as f32
+    }
+    /// Nearest unused corner within `radius` of `p`
+    fn nearest(&self, p: Vector2<f32>, radius: f32) -> Option<usize> {
+        self.corners
+            .iter()
+            .enumerate()
+            .filter(|&(i, c)| !self.used[i] && (c - p).norm() < radius)
+            .min_by(|(_, a), (_, b)| (*a - p).norm().total_cmp(&(*b - p).norm()))
+            .map(|(i, _)| i)
+    }
+    fn grow(&mut self, max_size: i32) {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            let nodes: Vec<_> = self.nodes.keys().copied().collect();
+            for node in nodes {
+                for d in DIRECTIONS {
+                    let target = (node.0 + d.0, node.1 + d.1);
+                    if self.nodes.contains_key(&target) {
+                        continue;
+                    }
+                    let step = self.step(node, d);
+                    let predicted = self.pos(node).unwrap() + step;
+                    if let Some(corner) = self.nearest(predicted, step.norm() * SEARCH_RADIUS) {
+                        self.insert(target, corner);
+                        changed = true;
+                    }
+                }
+            }
+            if self.nodes.len() > (max_size * max_size) as usize {
+                // Runaway growth into things that are not the board
+                return;
+            }
+        }
+    }
+}
+
+/// Find the two grid axes around a seed corner from its 4 nearest neighbours, which should
+/// be 2 pairs of opposite corners.
+fn seed_basis(corners: &[Vector2<f32>], seed: usize) -> Option<[(usize, usize); 2]> {
+    let p = corners[seed];
+    let mut neighbours: Vec<usize> = (0..corners.len()).filter(|&i| i != seed).collect();
+    neighbours.sort_by(|&a, &b| (corners[a] - p).norm().total_cmp(&(corners[b] - p).norm()));
+    neighbours.truncate(4);
+    if neighbours.len() < 4 {
+        return None;
+    }
+    let dir = |i: usize| (corners[i] - p).normalize();
+    let first = neighbours[0];
+    let opposite = *neighbours[1..]
+        .iter()
+        .min_by(|&&a, &&b| dir(first).dot(&dir(a)).total_cmp(&dir(first).dot(&dir(b))))?;
+    let rest: Vec<usize> = neighbours[1..]
+        .iter()
+        .copied()
+        .filter(|&i| i != opposite)
+        .collect();
+    const OPPOSITE: f32 = -0.8;
+    const PERPENDICULAR: f32 = 0.7;
+    if dir(first).dot(&dir(opposite)) > OPPOSITE
+        || dir(rest[0]).dot(&dir(rest[1])) > OPPOSITE
+        || dir(first).dot(&dir(rest[0])).abs() > PERPENDICULAR
+    {
+        return None;
+    }
+    Some([(first, opposite), (rest[0], rest[1])])
+}
+
+/// Whether `v` points right, or down if it is more vertical than horizontal
+fn points_forward(v: Vector2<f32>) -> bool {
+    if v.x.abs() >= v.y.abs() {
+        v.x > 0.0
+    } else {
+        v.y > 0.0
+    }
+}
+
+/// Link corners into a grid of `cols` by `rows` inner corners. Returns the corners row by
+/// row, or None if the whole board is not found.
+///
+/// Rows go down and columns go right in the image, as much as the board orientation allows,
+/// so the same physical corner gets the same index in both cameras.
+pub fn find_grid(corners: &[Vector2<f32>], cols: usize, rows: usize) -> Option<Vec<Vector2<f32>>> {
+    const MAX_SEEDS: usize = 8;
+    if corners.len() < cols * rows {
+        return None;
+    }
+    let centroid = corners.iter().sum::<Vector2<f32>>() / corners.len() as f32;
+    let mut seeds: Vec<usize> = (0..corners.len()).collect();
+    seeds.sort_by(|&a, &b| {
+        (corners[a] - centroid)
+            .norm()
+            .total_cmp(&(corners[b] - centroid).norm())
+    });
+    seeds.into_iter().take(MAX_SEEDS).find_map(|seed| {
+        let [(a, a_opposite), (b, b_opposite)] = seed_basis(corners, seed)?;
+        let p = corners[seed];
+        let mut grid = Grid {
+            corners,
+            nodes: HashMap::new(),
+            used: vec![false; corners.len()],
+            basis: [
+                (corners[a] - corners[a_opposite]) / 2.0,
+                (corners[b] - corners[b_opposite]) / 2.0,
+            ],
+        };
+        grid.insert((0, 0), seed);
+        for (node, corner) in [
+            ((1, 0), a),
+            ((-1, 0), a_opposite),
+            ((0, 1), b),
+            ((0, -1), b_opposite),
+        ] {
+            grid.insert(node, corner);
+        }
+        debug_assert!((grid.basis[0] - (corners[a] - p)).norm() < grid.basis[0].norm());
+        grid.grow(cols.max(rows) as i32 + 1);
+        organize(&grid, cols, rows)
+    })
+}
+
+/// Order the grid row by row, if it has exactly the expected size
+fn organize(grid: &Grid<'_>, cols: usize, rows: usize) -> Option<Vec<Vector2<f32>>> {
+    if grid.nodes.len() != cols * rows {
+        return None;
+    }
+    let min = |f: fn(&(i32, i32)) -> i32| grid.nodes.keys().map(f).min().unwrap();
+    let max = |f: fn(&(i32, i32)) -> i32| grid.nodes.keys().map(f).max().unwrap();
+    let (i0, j0) = (min(|n| n.0), min(|n| n.1));
+    let size = [
+        (max(|n| n.0) - i0 + 1) as usize,
+        (max(|n| n.1) - j0 + 1) as usize,
+    ];
+    // Average step along each grid axis
+    let mean_step = |d: (i32, i32)| {
+        grid.nodes
+            .keys()
+            .filter_map(|&n| Some(grid.pos((n.0 + d.0, n.1 + d.1))? - grid.pos(n)?))
+            .sum::<Vector2<f32>>()
+    };
+    let steps = [mean_step((1, 0)), mean_step((0, 1))];
+    // Which grid axis runs along the columns
+    let col_axis = if size == [cols, rows] && size != [rows, cols] {
+        0
+    } else if size == [rows, cols] && size != [cols, rows] {
+        1
+    } else if size == [cols, rows] {
+        // Square board, use the more horizontal axis
+        if steps[0].x.abs() >= steps[1].x.abs() {
+            0
+        } else {
+            1
+        }
+    } else {
+        return None;
+    };
+    let row_axis = 1 - col_axis;
+    let forward = [points_forward(steps[0]), points_forward(steps[1])];
+    let index = |axis: usize, k: usize| {
+        let k = if forward[axis] { k } else { size[axis] - 1 - k };
+        k as i32 + if axis == 0 { i0 } else { j0 }
+    };
+    (0..rows)
+        .flat_map(|r| (0..cols).map(move |c| (r, c)))
+        .map(|(r, c)| {
+            let mut node = [0; 2];
+            node[col_axis] = index(col_axis, c);
+            node[row_axis] = index(row_axis, r);
+            grid.pos((node[0], node[1]))
+        })
+        .collect()
+}
@@ -0,0 +1,84 @@
+use std::path::PathBuf;
+
+use argh::FromArgs;
+
+/// Camera passthrough for Valve Index on Linux
+#[derive(FromArgs, Debug)]
+pub struct Args {
+    #[argh(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand)]
+pub enum Command {
+    BenchCorrection(BenchCorrection),
+    Calibrate(Calibrate),
+    DumpCalibration(DumpCalibration),
+    FlatField(FlatFieldCalibrate),
+    Latency(LatencyCalibrate),
+}
+
+/// Measure how long the lens correction stage takes, with and without the lookup table.
+/// Uses the camera calibration from your Steam installation, doesn't need a VR runtime.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "bench-correction")]
+pub struct BenchCorrection {
+    /// number of frames to run for each variant
+    #[argh(option, default = "500")]
+    pub frames: u32,
+}
+
+/// Calibrate the cameras with a printed checkerboard, and write the result to a file that
+/// can be used with the `camera_calibration` option. Doesn't need a VR runtime.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "calibrate")]
+pub struct Calibrate {
+    /// number of inner corners along the long side of the checkerboard
+    #[argh(option, default = "9")]
+    pub cols: usize,
+    /// number of inner corners along the short side of the checkerboard
+    #[argh(option, default = "6")]
+    pub rows: usize,
+    /// size of the checkerboard squares, in meters
+    #[argh(option)]
+    pub square_size: f64,
+    /// number of views of the checkerboard to capture
+    #[argh(option, default = "20")]
+    pub views: usize,
+    /// where to write the calibration, TOML if the extension is .toml, JSON otherwise
+    #[argh(positional)]
+    pub output: PathBuf,
+}
+
+/// Print the camera calibration in use and where it came from, the lens correction
+/// parameters derived from it, and warnings about values that look wrong.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "dump-calibration")]
+pub struct DumpCalibration {}
+
+/// Capture the cameras looking at an evenly lit surface, and write a gain map that can be
+/// used with the `flat_field` option to even out the brightness of the camera images.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "flat-field")]
+pub struct FlatFieldCalibrate {
+    /// number of frames to average
+    #[argh(option, default = "60")]
+    pub frames: u32,
+    /// width and height of the gain map of each camera
+    #[argh(option, default = "64")]
+    pub size: u32,
+    /// where to write the gain map
+    #[argh(positional)]
+    pub output: PathBuf,
+}
+
+/// Measure the camera latency by turning your head around while wearing the HMD, and save it
+/// so the camera view lines up better with your head movement. Needs a VR runtime.
+#[derive(FromArgs, Debug)]
+#[argh(subcommand, name = "latency")]
+pub struct LatencyCalibrate {
+    /// how long to record for, in seconds
+    #[argh(option, default = "15")]
+    pub seconds: u32,
+}
@@ -0,0 +1,156 @@
+use anyhow::Result;
+use std::sync::Arc;
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::allocator::CommandBufferAllocator,
+    descriptor_set::{allocator::DescriptorSetAllocator, DescriptorSet, WriteDescriptorSet},
+    device::{Device, Queue},
+    image::{
+        sampler::{Filter, Sampler, SamplerCreateInfo},
+        view::{ImageView, ImageViewCreateInfo},
+        Image,
+    },
+    memory::allocator::{
+        AllocationCreateInfo, MemoryAllocatePreference, MemoryAllocator, MemoryTypeFilter,
+    },
+    sync::GpuFuture,
+};
+
+use crate::{
+    config::{ColorFilterConfig, ColorFilterMode},
+    fullscreen::FullscreenPass,
+};
+
+mod fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "shaders/color_filter.frag",
+        custom_derives: [Copy, Clone, Debug],
+    }
+}
+
+/// Accessibility colour filters, applied to the whole side-by-side camera image.
+pub struct ColorFilter {
+    pass: FullscreenPass,
+    desc_set: Arc<DescriptorSet>,
+    parameters: Subbuffer<fs::Parameters>,
+    cycle: Vec<ColorFilterMode>,
+    mode: ColorFilterMode,
+}
+
+impl std::fmt::Debug for ColorFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ColorFilter")
+            .field("pass", &self.pass)
+            .field("cycle", &self.cycle)
+            .field("mode", &self.mode)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ColorFilter {
+    /// Create a colour filter stage reading from `input`. The output image must have the
+    /// same size as the input.
+    pub fn new(
+        device: Arc<Device>,
+        allocator: Arc<dyn MemoryAllocator>,
+        descriptor_set_allocator: Arc<dyn DescriptorSetAllocator>,
+        input: &Arc<Image>,
+        cfg: &ColorFilterConfig,
+    ) -> Result<Self> {
+        let [w, h, _] = input.extent();
+        let fs = fs::load(device.clone())?.entry_point("main").unwrap();
+        let pass = FullscreenPass::new(device.clone(), fs, [w, h])?;
+        let parameters = Buffer::from_data(
+            allocator,
+            BufferCreateInfo {
+                usage: BufferUsage::UNIFORM_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::HOST_SEQUENTIAL_WRITE
+                    | MemoryTypeFilter::PREFER_DEVICE,
+                allocate_preference: MemoryAllocatePreference::Unknown,
+                ..Default::default()
+            },
+            fs::Parameters {
+                mode: cfg.mode.shader_id(),
+                threshold: cfg.threshold,
+            },
+        )?;
+        let sampler = Sampler::new(
+            device.clone(),
+            SamplerCreateInfo {
+                min_filter: Filter::Nearest,
+                mag_filter: Filter::Nearest,
+                ..Default::default()
+            },
+        )?;
+        let desc_set_layout = pass.descriptor_set_layout();
+        let desc_set = DescriptorSet::new(
+            descriptor_set_allocator,
+            desc_set_layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, parameters.clone()),
+                WriteDescriptorSet::image_view_sampler(
+                    1,
+                    ImageView::new(input.clone(), ImageViewCreateInfo::from_image(input))?,
+                    sampler,
+                ),
+            ],
+            None,
+        )?;
+        Ok(Self {
+            pass,
+            desc_set,
+            parameters,
+            cycle: cfg.cycle.clone(),
+            mode: cfg.mode,
+        })
+    }
+    pub fn mode(&self) -> ColorFilterMode {
+        self.mode
+    }
+    pub fn set_mode(&mut self, mode: ColorFilterMode) -> Result<()> {
+        if self.mode == mode {
+            return Ok(());
+        }
+        self.parameters.write()?.mode = mode.shader_id();
+        self.mode = mode;
+        Ok(())
+    }
+    /// Switch to the next filter in the configured cycle. If the current filter is not
+    /// part of the cycle, start from the beginning.
+    pub fn cycle(&mut self) -> Result<ColorFilterMode> {
+        if self.cycle.is_empty() {
+            return Ok(self.mode);
+        }
+        let next = self
+            .cycle
+            .iter()
+            .position(|m| *m == self.mode)
+            .map(|i| (i + 1) % self.cycle.len())
+            .unwrap_or(0);
+        self.set_mode(self.cycle[next])?;
+        Ok(self.mode)
+    }
+    /// Apply the current filter, writing the result into `output`.
+    pub fn apply(
+        &self,
+        allocator: Arc<dyn MemoryAllocator>,
+        cmdbuf_allocator: Arc<dyn CommandBufferAllocator>,
+        after: impl GpuFuture,
+        queue: &Arc<Queue>,
+        output: Arc<Image>,
+    ) -> Result<impl GpuFuture> {
+        self.pass.apply(
+            allocator,
+            cmdbuf_allocator,
+            after,
+            queue,
+            &self.desc_set,
+            output,
+            |_| Ok(()),
+        )
+    }
+}
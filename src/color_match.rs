@@ -0,0 +1,270 @@
+use anyhow::Result;
+use std::sync::Arc;
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::allocator::CommandBufferAllocator,
+    descriptor_set::{allocator::DescriptorSetAllocator, DescriptorSet, WriteDescriptorSet},
+    device::{Device, Queue},
+    image::{
+        sampler::{Filter, Sampler, SamplerCreateInfo},
+        view::{ImageView, ImageViewCreateInfo},
+        Image,
+    },
+    memory::allocator::{
+        AllocationCreateInfo, MemoryAllocatePreference, MemoryAllocator, MemoryTypeFilter,
+    },
+    sync::GpuFuture,
+};
+
+use crate::{
+    config::{ColorMatchConfig, ColorMatchMode},
+    fullscreen::FullscreenPass,
+};
+
+mod fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "shaders/color_match.frag",
+        custom_derives: [Copy, Clone, Debug],
+    }
+}
+
+/// Number of frames averaged in `ColorMatchMode::Once`
+const ONCE_FRAMES: u32 = 60;
+/// Only every `SAMPLE_STEP`th pixel in each direction is used for the statistics
+const SAMPLE_STEP: usize = 4;
+/// Limits of the per channel gain, so a camera that is covered or sees something
+/// completely different doesn't blow up the other one
+const MIN_GAIN: f32 = 0.5;
+const MAX_GAIN: f32 = 2.0;
+
+/// Mean and standard deviation of each colour channel of one camera
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ChannelStats {
+    mean: [f32; 3],
+    std: [f32; 3],
+}
+
+impl ChannelStats {
+    /// Statistics of the centre of one eye of a side-by-side camera frame. The centre
+    /// of the two cameras sees mostly the same things, and has the least vignetting.
+    fn from_frame(frame: &[u8], is_yuv: bool, eye_size: usize, eye: usize) -> Self {
+        let bytes_per_pixel = if is_yuv { 2 } else { 4 };
+        let stride = eye_size * 2 * bytes_per_pixel;
+        let mut sum = [0.0f64; 3];
+        let mut sum_sq = [0.0f64; 3];
+        let mut count = 0;
+        for y in (eye_size / 4..eye_size * 3 / 4).step_by(SAMPLE_STEP) {
+            let row = &frame[y * stride..(y + 1) * stride];
+            for x in (eye_size / 4..eye_size * 3 / 4).step_by(SAMPLE_STEP) {
+                let x = x + eye * eye_size;
+                let rgb = if is_yuv {
+                    // a YUYV macropixel holds two pixels, use the first one
+                    let p = &row[(x & !1) * 2..(x & !1) * 2 + 4];
+                    yuv_to_rgb(p[0], p[1], p[3])
+                } else {
+                    let p = &row[x * 4..x * 4 + 3];
+                    [p[0], p[1], p[2]].map(|v| v as f32 / 255.0)
+                };
+                for (c, v) in rgb.into_iter().enumerate() {
+                    sum[c] += v as f64;
+                    sum_sq[c] += (v * v) as f64;
+                }
+                count += 1;
+            }
+        }
+        let count = count.max(1) as f64;
+        let mean = sum.map(|s| s / count);
+        let std = [0, 1, 2].map(|c| (sum_sq[c] / count - mean[c] * mean[c]).max(0.0).sqrt());
+        Self {
+            mean: mean.map(|v| v as f32),
+            std: std.map(|v| v as f32),
+        }
+    }
+    /// Move `self` towards `other` by `weight`
+    fn blend(&mut self, other: &Self, weight: f32) {
+        for (a, b) in self.mean.iter_mut().zip(other.mean) {
+            *a += (b - *a) * weight;
+        }
+        for (a, b) in self.std.iter_mut().zip(other.std) {
+            *a += (b - *a) * weight;
+        }
+    }
+}
+
+/// Same conversion as `yuyv2rgb.frag`
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> [f32; 3] {
+    let y = y as f32 / 255.0 - 0.0625;
+    let u = u as f32 / 255.0 - 0.5;
+    let v = v as f32 / 255.0 - 0.5;
+    [
+        1.164 * y + 1.596 * v,
+        1.164 * y - 0.392 * u - 0.813 * v,
+        1.164 * y + 2.017 * u,
+    ]
+    .map(|c| c.clamp(0.0, 1.0))
+}
+
+/// Per channel gain and offset for both eyes, which map the colour statistics of each
+/// camera to the average of the two.
+fn transforms(stats: &[ChannelStats; 2]) -> fs::Parameters {
+    let target_mean: [f32; 3] =
+        std::array::from_fn(|c| (stats[0].mean[c] + stats[1].mean[c]) / 2.0);
+    // geometric mean, so the gains of the two eyes are reciprocal
+    let target_std: [f32; 3] = std::array::from_fn(|c| (stats[0].std[c] * stats[1].std[c]).sqrt());
+    let gain = stats.map(|s| {
+        std::array::from_fn::<_, 3, _>(|c| {
+            if s.std[c] > f32::EPSILON {
+                (target_std[c] / s.std[c]).clamp(MIN_GAIN, MAX_GAIN)
+            } else {
+                1.0
+            }
+        })
+    });
+    fs::Parameters {
+        gain: gain.map(|[r, g, b]| [r, g, b, 1.0]),
+        offset: [0, 1].map(|eye| {
+            let [r, g, b]: [f32; 3] =
+                std::array::from_fn(|c| target_mean[c] - gain[eye][c] * stats[eye].mean[c]);
+            [r, g, b, 0.0]
+        }),
+    }
+}
+
+/// Matches the brightness and colours of the two cameras. The colour statistics of the
+/// centre of both camera images are compared, and each eye gets a per channel gain and
+/// offset that maps it to the average of the two.
+pub struct ColorMatch {
+    pass: FullscreenPass,
+    desc_set: Arc<DescriptorSet>,
+    parameters: Subbuffer<fs::Parameters>,
+    is_yuv: bool,
+    eye_size: usize,
+    cfg: ColorMatchConfig,
+    /// Smoothed statistics of both eyes
+    stats: Option<[ChannelStats; 2]>,
+    frames: u32,
+}
+
+impl std::fmt::Debug for ColorMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ColorMatch")
+            .field("pass", &self.pass)
+            .field("cfg", &self.cfg)
+            .field("stats", &self.stats)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ColorMatch {
+    /// Create a colour matching stage reading from `input`, which holds both eyes side by
+    /// side. The output image must have the same size as the input. `is_yuv` is the
+    /// format of the camera frames passed to `update`.
+    pub fn new(
+        device: Arc<Device>,
+        allocator: Arc<dyn MemoryAllocator>,
+        descriptor_set_allocator: Arc<dyn DescriptorSetAllocator>,
+        input: &Arc<Image>,
+        is_yuv: bool,
+        cfg: &ColorMatchConfig,
+    ) -> Result<Self> {
+        let [w, h, _] = input.extent();
+        let fs = fs::load(device.clone())?.entry_point("main").unwrap();
+        let pass = FullscreenPass::new(device.clone(), fs, [w, h])?;
+        let parameters = Buffer::from_data(
+            allocator,
+            BufferCreateInfo {
+                usage: BufferUsage::UNIFORM_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::HOST_SEQUENTIAL_WRITE
+                    | MemoryTypeFilter::PREFER_DEVICE,
+                allocate_preference: MemoryAllocatePreference::Unknown,
+                ..Default::default()
+            },
+            fs::Parameters {
+                gain: [[1.0; 4]; 2],
+                offset: [[0.0; 4]; 2],
+            },
+        )?;
+        let sampler = Sampler::new(
+            device.clone(),
+            SamplerCreateInfo {
+                min_filter: Filter::Nearest,
+                mag_filter: Filter::Nearest,
+                ..Default::default()
+            },
+        )?;
+        let desc_set_layout = pass.descriptor_set_layout();
+        let desc_set = DescriptorSet::new(
+            descriptor_set_allocator,
+            desc_set_layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, parameters.clone()),
+                WriteDescriptorSet::image_view_sampler(
+                    1,
+                    ImageView::new(input.clone(), ImageViewCreateInfo::from_image(input))?,
+                    sampler,
+                ),
+            ],
+            None,
+        )?;
+        Ok(Self {
+            pass,
+            desc_set,
+            parameters,
+            is_yuv,
+            eye_size: h as usize,
+            cfg: *cfg,
+            stats: None,
+            frames: 0,
+        })
+    }
+    /// Update the colour transforms from a new camera frame. Must not be called while a
+    /// previous `apply` is still running on the GPU.
+    pub fn update(&mut self, frame: &[u8]) -> Result<()> {
+        let weight = match self.cfg.mode {
+            ColorMatchMode::Once if self.frames >= ONCE_FRAMES => return Ok(()),
+            // plain average of all frames so far
+            ColorMatchMode::Once => 1.0 / (self.frames + 1) as f32,
+            ColorMatchMode::Continuous => self.cfg.smoothing.clamp(0.0, 1.0),
+        };
+        let current =
+            [0, 1].map(|eye| ChannelStats::from_frame(frame, self.is_yuv, self.eye_size, eye));
+        let stats = match self.stats {
+            Some(mut stats) => {
+                stats[0].blend(&current[0], weight);
+                stats[1].blend(&current[1], weight);
+                stats
+            }
+            None => current,
+        };
+        *self.parameters.write()? = transforms(&stats);
+        self.stats = Some(stats);
+        self.frames += 1;
+        if self.cfg.mode == ColorMatchMode::Once && self.frames == ONCE_FRAMES {
+            log::info!("Colour matching done: {:?}", self.stats);
+        }
+        Ok(())
+    }
+    /// Apply the colour transforms, writing the result into `output`.
+    pub fn apply(
+        &self,
+        allocator: Arc<dyn MemoryAllocator>,
+        cmdbuf_allocator: Arc<dyn CommandBufferAllocator>,
+        after: impl GpuFuture,
+        queue: &Arc<Queue>,
+        output: Arc<Image>,
+    ) -> Result<impl GpuFuture> {
+        self.pass.apply(
+            allocator,
+            cmdbuf_allocator,
+            after,
+            queue,
+            &self.desc_set,
+            output,
+            |_| Ok(()),
+        )
+    }
+}
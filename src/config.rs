@@ -12,6 +12,16 @@ pub enum ProjectionMode {
     /// in this mode, we assume your cameras are at your eyes' physical location. everything will
     /// have the right scale in this mode, but the viewing range is smaller.
     FromEye,
+    /// in this mode, depth is estimated from the two cameras, and every pixel is reprojected
+    /// to your eyes' physical location. everything will have the right scale, and things up
+    /// close are aligned correctly, at the cost of some GPU time. where the depth is unknown,
+    /// things are shown at the overlay distance. requires camera calibration data.
+    FromDepth,
+    /// in this mode, everything is assumed to be on a plane in front of your eyes, at the
+    /// distance set in the `fixed_depth` section, and reprojected to your eyes' physical
+    /// location. things at that distance line up in both eyes and have the right scale.
+    /// requires camera calibration data.
+    FixedDepth,
 }
 
 impl Default for ProjectionMode {
@@ -123,7 +133,15 @@ pub const fn default_display_eye() -> Eye {
     Eye::Left
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub const fn default_proximity_distance() -> f32 {
+    1.2
+}
+
+pub const fn default_proximity_fade() -> f32 {
+    0.3
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
 #[serde(tag = "mode")]
 pub enum DisplayMode {
     #[default]
@@ -142,25 +160,570 @@ pub enum DisplayMode {
         #[serde(default = "default_display_eye")]
         eye: Eye,
     },
+    /// like `Stereo`, but only show things that are close to you, so you can see a person
+    /// walking up to you, or the edge of a table, while still seeing the game. uses the
+    /// estimated depth, requires camera calibration data.
+    Proximity {
+        /// how is the camera's image projected onto the overlay
+        #[serde(default)]
+        projection_mode: ProjectionMode,
+        /// things further than this are hidden, in meters
+        #[serde(default = "default_proximity_distance")]
+        distance: f32,
+        /// things fade out over this range before `distance`, in meters
+        #[serde(default = "default_proximity_fade")]
+        fade: f32,
+    },
+    /// debug mode, display the estimated depth of both cameras side by side, colour coded.
+    /// requires the `depth` section to be set.
+    Depth,
 }
 
 impl DisplayMode {
     pub(crate) fn projection_mode(&self) -> Option<ProjectionMode> {
         match self {
-            DisplayMode::Stereo { projection_mode } => Some(*projection_mode),
+            DisplayMode::Stereo { projection_mode }
+            | DisplayMode::Proximity {
+                projection_mode, ..
+            } => Some(*projection_mode),
             _ => None,
         }
     }
     pub(crate) fn is_stereo(&self) -> bool {
-        matches!(self, DisplayMode::Stereo { .. } | DisplayMode::Direct)
+        matches!(
+            self,
+            DisplayMode::Stereo { .. }
+                | DisplayMode::Proximity { .. }
+                | DisplayMode::Direct
+                | DisplayMode::Depth
+        )
+    }
+    /// whether lens correction can be done while projecting the overlay, because nothing
+    /// else needs the undistorted image. see `CorrectionConfig::fused`.
+    pub(crate) fn can_fuse_correction(&self) -> bool {
+        matches!(
+            self,
+            DisplayMode::Stereo {
+                projection_mode: ProjectionMode::FromCamera
+                    | ProjectionMode::FromEye
+                    | ProjectionMode::FixedDepth
+            }
+        )
+    }
+    /// whether this display mode uses the estimated depth map
+    pub(crate) fn needs_depth(&self) -> bool {
+        matches!(
+            self,
+            DisplayMode::Depth
+                | DisplayMode::Proximity { .. }
+                | DisplayMode::Stereo {
+                    projection_mode: ProjectionMode::FromDepth
+                }
+        )
+    }
+    /// whether this display mode changes the processed camera frame itself, instead of
+    /// only how it is shown. only the main overlay can use these.
+    pub(crate) fn changes_frame(&self) -> bool {
+        matches!(self, DisplayMode::Proximity { .. } | DisplayMode::Depth)
+    }
+    /// whether the overlay texture uses the alpha channel to hide parts of the camera view
+    pub(crate) fn is_masked(&self) -> bool {
+        matches!(self, DisplayMode::Proximity { .. })
+    }
+}
+
+pub const fn default_cylinder_radius() -> f32 {
+    1.0
+}
+
+/// Shape of the overlay
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(tag = "type")]
+pub enum OverlayShape {
+    /// a flat rectangle
+    #[default]
+    Flat,
+    /// a section of a vertical cylinder, curving around you
+    Cylinder {
+        /// radius of the cylinder, in meters. works best if it is close to the distance
+        /// of the overlay.
+        #[serde(default = "default_cylinder_radius")]
+        radius: f32,
+    },
+    /// the camera view covers your whole field of view, like a see-through headset. the
+    /// overlay position is ignored. only supported by the OpenXR backend, and only with the
+    /// "Stereo" and "Proximity" display modes.
+    FullView,
+}
+
+impl OverlayShape {
+    /// Radius of the cylinder for an overlay of the given width, 0 if the overlay is
+    /// flat. The overlay can wrap around at most once.
+    pub fn radius(self, width: f32) -> f32 {
+        match self {
+            Self::Flat | Self::FullView => 0.0,
+            Self::Cylinder { radius } => radius.max(width / std::f32::consts::TAU),
+        }
+    }
+}
+
+pub const fn default_corner_radius() -> f32 {
+    0.1
+}
+
+pub const fn default_mask_opacity() -> f32 {
+    1.0
+}
+
+/// Shape of the window the camera view is seen through
+#[derive(PartialEq, Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(tag = "type")]
+pub enum MaskShape {
+    /// the whole overlay
+    #[default]
+    Rectangle,
+    /// the largest circle that fits on the overlay
+    Circle,
+    /// a rectangle with rounded corners
+    RoundedRectangle {
+        /// radius of the corners, as a fraction of the overlay width
+        #[serde(default = "default_corner_radius")]
+        corner_radius: f32,
+    },
+    /// an image stretched over the overlay. its alpha channel is used, or its brightness if
+    /// it has none. relative paths are relative to the config directory.
+    Image { path: std::path::PathBuf },
+}
+
+/// Shape, soft edges and opacity of the overlay
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct OverlayMaskConfig {
+    #[serde(default)]
+    pub shape: MaskShape,
+    /// width of the soft edge the camera view fades out over, as a fraction of the overlay
+    /// width. 0 for a hard edge.
+    #[serde(default)]
+    pub feather: f32,
+    /// opacity of the camera view, from 0 to 1
+    #[serde(default = "default_mask_opacity")]
+    pub opacity: f32,
+}
+
+impl Default for OverlayMaskConfig {
+    fn default() -> Self {
+        Self {
+            shape: MaskShape::default(),
+            feather: 0.0,
+            opacity: default_mask_opacity(),
+        }
+    }
+}
+
+impl OverlayMaskConfig {
+    /// whether the mask shows the whole overlay, fully opaque
+    pub(crate) fn is_opaque(&self) -> bool {
+        self.shape == MaskShape::Rectangle && self.feather <= 0.0 && self.opacity >= 1.0
+    }
+}
+
+/// What to do when the cameras can't see enough of the overlay
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutOfViewAction {
+    /// nothing, the parts the cameras can't see are transparent
+    #[default]
+    None,
+    /// draw a border around the overlay, and an arrow pointing to where the cameras can see
+    Indicator,
+    /// show the overlay in front of you instead, until the cameras can see where it belongs
+    HeadLocked,
+    /// hide the overlay until the cameras can see it again
+    Hide,
+}
+
+pub const fn default_out_of_view_threshold() -> f32 {
+    0.5
+}
+
+/// What happens when the overlay is outside of the cameras' field of view, e.g. when you
+/// turn away from a "Sticky" or "Absolute" overlay
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct OutOfViewConfig {
+    #[serde(default)]
+    pub action: OutOfViewAction,
+    /// the action is taken when the cameras see less than this fraction of the overlay
+    #[serde(default = "default_out_of_view_threshold")]
+    pub threshold: f32,
+    /// how far away the head-locked overlay is placed, in meters
+    #[serde(default = "default_overlay_distance")]
+    pub distance: f32,
+}
+
+impl Default for OutOfViewConfig {
+    fn default() -> Self {
+        Self {
+            action: OutOfViewAction::default(),
+            threshold: default_out_of_view_threshold(),
+            distance: default_overlay_distance(),
+        }
+    }
+}
+
+impl OutOfViewConfig {
+    /// Limit `threshold` to values where the overlay can come back into view, see
+    /// `out_of_view::HYSTERESIS`. `overlay` names the overlay in the warnings.
+    fn sanitize(&mut self, overlay: &str) {
+        let max = 1.0 - crate::out_of_view::HYSTERESIS;
+        let threshold = if self.threshold.is_nan() {
+            default_out_of_view_threshold()
+        } else {
+            self.threshold.clamp(0.0, max)
+        };
+        if threshold != self.threshold {
+            log::warn!(
+                "{overlay}: out_of_view threshold {} is not between 0 and {max}, using {threshold}",
+                self.threshold
+            );
+            self.threshold = threshold;
+        }
+        if self.threshold <= 0.0 && self.action != OutOfViewAction::None {
+            log::warn!(
+                "{overlay}: out_of_view threshold is 0, {:?} is never done",
+                self.action
+            );
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+pub const fn default_overlay_size() -> f32 {
+    1.0
+}
+
+pub const fn default_overlay_resize_speed() -> f32 {
+    0.5
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct OverlayConfig {
     /// how is the overlay positioned
     #[serde(default)]
     pub position: PositionMode,
+    /// re-warp the last camera frame to your latest head pose on every display refresh,
+    /// instead of only when a new camera frame arrives. this reduces judder when you turn
+    /// your head, at the cost of more GPU time. only has an effect with the "Stereo" and
+    /// "Proximity" display modes.
+    #[serde(default)]
+    pub reprojection: bool,
+    /// flat or curved overlay
+    #[serde(default)]
+    pub shape: OverlayShape,
+    /// width of the overlay, in meters
+    #[serde(default = "default_overlay_size")]
+    pub width: f32,
+    /// height of the overlay, in meters. the display modes that project the camera view
+    /// match any aspect ratio, the others stretch the camera image to fit.
+    #[serde(default = "default_overlay_size")]
+    pub height: f32,
+    /// resize the overlay while running, by pushing the right thumbstick up or down. not
+    /// available if the thumbstick adjusts the fixed depth.
+    #[serde(default)]
+    pub resize_thumbstick: bool,
+    /// how fast the overlay is resized, as a fraction of its size per second
+    #[serde(default = "default_overlay_resize_speed")]
+    pub resize_speed: f32,
+    /// shape, soft edges and opacity of the camera view on the overlay
+    #[serde(default)]
+    pub mask: OverlayMaskConfig,
+    /// what to do when the cameras can't see the overlay. only for the display modes that
+    /// project the camera view.
+    #[serde(default)]
+    pub out_of_view: OutOfViewConfig,
+}
+
+impl Default for OverlayConfig {
+    fn default() -> Self {
+        Self {
+            position: Default::default(),
+            reprojection: false,
+            shape: Default::default(),
+            width: default_overlay_size(),
+            height: default_overlay_size(),
+            resize_thumbstick: false,
+            resize_speed: default_overlay_resize_speed(),
+            mask: Default::default(),
+            out_of_view: Default::default(),
+        }
+    }
+}
+
+impl OverlayConfig {
+    /// width and height of the overlay, in meters
+    pub(crate) fn size(&self) -> [f32; 2] {
+        [self.width, self.height]
+    }
+}
+
+/// An additional overlay, showing the same camera view as the main one
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExtraOverlayConfig {
+    /// how is the camera view displayed on this overlay. "Proximity" and "Depth" are only
+    /// available for the main overlay.
+    #[serde(default)]
+    pub display_mode: DisplayMode,
+    /// how is the overlay positioned
+    #[serde(default)]
+    pub position: PositionMode,
+    /// flat or curved overlay. "FullView" is only available for the main overlay.
+    #[serde(default)]
+    pub shape: OverlayShape,
+    /// width of the overlay, in meters
+    #[serde(default = "default_overlay_size")]
+    pub width: f32,
+    /// height of the overlay, in meters
+    #[serde(default = "default_overlay_size")]
+    pub height: f32,
+    /// shape, soft edges and opacity of the camera view on the overlay
+    #[serde(default)]
+    pub mask: OverlayMaskConfig,
+    /// what to do when the cameras can't see the overlay
+    #[serde(default)]
+    pub out_of_view: OutOfViewConfig,
+}
+
+impl ExtraOverlayConfig {
+    /// width and height of the overlay, in meters
+    pub(crate) fn size(&self) -> [f32; 2] {
+        [self.width, self.height]
+    }
+}
+
+/// Colour filters that can be applied to the camera image, to make it easier to
+/// read for people with low vision or colour vision deficiencies.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorFilterMode {
+    /// show the camera image as is
+    #[default]
+    None,
+    /// convert the image to grayscale
+    Grayscale,
+    /// invert the colors
+    Inverted,
+    /// black and white, based on a luminance threshold
+    HighContrast,
+    /// map luminance to a false colour gradient
+    FalseColor,
+    /// daltonization for red-green colour blindness (green weak)
+    Deuteranopia,
+    /// daltonization for red-green colour blindness (red weak)
+    Protanopia,
+    /// daltonization for blue-yellow colour blindness
+    Tritanopia,
+}
+
+impl ColorFilterMode {
+    /// The mode id used by the colour filter shader
+    pub(crate) fn shader_id(self) -> i32 {
+        self as i32
+    }
+}
+
+pub fn default_color_filter_cycle() -> Vec<ColorFilterMode> {
+    vec![
+        ColorFilterMode::None,
+        ColorFilterMode::Grayscale,
+        ColorFilterMode::Inverted,
+        ColorFilterMode::HighContrast,
+        ColorFilterMode::FalseColor,
+        ColorFilterMode::Deuteranopia,
+        ColorFilterMode::Protanopia,
+        ColorFilterMode::Tritanopia,
+    ]
+}
+
+pub const fn default_high_contrast_threshold() -> f32 {
+    0.5
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ColorFilterConfig {
+    /// the filter to use when the program starts
+    #[serde(default)]
+    pub mode: ColorFilterMode,
+    /// filters to cycle through when the "cycle filter" button is pressed
+    #[serde(default = "default_color_filter_cycle")]
+    pub cycle: Vec<ColorFilterMode>,
+    /// luminance threshold for the "HighContrast" filter, between 0 and 1
+    #[serde(default = "default_high_contrast_threshold")]
+    pub threshold: f32,
+}
+
+/// When the colour transforms of the two cameras are estimated
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorMatchMode {
+    /// Keep estimating while running, follows changes in lighting
+    #[default]
+    Continuous,
+    /// Estimate from the first frames after startup, then keep the transforms fixed
+    Once,
+}
+
+pub const fn default_color_match_smoothing() -> f32 {
+    0.05
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct ColorMatchConfig {
+    /// when the colour transforms are estimated
+    #[serde(default)]
+    pub mode: ColorMatchMode,
+    /// how quickly the transforms follow changes of the image in "Continuous" mode,
+    /// between 0 (never) and 1 (only use the current frame)
+    #[serde(default = "default_color_match_smoothing")]
+    pub smoothing: f32,
+}
+
+pub const fn default_lookup_table() -> bool {
+    true
+}
+
+pub const fn default_lookup_table_downscale() -> u32 {
+    1
+}
+
+/// How much of the camera image the lens corrected image shows
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum OutputFov {
+    /// The largest image without black borders, the edges of the camera image are cropped
+    #[default]
+    Fill,
+    /// All of the camera image, with black borders where there is no image. Parts more than
+    /// ~84 degrees off axis are cropped.
+    Fit,
+    /// An explicit field of view, in degrees, horizontally and vertically
+    Degrees(f32),
+}
+
+pub const fn default_zoom() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct CorrectionConfig {
+    /// precompute where every output pixel samples the camera image, instead of
+    /// evaluating the lens model for every pixel in every frame
+    #[serde(default = "default_lookup_table")]
+    pub lookup_table: bool,
+    /// the lookup table is computed at the camera resolution divided by this factor,
+    /// and interpolated in between. 1 means no interpolation.
+    #[serde(default = "default_lookup_table_downscale")]
+    pub lookup_table_downscale: u32,
+    /// sample the camera image through the lookup table directly when projecting the
+    /// overlay, instead of undistorting it into an intermediate image first. saves a
+    /// render pass and a resampling step. only has an effect in the "Stereo" display
+    /// mode with the "FromCamera" or "FromEye" projection modes, when depth estimation
+    /// is disabled.
+    #[serde(default)]
+    pub fused: bool,
+    /// how much of the camera image the lens corrected image shows
+    #[serde(default)]
+    pub fov: OutputFov,
+    /// digital zoom, the image is magnified by this factor
+    #[serde(default = "default_zoom")]
+    pub zoom: f32,
+}
+
+impl CorrectionConfig {
+    /// The zoom factor, limited to sensible values
+    pub fn zoom(&self) -> f32 {
+        self.zoom.max(0.1)
+    }
+}
+
+impl Default for CorrectionConfig {
+    fn default() -> Self {
+        Self {
+            lookup_table: default_lookup_table(),
+            lookup_table_downscale: default_lookup_table_downscale(),
+            fused: false,
+            fov: OutputFov::default(),
+            zoom: default_zoom(),
+        }
+    }
+}
+
+pub const fn default_depth_downscale() -> u32 {
+    4
+}
+
+pub const fn default_max_disparity() -> u32 {
+    48
+}
+
+pub const fn default_depth_window() -> u32 {
+    3
+}
+
+pub const fn default_depth_uniqueness() -> f32 {
+    0.9
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DepthConfig {
+    /// the depth map is computed at the camera resolution divided by this factor
+    #[serde(default = "default_depth_downscale")]
+    pub downscale: u32,
+    /// maximum disparity to search, in pixels of the downscaled image. larger values
+    /// allow closer objects, at a higher GPU cost. at most 128.
+    #[serde(default = "default_max_disparity")]
+    pub max_disparity: u32,
+    /// radius of the block matching window, in pixels of the downscaled image
+    #[serde(default = "default_depth_window")]
+    pub window: u32,
+    /// a match is rejected if its cost is not lower than this times the cost of the
+    /// second best match. lower values reject more ambiguous matches.
+    #[serde(default = "default_depth_uniqueness")]
+    pub uniqueness: f32,
+}
+
+impl Default for DepthConfig {
+    fn default() -> Self {
+        Self {
+            downscale: default_depth_downscale(),
+            max_disparity: default_max_disparity(),
+            window: default_depth_window(),
+            uniqueness: default_depth_uniqueness(),
+        }
+    }
+}
+
+pub const fn default_fixed_depth_distance() -> f32 {
+    1.0
+}
+
+pub const fn default_fixed_depth_adjust_speed() -> f32 {
+    0.5
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct FixedDepthConfig {
+    /// distance of the plane everything is assumed to be on, in meters
+    #[serde(default = "default_fixed_depth_distance")]
+    pub distance: f32,
+    /// change the distance while running, by pushing the right thumbstick up or down
+    #[serde(default)]
+    pub thumbstick: bool,
+    /// how fast the distance changes with the thumbstick fully pushed, in meters per second
+    #[serde(default = "default_fixed_depth_adjust_speed")]
+    pub adjust_speed: f32,
+}
+
+impl Default for FixedDepthConfig {
+    fn default() -> Self {
+        Self {
+            distance: default_fixed_depth_distance(),
+            thumbstick: false,
+            adjust_speed: default_fixed_depth_adjust_speed(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
@@ -232,12 +795,48 @@ pub struct Config {
     /// camera device to use. auto detect if not set
     #[serde(default)]
     pub camera_device: String,
+    /// camera calibration file to use instead of the one from SteamVR or the Steam
+    /// installation. relative paths are relative to the config directory.
+    #[serde(default)]
+    pub camera_calibration: Option<std::path::PathBuf>,
+    /// Steam install to look for camera calibration in, in addition to the usual places
+    #[serde(default)]
+    pub steam_root: Option<std::path::PathBuf>,
+    /// serial number of the HMD whose camera calibration to use, if SteamVR cannot tell
+    /// us. needed if several HMDs have been used with Steam on this machine.
+    #[serde(default)]
+    pub hmd_serial: Option<String>,
+    /// gain map for flat-field correction, created with the `flat-field` command. relative
+    /// paths are relative to the config directory. the stage is disabled if this is not set.
+    #[serde(default)]
+    pub flat_field: Option<std::path::PathBuf>,
     /// overlay related configuration
     #[serde(default)]
     pub overlay: OverlayConfig,
     /// how is the camera view displayed on the overlay
     #[serde(default)]
     pub display_mode: DisplayMode,
+    /// additional overlays by name, each with its own position, size and display mode.
+    /// they show the same processed camera frame as the main overlay.
+    #[serde(default)]
+    pub overlays: std::collections::BTreeMap<String, ExtraOverlayConfig>,
+    /// colour filters applied to the camera image. the filter stage is
+    /// disabled if this is not set.
+    #[serde(default)]
+    pub color_filter: Option<ColorFilterConfig>,
+    /// match the brightness and colours of the two cameras. disabled if not set.
+    #[serde(default)]
+    pub color_match: Option<ColorMatchConfig>,
+    /// lens distortion correction
+    #[serde(default)]
+    pub correction: CorrectionConfig,
+    /// stereo depth estimation. the depth stage is disabled if this is not set,
+    /// unless the display mode needs it.
+    #[serde(default)]
+    pub depth: Option<DepthConfig>,
+    /// the plane used by the "FixedDepth" projection mode
+    #[serde(default)]
+    pub fixed_depth: FixedDepthConfig,
     /// which button should toggle the overlay visibility. press things
     /// button on both controllers to toggle the overlay.
     #[serde(default = "default_toggle_button")]
@@ -246,6 +845,10 @@ pub struct Config {
     /// closing the overlay is always instantaneous
     #[serde(default = "default_open_delay", with = "humantime_serde")]
     pub open_delay: std::time::Duration,
+    /// delay between the camera capturing a frame and the timestamp we get for it. the
+    /// value measured with the `latency` command is used if this is not set.
+    #[serde(default, with = "humantime_serde")]
+    pub camera_latency: Option<std::time::Duration>,
     /// z order of the overlay. higher z order means the overlay is on top of
     /// other overlays. Not supported on all backends, supported on OpenXR.
     #[serde(default = "default_z_order")]
@@ -260,11 +863,22 @@ impl Default for Config {
     fn default() -> Self {
         Self {
             camera_device: "".to_owned(),
+            camera_calibration: None,
+            steam_root: None,
+            hmd_serial: None,
+            flat_field: None,
             backend: Backend::OpenVR,
             overlay: Default::default(),
             display_mode: Default::default(),
+            overlays: Default::default(),
+            color_filter: None,
+            color_match: None,
+            correction: Default::default(),
+            depth: None,
+            fixed_depth: Default::default(),
             toggle_button: default_toggle_button(),
             open_delay: std::time::Duration::ZERO,
+            camera_latency: None,
             debug: false,
             z_order: default_z_order(),
         }
@@ -276,7 +890,12 @@ use xdg::BaseDirectories;
 pub fn load_config(xdg: &BaseDirectories) -> Result<Config> {
     if let Some(f) = xdg.find_config_file("index_camera_passthrough.toml") {
         let cfg = std::fs::read_to_string(f)?;
-        Ok(toml::from_str(&cfg)?)
+        let mut cfg: Config = toml::from_str(&cfg)?;
+        cfg.overlay.out_of_view.sanitize("Main overlay");
+        for (name, overlay) in &mut cfg.overlays {
+            overlay.out_of_view.sanitize(&format!("Overlay {name}"));
+        }
+        Ok(cfg)
     } else {
         Ok(Default::default())
     }
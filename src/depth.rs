@@ -0,0 +1,508 @@
+//! Depth estimation from the stereo camera pair.
+//!
+//! Undistorted images -> rectification -> block matching -> depth map
+//!
+//! The undistorted images from `StereoCorrection` are first resampled into a common
+//! "rectified" camera frame, in which the baseline between the two cameras is along the
+//! X axis, so matching points in the two images are on the same row. Then for each pixel
+//! of each eye, we search along the row of the other eye for the best matching block. The
+//! resulting disparity is converted to depth in meters.
+//!
+//! The depth map is side-by-side, like the camera image, and each eye uses its own
+//! rectified image as reference.
+//!
+//! The depth map can then be visualised for debugging, or used to mask out everything
+//! beyond a distance.
+use anyhow::{anyhow, Result};
+use nalgebra::{Matrix3, Vector3};
+use std::sync::Arc;
+use vulkano::{
+    buffer::{Buffer, BufferContents, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::{
+        allocator::CommandBufferAllocator, CommandBufferBeginInfo, CommandBufferLevel,
+        CommandBufferUsage::OneTimeSubmit, RecordingCommandBuffer,
+    },
+    descriptor_set::{allocator::DescriptorSetAllocator, DescriptorSet, WriteDescriptorSet},
+    device::{Device, DeviceOwned, Queue},
+    format::Format,
+    image::{
+        sampler::{Filter, Sampler, SamplerCreateInfo},
+        view::{ImageView, ImageViewCreateInfo},
+        Image, ImageCreateInfo, ImageUsage,
+    },
+    memory::allocator::{
+        AllocationCreateInfo, MemoryAllocatePreference, MemoryAllocator, MemoryTypeFilter,
+    },
+    pipeline::{
+        compute::ComputePipelineCreateInfo, layout::PipelineDescriptorSetLayoutCreateInfo,
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    sync::GpuFuture,
+    Handle, VulkanObject,
+};
+
+use crate::{fullscreen::FullscreenPass, utils::DeviceExt as _};
+
+mod rectify {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "shaders/depth_rectify.comp",
+        custom_derives: [Copy, Clone, Debug],
+    }
+}
+
+mod matching {
+    vulkano_shaders::shader! {
+        ty: "compute",
+        path: "shaders/depth_match.comp",
+        custom_derives: [Copy, Clone, Debug],
+    }
+}
+
+mod mask {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "shaders/depth_mask.frag",
+        custom_derives: [Copy, Clone, Debug],
+    }
+}
+
+mod visualize {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "shaders/depth_visualize.frag",
+        custom_derives: [Copy, Clone, Debug],
+    }
+}
+
+/// Depth range shown by the debug visualisation, in meters
+const VISUALIZE_RANGE: (f32, f32) = (0.3, 5.0);
+
+fn uniform_buffer<T: BufferContents>(
+    allocator: Arc<dyn MemoryAllocator>,
+    data: T,
+) -> Result<Subbuffer<T>> {
+    Ok(Buffer::from_data(
+        allocator,
+        BufferCreateInfo {
+            usage: BufferUsage::UNIFORM_BUFFER,
+            ..Default::default()
+        },
+        AllocationCreateInfo {
+            memory_type_filter: MemoryTypeFilter::HOST_SEQUENTIAL_WRITE
+                | MemoryTypeFilter::PREFER_DEVICE,
+            allocate_preference: MemoryAllocatePreference::Unknown,
+            ..Default::default()
+        },
+        data,
+    )?)
+}
+
+/// The common camera frame the two camera images are resampled into before matching.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rectification {
+    /// Rotations from the rectified frame to the frames of the left and right undistorted
+    /// camera images.
+    pub rotations: [Matrix3<f32>; 2],
+    /// Field-of-view parameter of the rectified images, same unit as `StereoCorrection::fov`
+    pub fov: f32,
+    /// Distance between the two cameras, in meters
+    pub baseline: f32,
+}
+
+impl Rectification {
+    /// # Arguments
+    ///
+    /// - fov: adjusted fovs of the undistorted images, see `StereoCorrection::fov`
+    pub fn new(camera_calib: &crate::vrapi::StereoCamera, fov: &[[f32; 2]; 2]) -> Self {
+        // Camera to HMD transforms use the negated extrinsics position, see
+        // `Extrinsics::camera_to_hmd`.
+        let left = -Vector3::from(camera_calib.left.extrinsics.position.map(|x| x as f32));
+        let right = -Vector3::from(camera_calib.right.extrinsics.position.map(|x| x as f32));
+        let baseline = right - left;
+        let camera_rotations =
+            [camera_calib.left, camera_calib.right].map(|c| c.extrinsics.rotation());
+        // New X axis along the baseline, new Z axis as close to the average of the cameras'
+        // Z axes as possible.
+        let x = baseline.normalize();
+        let z = camera_rotations[0].column(2) + camera_rotations[1].column(2);
+        let z = (z - x * x.dot(&z)).normalize();
+        let y = z.cross(&x);
+        let rect_to_hmd = Matrix3::from_columns(&[x, y, z]);
+        Self {
+            rotations: camera_rotations.map(|r| r.transpose() * rect_to_hmd),
+            // Use the narrowest field of view, so the rectified images are covered by
+            // both cameras.
+            fov: fov.iter().flatten().copied().fold(0.0, f32::max),
+            baseline: baseline.norm(),
+        }
+    }
+}
+
+/// The output of `StereoDepth`, for use by later stages
+#[derive(Clone)]
+pub struct DepthMap {
+    /// R32_SFLOAT side-by-side image in the rectified camera frame. Depth is in meters along
+    /// the rectified Z axis, 0 means unknown.
+    pub image: Arc<Image>,
+    pub rectification: Rectification,
+}
+
+impl std::fmt::Debug for DepthMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DepthMap")
+            .field("image", &self.image.handle().as_raw())
+            .field("rectification", &self.rectification)
+            .finish()
+    }
+}
+
+/// Estimate depth from the undistorted stereo image
+pub struct StereoDepth {
+    device: Arc<Device>,
+    rectify_pipeline: Arc<ComputePipeline>,
+    rectify_desc_set: Arc<DescriptorSet>,
+    match_pipeline: Arc<ComputePipeline>,
+    match_desc_set: Arc<DescriptorSet>,
+    visualize_pass: FullscreenPass,
+    visualize_desc_set: Arc<DescriptorSet>,
+    mask_pass: FullscreenPass,
+    mask_desc_set: Arc<DescriptorSet>,
+    mask_parameters: Subbuffer<mask::Parameters>,
+    depth: Arc<Image>,
+    rectification: Rectification,
+}
+
+impl std::fmt::Debug for StereoDepth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StereoDepth")
+            .field("device", &self.device.handle().as_raw())
+            .field("depth", &self.depth.handle().as_raw())
+            .field("rectification", &self.rectification)
+            .finish_non_exhaustive()
+    }
+}
+
+impl StereoDepth {
+    /// Input is the undistorted (size * 2, size) image from `StereoCorrection`, the depth
+    /// map is (size * 2 / downscale, size / downscale).
+    ///
+    /// # Arguments
+    ///
+    /// - fov: adjusted fovs of the undistorted images, see `StereoCorrection::fov`
+    pub fn new(
+        device: Arc<Device>,
+        allocator: Arc<dyn MemoryAllocator>,
+        descriptor_set_allocator: Arc<dyn DescriptorSetAllocator>,
+        input: &Arc<Image>,
+        camera_calib: &crate::vrapi::StereoCamera,
+        fov: &[[f32; 2]; 2],
+        cfg: &crate::config::DepthConfig,
+    ) -> Result<Self> {
+        let [w, h, _] = input.extent();
+        if w != h * 2 {
+            return Err(anyhow!("Input not square"));
+        }
+        let size = h / cfg.downscale.max(1);
+        let rectification = Rectification::new(camera_calib, fov);
+        log::info!("Depth rectification: {rectification:?}");
+        let new_storage_image = |name: &str| {
+            let image = device.clone().new_image(
+                ImageCreateInfo {
+                    extent: [size * 2, size, 1],
+                    format: Format::R32_SFLOAT,
+                    usage: ImageUsage::STORAGE | ImageUsage::SAMPLED,
+                    ..Default::default()
+                },
+                MemoryTypeFilter::PREFER_DEVICE,
+            )?;
+            device.set_debug_utils_object_name(&image, Some(name))?;
+            anyhow::Ok(image)
+        };
+        let rectified = new_storage_image("rectified")?;
+        let depth = new_storage_image("depth")?;
+
+        // Rectification pass
+        let rectify_pipeline = Self::compute_pipeline(
+            device.clone(),
+            rectify::load(device.clone())?.entry_point("main").unwrap(),
+        )?;
+        let rectify_parameters = uniform_buffer(
+            allocator.clone(),
+            rectify::Parameters {
+                rectToCamera: rectification
+                    .rotations
+                    .map(|r| *r.to_homogeneous().as_ref()),
+                fov: [fov[0][0], fov[0][1], fov[1][0], fov[1][1]],
+                rectFov: rectification.fov.into(),
+            },
+        )?;
+        let sampler = Sampler::new(
+            device.clone(),
+            SamplerCreateInfo {
+                mag_filter: Filter::Linear,
+                min_filter: Filter::Linear,
+                ..Default::default()
+            },
+        )?;
+        let rectify_desc_set = DescriptorSet::new(
+            descriptor_set_allocator.clone(),
+            rectify_pipeline.layout().set_layouts()[0].clone(),
+            [
+                WriteDescriptorSet::buffer(0, rectify_parameters),
+                WriteDescriptorSet::image_view_sampler(
+                    1,
+                    ImageView::new(input.clone(), ImageViewCreateInfo::from_image(input))?,
+                    sampler,
+                ),
+                WriteDescriptorSet::image_view(
+                    2,
+                    ImageView::new(
+                        rectified.clone(),
+                        ImageViewCreateInfo::from_image(&rectified),
+                    )?,
+                ),
+            ],
+            None,
+        )?;
+
+        // Matching pass
+        let match_pipeline = Self::compute_pipeline(
+            device.clone(),
+            matching::load(device.clone())?.entry_point("main").unwrap(),
+        )?;
+        let match_parameters = uniform_buffer(
+            allocator.clone(),
+            matching::Parameters {
+                maxDisparity: (cfg.max_disparity as i32).into(),
+                window: (cfg.window as i32).into(),
+                // Rectified images are `size` pixels wide, with the same fov definition
+                // as the undistorted images.
+                focalBaseline: (rectification.fov * size as f32 * rectification.baseline).into(),
+                uniqueness: cfg.uniqueness.into(),
+            },
+        )?;
+        let match_desc_set = DescriptorSet::new(
+            descriptor_set_allocator.clone(),
+            match_pipeline.layout().set_layouts()[0].clone(),
+            [
+                WriteDescriptorSet::buffer(0, match_parameters),
+                WriteDescriptorSet::image_view(
+                    1,
+                    ImageView::new(
+                        rectified.clone(),
+                        ImageViewCreateInfo::from_image(&rectified),
+                    )?,
+                ),
+                WriteDescriptorSet::image_view(
+                    2,
+                    ImageView::new(depth.clone(), ImageViewCreateInfo::from_image(&depth))?,
+                ),
+            ],
+            None,
+        )?;
+
+        // Visualisation and mask passes
+        let visualize_pass = FullscreenPass::new(
+            device.clone(),
+            visualize::load(device.clone())?
+                .entry_point("main")
+                .unwrap(),
+            [w, h],
+        )?;
+        let visualize_parameters = uniform_buffer(
+            allocator.clone(),
+            visualize::Parameters {
+                near: VISUALIZE_RANGE.0.into(),
+                far: VISUALIZE_RANGE.1.into(),
+                outputSize: [w as f32, h as f32],
+            },
+        )?;
+        // Linear filtering of R32_SFLOAT is not universally supported
+        let nearest_sampler = Sampler::new(
+            device.clone(),
+            SamplerCreateInfo {
+                mag_filter: Filter::Nearest,
+                min_filter: Filter::Nearest,
+                ..Default::default()
+            },
+        )?;
+        let visualize_desc_set = DescriptorSet::new(
+            descriptor_set_allocator.clone(),
+            visualize_pass.descriptor_set_layout().clone(),
+            [
+                WriteDescriptorSet::image_view_sampler(
+                    0,
+                    ImageView::new(depth.clone(), ImageViewCreateInfo::from_image(&depth))?,
+                    nearest_sampler.clone(),
+                ),
+                WriteDescriptorSet::buffer(1, visualize_parameters),
+            ],
+            None,
+        )?;
+        let mask_pass = FullscreenPass::new(
+            device.clone(),
+            mask::load(device.clone())?.entry_point("main").unwrap(),
+            [w, h],
+        )?;
+        let mask_parameters = uniform_buffer(
+            allocator.clone(),
+            mask::Parameters {
+                cameraToRect: rectification
+                    .rotations
+                    .map(|r| *r.transpose().to_homogeneous().as_ref()),
+                fov: [fov[0][0], fov[0][1], fov[1][0], fov[1][1]],
+                rectFov: rectification.fov.into(),
+                distance: 0.0f32.into(),
+                fade: 0.0f32.into(),
+            },
+        )?;
+        let mask_desc_set = DescriptorSet::new(
+            descriptor_set_allocator,
+            mask_pass.descriptor_set_layout().clone(),
+            [
+                WriteDescriptorSet::image_view_sampler(
+                    0,
+                    ImageView::new(input.clone(), ImageViewCreateInfo::from_image(input))?,
+                    nearest_sampler.clone(),
+                ),
+                WriteDescriptorSet::image_view_sampler(
+                    1,
+                    ImageView::new(depth.clone(), ImageViewCreateInfo::from_image(&depth))?,
+                    nearest_sampler,
+                ),
+                WriteDescriptorSet::buffer(2, mask_parameters.clone()),
+            ],
+            None,
+        )?;
+        Ok(Self {
+            device,
+            rectify_pipeline,
+            rectify_desc_set,
+            match_pipeline,
+            match_desc_set,
+            visualize_pass,
+            visualize_desc_set,
+            mask_pass,
+            mask_desc_set,
+            mask_parameters,
+            depth,
+            rectification,
+        })
+    }
+    fn compute_pipeline(
+        device: Arc<Device>,
+        entry_point: vulkano::shader::EntryPoint,
+    ) -> Result<Arc<ComputePipeline>> {
+        let stage = PipelineShaderStageCreateInfo::new(entry_point);
+        let layout = PipelineLayout::new(
+            device.clone(),
+            PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+                .into_pipeline_layout_create_info(device.clone())?,
+        )?;
+        Ok(ComputePipeline::new(
+            device,
+            None,
+            ComputePipelineCreateInfo::stage_layout(stage, layout),
+        )?)
+    }
+    pub fn depth_map(&self) -> DepthMap {
+        DepthMap {
+            image: self.depth.clone(),
+            rectification: self.rectification,
+        }
+    }
+    /// Estimate depth from the input image given to `new`.
+    pub fn estimate(
+        &self,
+        cmdbuf_allocator: Arc<dyn CommandBufferAllocator>,
+        after: impl GpuFuture,
+        queue: &Arc<Queue>,
+    ) -> Result<impl GpuFuture> {
+        if queue.device() != &self.device {
+            return Err(anyhow!("Device mismatch"));
+        }
+        if let Some(after_queue) = after.queue() {
+            if &after_queue != queue {
+                return Err(anyhow!("Queue mismatch"));
+            }
+        }
+        let [w, h, _] = self.depth.extent();
+        let groups = [w.div_ceil(8), h.div_ceil(8), 1];
+        let mut cmdbuf = RecordingCommandBuffer::new(
+            cmdbuf_allocator,
+            queue.queue_family_index(),
+            CommandBufferLevel::Primary,
+            CommandBufferBeginInfo {
+                usage: OneTimeSubmit,
+                ..Default::default()
+            },
+        )?;
+        cmdbuf
+            .bind_pipeline_compute(self.rectify_pipeline.clone())?
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.rectify_pipeline.layout().clone(),
+                0,
+                self.rectify_desc_set.clone(),
+            )?
+            .dispatch(groups)?
+            .bind_pipeline_compute(self.match_pipeline.clone())?
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.match_pipeline.layout().clone(),
+                0,
+                self.match_desc_set.clone(),
+            )?
+            .dispatch(groups)?;
+        Ok(after.then_execute(queue.clone(), cmdbuf.end()?)?)
+    }
+    /// Render a colour coded depth map into `output`, for debugging.
+    pub fn visualize(
+        &self,
+        allocator: Arc<dyn MemoryAllocator>,
+        cmdbuf_allocator: Arc<dyn CommandBufferAllocator>,
+        after: impl GpuFuture,
+        queue: &Arc<Queue>,
+        output: Arc<Image>,
+    ) -> Result<impl GpuFuture> {
+        self.visualize_pass.apply(
+            allocator,
+            cmdbuf_allocator,
+            after,
+            queue,
+            &self.visualize_desc_set,
+            output,
+            |_| Ok(()),
+        )
+    }
+    /// Write the undistorted image into `output`, with everything further than `distance`
+    /// made transparent. Pixels fade out over `fade` meters before `distance`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn mask(
+        &self,
+        allocator: Arc<dyn MemoryAllocator>,
+        cmdbuf_allocator: Arc<dyn CommandBufferAllocator>,
+        after: impl GpuFuture,
+        queue: &Arc<Queue>,
+        output: Arc<Image>,
+        distance: f32,
+        fade: f32,
+    ) -> Result<impl GpuFuture> {
+        {
+            let mut parameters = self.mask_parameters.write()?;
+            parameters.distance = distance.into();
+            parameters.fade = fade.into();
+        }
+        self.mask_pass.apply(
+            allocator,
+            cmdbuf_allocator,
+            after,
+            queue,
+            &self.mask_desc_set,
+            output,
+            |_| Ok(()),
+        )
+    }
+}
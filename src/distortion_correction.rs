@@ -1,20 +1,21 @@
 use anyhow::{anyhow, Result};
 use log::{info, trace};
 use smallvec::smallvec;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use vulkano::{
-    buffer::{Buffer, BufferCreateInfo, BufferUsage},
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
     command_buffer::{
         allocator::CommandBufferAllocator, CommandBufferBeginInfo, CommandBufferLevel,
-        CommandBufferUsage::OneTimeSubmit, RecordingCommandBuffer, RenderPassBeginInfo,
-        SubpassBeginInfo, SubpassContents, SubpassEndInfo,
+        CommandBufferUsage::OneTimeSubmit, CopyBufferToImageInfo, RecordingCommandBuffer,
+        RenderPassBeginInfo, SubpassBeginInfo, SubpassContents, SubpassEndInfo,
     },
     descriptor_set::{allocator::DescriptorSetAllocator, DescriptorSet, WriteDescriptorSet},
     device::{Device, Queue},
+    format::{Format, FormatFeatures},
     image::{
         sampler::{Filter, Sampler, SamplerCreateInfo},
         view::{ImageView, ImageViewCreateInfo},
-        Image, ImageLayout,
+        Image, ImageCreateInfo, ImageLayout, ImageUsage,
     },
     memory::allocator::{
         AllocationCreateInfo, MemoryAllocatePreference, MemoryAllocator, MemoryTypeFilter,
@@ -39,6 +40,12 @@ use vulkano::{
     Handle, VulkanObject,
 };
 
+use crate::{
+    config::{CorrectionConfig, OutputFov},
+    utils::DeviceExt as _,
+    vrapi::{Distort, DistortionModel},
+};
+
 #[derive(VertexTrait, Default, Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 #[allow(non_snake_case)]
 #[repr(C)]
@@ -49,6 +56,10 @@ struct Vertex {
     inCoord: [f32; 2],
 }
 
+/// Largest undistorted normalized image coordinate the output fov is searched up to, ~84
+/// degrees off axis
+const MAX_EXTENT: f64 = 10.0;
+
 /// Lens correction for a stereo side-by-side image
 pub struct StereoCorrection {
     device: Arc<Device>,
@@ -57,6 +68,33 @@ pub struct StereoCorrection {
     desc_sets: [Arc<DescriptorSet>; 2],
     /// field-of-view parameter, 0 = left eye, 1 = right eye
     fov: [[f32; 2]; 2],
+    /// Per-eye remap textures, if the lookup table is used
+    lookup_tables: Option<[Arc<Image>; 2]>,
+    /// Contents of `lookup_tables`, uploaded with the first frame
+    pending_upload: Mutex<Option<[Subbuffer<[[f32; 2]]>; 2]>>,
+    lut_sampler: Arc<Sampler>,
+}
+
+/// The lens correction lookup tables, used by `Projection` to sample the camera image
+/// before lens correction. See `CorrectionConfig::fused`.
+#[derive(Clone)]
+pub struct LensRemap {
+    /// Texture coordinates into the side-by-side camera image, for every pixel of the
+    /// undistorted image of the left and right eye.
+    pub lookup_tables: [Arc<Image>; 2],
+    pub sampler: Arc<Sampler>,
+}
+
+impl std::fmt::Debug for LensRemap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LensRemap")
+            .field(
+                "lookup_tables",
+                &self.lookup_tables.each_ref().map(|x| x.handle().as_raw()),
+            )
+            .field("sampler", &self.sampler.handle().as_raw())
+            .finish()
+    }
 }
 
 impl std::fmt::Debug for StereoCorrection {
@@ -72,6 +110,13 @@ impl std::fmt::Debug for StereoCorrection {
                 &self.pipelines.each_ref().map(|x| x.handle().as_raw()),
             )
             .field("fov", &self.fov)
+            .field(
+                "lookup_tables",
+                &self
+                    .lookup_tables
+                    .as_ref()
+                    .map(|l| l.each_ref().map(|x| x.handle().as_raw())),
+            )
             .finish_non_exhaustive()
     }
 }
@@ -80,64 +125,187 @@ impl StereoCorrection {
     pub fn fov(&self) -> [[f32; 2]; 2] {
         self.fov
     }
-    /// i.e. solving Undistort(src) = dst for the smallest non-zero root.
-    fn undistort_inverse(coeff: &[f64; 4], dst: f64) -> Option<f64> {
-        // solving: x * (1 + k1*x^2 + k2*x^4 + k3*x^6 + k4*x^8) - dst = 0
-        let f = |x: f64| {
-            let x2 = x * x;
-            x * (1.0 + x2 * (coeff[0] + x2 * (coeff[1] + x2 * (coeff[2] + x2 * coeff[3])))) - dst
-        };
-        let fp = |x: f64| {
-            let x2 = x * x;
-            1.0 + x2
-                * (3.0 * coeff[0]
-                    + x2 * (5.0 * coeff[1] + x2 * (7.0 * coeff[2] + x2 * 9.0 * coeff[3])))
-        };
-        const MAX_ITER: u32 = 100;
-        let mut x = 0.0;
-        for _ in 0..MAX_ITER {
-            if fp(x) == 0.0 {
-                // Give up
-                info!("Divided by zero");
-                return None;
+    /// The lookup tables, if they are used.
+    pub fn lens_remap(&self) -> Option<LensRemap> {
+        self.lookup_tables.clone().map(|lookup_tables| LensRemap {
+            lookup_tables,
+            sampler: self.lut_sampler.clone(),
+        })
+    }
+    /// Whether the lookup tables still need to be uploaded. This is done by `correct`, or
+    /// `upload_lookup_tables` if `correct` is not used.
+    pub fn has_pending_upload(&self) -> bool {
+        self.pending_upload.lock().unwrap().is_some()
+    }
+    /// Upload the lookup tables, without doing the correction.
+    pub fn upload_lookup_tables(
+        &self,
+        cmdbuf_allocator: Arc<dyn CommandBufferAllocator>,
+        after: impl GpuFuture,
+        queue: &Arc<Queue>,
+    ) -> Result<impl GpuFuture> {
+        let mut cmdbuf = RecordingCommandBuffer::new(
+            cmdbuf_allocator,
+            queue.queue_family_index(),
+            CommandBufferLevel::Primary,
+            CommandBufferBeginInfo {
+                usage: OneTimeSubmit,
+                ..Default::default()
+            },
+        )?;
+        self.record_upload(&mut cmdbuf)?;
+        Ok(after.then_execute(queue.clone(), cmdbuf.end()?)?)
+    }
+    fn record_upload(&self, cmdbuf: &mut RecordingCommandBuffer) -> Result<()> {
+        if let (Some(lookup_tables), Some(staging)) = (
+            &self.lookup_tables,
+            self.pending_upload.lock().unwrap().take(),
+        ) {
+            for (buffer, lut) in staging.into_iter().zip(lookup_tables) {
+                cmdbuf.copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(
+                    buffer,
+                    lut.clone(),
+                ))?;
             }
-            trace!("{} {} {}", x, f(x), fp(x));
-            if f(x).abs() < 1e-6 {
-                info!("Inverse is: {}, {} {}", x, f(x), dst);
-                return Some(x);
+        }
+        Ok(())
+    }
+    /// Half size of the output image on each axis, in undistorted normalized image coordinates,
+    /// i.e. the tangent of half the output fov.
+    fn output_extent(
+        distortion: &Distort,
+        center: &[f64; 2],
+        focal: &[f64; 2],
+        fov: OutputFov,
+    ) -> Option<[f64; 2]> {
+        match fov {
+            OutputFov::Degrees(degrees) => {
+                let extent = (degrees.clamp(1.0, 179.0) as f64).to_radians() / 2.0;
+                Some([extent.tan(); 2])
+            }
+            // Map the middle point of the 4 edges of the output image to the edge of the
+            // camera image.
+            OutputFov::Fill => [0, 1].try_map(|i| {
+                let min_edge_dist = center[i].min(1.0 - center[i]) / focal[i];
+                // Find the undistorted coordinates on this axis that are distorted to
+                // min_edge_dist.
+                let edge = |t: f64| {
+                    let mut p = [0.0; 2];
+                    p[i] = t;
+                    distort(distortion, p)[i]
+                };
+                Self::find_edge(edge, min_edge_dist)
+            }),
+            // Make the output image contain the 4 corners of the camera image. Corners further
+            // off axis than the output can show, which is the case for the Index cameras, are
+            // clamped to the largest extent it can.
+            OutputFov::Fit => {
+                let mut extent = [0.0f64; 2];
+                for corner in [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0], [1.0, 1.0]] {
+                    let d = [0, 1].map(|i| (corner[i] - center[i]) / focal[i]);
+                    let dist = d[0].hypot(d[1]);
+                    let dir = d.map(|x| x / dist);
+                    let radius = |t: f64| {
+                        let p = distort(distortion, dir.map(|x| x * t));
+                        p[0].hypot(p[1])
+                    };
+                    let t = Self::find_edge(radius, dist).unwrap_or(MAX_EXTENT);
+                    for i in 0..2 {
+                        extent[i] = extent[i].max((dir[i] * t).abs());
+                    }
+                }
+                Some(extent)
             }
-            x = x - f(x) / fp(x);
         }
-        // Give up
-        info!("Cannot find scale");
-        None
     }
-    // Find a scale that maps the middle point of 4 edges of the undistorted image to
-    // the edge of the field of view of the distorted image.
-    //
-    // Returns the scales and the adjusted fovs
-    fn find_scale(coeff: &[f64; 4], center: &[f64; 2], focal: &[f64; 2]) -> [(f32, f32); 2] {
-        [0, 1].map(|i| {
-            let min_edge_dist = center[i].min(1.0 - center[i]) / focal[i];
-            // Find the input theta angle where Undistort(theta) = min_edge_dist
-            if let Some(theta) = Self::undistort_inverse(coeff, min_edge_dist) {
-                if theta >= std::f64::consts::PI / 2.0 {
-                    // infinity?
-                    (1.0, focal[i] as f32)
+    // Find the scale of the output image, and the adjusted fov, which is the projection
+    // factor of the output image: u = fov * x / z + 0.5.
+    fn find_scale(
+        distortion: &Distort,
+        center: &[f64; 2],
+        focal: &[f64; 2],
+        cfg: &CorrectionConfig,
+    ) -> Result<[(f32, f32); 2]> {
+        let extent = Self::output_extent(distortion, center, focal, cfg.fov).ok_or_else(|| {
+            anyhow!(
+                "Cannot find the output size for correction fov {:?}, set it to Degrees \
+                 instead",
+                cfg.fov
+            )
+        })?;
+        let zoom = cfg.zoom() as f64;
+        Ok([0, 1].map(|i| {
+            let edge = extent[i] / zoom;
+            ((edge / (0.5 / focal[i])) as f32, (0.5 / edge) as f32)
+        }))
+    }
+    /// The scales and adjusted fovs of the left and right cameras, see `find_scale`. `size`
+    /// is the size of one camera image in pixels.
+    pub fn scale_fov(
+        camera_calib: &crate::vrapi::StereoCamera,
+        size: f64,
+        cfg: &CorrectionConfig,
+    ) -> Result<[[(f32, f32); 2]; 2]> {
+        [&camera_calib.left, &camera_calib.right].try_map(|camera| {
+            let intrinsics = &camera.intrinsics;
+            Self::find_scale(
+                &intrinsics.distort,
+                &[intrinsics.center_x / size, intrinsics.center_y / size],
+                &[intrinsics.focal_x / size, intrinsics.focal_y / size],
+                cfg,
+            )
+        })
+    }
+    /// Find the smallest positive `t` where `f(t) = dst`, with `f(0) = 0`.
+    fn find_edge(f: impl Fn(f64) -> f64, dst: f64) -> Option<f64> {
+        const STEPS: u32 = 1000;
+        let mut lo = 0.0;
+        let hi = (1..=STEPS)
+            .map(|i| MAX_EXTENT * i as f64 / STEPS as f64)
+            .find(|&t| {
+                if f(t) >= dst {
+                    true
                 } else {
-                    // Find the input coordinates that will give us that theta
-                    let target_edge = theta.tan();
-                    log::info!("{}", target_edge);
-                    (
-                        (target_edge / (0.5 / focal[i])) as f32,
-                        (1.0 / min_edge_dist / 2.0) as f32,
-                    )
+                    lo = t;
+                    false
                 }
+            });
+        let Some(mut hi) = hi else {
+            info!("Cannot find scale");
+            return None;
+        };
+        for _ in 0..50 {
+            let mid = (lo + hi) / 2.0;
+            trace!("{} {}", mid, f(mid));
+            if f(mid) >= dst {
+                hi = mid;
             } else {
-                // Cannot find scale so just don't scale
-                (1.0, focal[i] as f32)
+                lo = mid;
             }
-        })
+        }
+        info!("Inverse is: {}, {} {}", hi, f(hi), dst);
+        Some(hi)
+    }
+    /// Compute where each output pixel of eye `id` samples the input texture, i.e. what
+    /// stereo_correction.frag computes for every pixel. The table is `size` by `size`.
+    fn bake_lookup_table(
+        distortion: &Distort,
+        center: &[f64; 2],
+        focal: &[f64; 2],
+        scale: [f32; 2],
+        id: usize,
+        size: u32,
+    ) -> Vec<[f32; 2]> {
+        (0..size)
+            .flat_map(|y| (0..size).map(move |x| [x, y]))
+            .map(|pixel| {
+                let coord = pixel.map(|v| (v as f64 + 0.5) / size as f64 - 0.5);
+                let r = [0, 1].map(|i| coord[i] * scale[i] as f64 / focal[i]);
+                let mapped = distort(distortion, r);
+                let mapped = [0, 1].map(|i| mapped[i] * focal[i] + center[i]);
+                [(mapped[0] * 0.5 + 0.5 * id as f64) as f32, mapped[1] as f32]
+            })
+            .collect()
     }
     /// Input size is (size * 2, size)
     /// returns also the adjusted FOV for left and right
@@ -153,11 +321,29 @@ impl StereoCorrection {
         descriptor_set_allocator: Arc<dyn DescriptorSetAllocator>,
         input: Arc<Image>,
         camera_calib: &crate::vrapi::StereoCamera,
+        cfg: &CorrectionConfig,
     ) -> Result<Self> {
         let [w, h, _] = input.extent();
         if w != h * 2 {
             return Err(anyhow!("Input not square"));
         }
+        let lut_linear = device
+            .physical_device()
+            .format_properties(Format::R32G32_SFLOAT)
+            .map(|p| {
+                p.optimal_tiling_features
+                    .intersects(FormatFeatures::SAMPLED_IMAGE_FILTER_LINEAR)
+            })
+            .unwrap_or(false);
+        // The fused path samples the lookup table from the projection shader
+        let lut_size = (cfg.lookup_table || cfg.fused).then(|| {
+            let mut downscale = cfg.lookup_table_downscale.max(1);
+            if downscale > 1 && !lut_linear {
+                log::warn!("Lookup table cannot be interpolated on this device, using full size");
+                downscale = 1;
+            }
+            h / downscale
+        });
         let size = h as f64;
         let center_left = [
             camera_calib.left.intrinsics.center_x / size,
@@ -175,10 +361,14 @@ impl StereoCorrection {
             camera_calib.right.intrinsics.focal_x / size,
             camera_calib.right.intrinsics.focal_y / size,
         ];
-        let coeff_left = camera_calib.left.intrinsics.distort.coeffs;
-        let coeff_right = camera_calib.left.intrinsics.distort.coeffs;
+        let distort_left = camera_calib.left.intrinsics.distort;
+        let distort_right = camera_calib.right.intrinsics.distort;
         let vs = vs::load(device.clone())?;
-        let fs = fs::load(device.clone())?;
+        let fs = if lut_size.is_some() {
+            fs_lut::load(device.clone())?
+        } else {
+            fs::load(device.clone())?
+        };
         let render_passes = [
             vulkano::single_pass_renderpass!(device.clone(),
                 attachments: {
@@ -266,17 +456,83 @@ impl StereoCorrection {
         )?;
 
         let coeffs = [
-            (0, coeff_left, center_left, focal_left),
-            (1, coeff_right, center_right, focal_right),
+            (0, distort_left, center_left, focal_left),
+            (1, distort_right, center_right, focal_right),
         ];
-        let scale_fov = coeffs
-            .each_ref()
-            .map(|(_, coeff, center, focal)| Self::find_scale(coeff, center, focal));
+        let scale_fov = Self::scale_fov(camera_calib, size, cfg)?;
+        let lookup_tables = lut_size
+            .map(|lut_size| {
+                coeffs.each_ref().try_map(|(id, distort, center, focal)| {
+                    let scale = [scale_fov[*id][0].0, scale_fov[*id][1].0];
+                    let table =
+                        Self::bake_lookup_table(distort, center, focal, scale, *id, lut_size);
+                    let staging = Buffer::from_iter(
+                        allocator.clone(),
+                        BufferCreateInfo {
+                            usage: BufferUsage::TRANSFER_SRC,
+                            ..Default::default()
+                        },
+                        AllocationCreateInfo {
+                            memory_type_filter: MemoryTypeFilter::HOST_SEQUENTIAL_WRITE
+                                | MemoryTypeFilter::PREFER_HOST,
+                            allocate_preference: MemoryAllocatePreference::Unknown,
+                            ..Default::default()
+                        },
+                        table,
+                    )?;
+                    let image = device.clone().new_image(
+                        ImageCreateInfo {
+                            extent: [lut_size, lut_size, 1],
+                            format: Format::R32G32_SFLOAT,
+                            usage: ImageUsage::TRANSFER_DST | ImageUsage::SAMPLED,
+                            ..Default::default()
+                        },
+                        MemoryTypeFilter::PREFER_DEVICE,
+                    )?;
+                    anyhow::Ok((image, staging))
+                })
+            })
+            .transpose()?;
+        let lut_filter = if lut_linear {
+            Filter::Linear
+        } else {
+            Filter::Nearest
+        };
+        let lut_sampler = Sampler::new(
+            device.clone(),
+            SamplerCreateInfo {
+                mag_filter: lut_filter,
+                min_filter: lut_filter,
+                ..Default::default()
+            },
+        )?;
         // Left pass
-        let desc_sets = coeffs.try_map(|(id, coeff, center, focal)| {
+        let desc_sets = coeffs.try_map(|(id, distort, center, focal)| {
+            let desc_set_layout = pipelines[id].layout().set_layouts().first().unwrap();
+            let input_view =
+                ImageView::new(input.clone(), ImageViewCreateInfo::from_image(&input))?;
+            if let Some(lookup_tables) = &lookup_tables {
+                let lut = &lookup_tables[id].0;
+                return Ok::<_, anyhow::Error>(DescriptorSet::new(
+                    descriptor_set_allocator.clone(),
+                    desc_set_layout.clone(),
+                    [
+                        WriteDescriptorSet::image_view_sampler(
+                            0,
+                            ImageView::new(lut.clone(), ImageViewCreateInfo::from_image(lut))?,
+                            lut_sampler.clone(),
+                        ),
+                        WriteDescriptorSet::image_view_sampler(1, input_view, sampler.clone()),
+                    ],
+                    None,
+                )?);
+            }
+            let coeffs = distort.model_coeffs().map(|x| x as f32);
             let uniform = fs::Parameters {
                 center: center.map(|x| x as f32),
-                dcoef: coeff.map(|x| x as f32),
+                dcoef: coeffs[..4].try_into().unwrap(),
+                dcoef2: coeffs[4..].try_into().unwrap(),
+                model: distort.model.shader_id(),
                 focal: focal.map(|x| x as f32),
                 sensorSize: (size as f32).into(),
                 scale: [scale_fov[id][0].0, scale_fov[id][1].0],
@@ -296,21 +552,23 @@ impl StereoCorrection {
                 },
                 uniform,
             )?;
-            let desc_set_layout = pipelines[id].layout().set_layouts().first().unwrap();
-            Ok::<_, anyhow::Error>(DescriptorSet::new(
+            Ok(DescriptorSet::new(
                 descriptor_set_allocator.clone(),
                 desc_set_layout.clone(),
                 [
                     WriteDescriptorSet::buffer(0, uniform),
-                    WriteDescriptorSet::image_view_sampler(
-                        1,
-                        ImageView::new(input.clone(), ImageViewCreateInfo::from_image(&input))?,
-                        sampler.clone(),
-                    ),
+                    WriteDescriptorSet::image_view_sampler(1, input_view, sampler.clone()),
                 ],
                 None,
             )?)
         })?;
+        let (lookup_tables, pending_upload) = match lookup_tables {
+            Some(l) => {
+                let [(left, left_staging), (right, right_staging)] = l;
+                (Some([left, right]), Some([left_staging, right_staging]))
+            }
+            None => (None, None),
+        };
 
         Ok(Self {
             device,
@@ -321,6 +579,9 @@ impl StereoCorrection {
                 [scale_fov[0][0].1, scale_fov[0][1].1],
                 [scale_fov[1][0].1, scale_fov[1][1].1],
             ],
+            lookup_tables,
+            pending_upload: Mutex::new(pending_upload),
+            lut_sampler,
         })
     }
     pub fn correct(
@@ -383,6 +644,7 @@ impl StereoCorrection {
             .cloned(),
         )
         .unwrap();
+        self.record_upload(&mut cmdbuf)?;
         for id in 0..2 {
             let framebuffer = Framebuffer::new(
                 self.render_passes[id].clone(),
@@ -419,6 +681,46 @@ impl StereoCorrection {
     }
 }
 
+/// Map undistorted normalized image coordinates (x/z, y/z) to distorted normalized image
+/// coordinates. Must be kept in sync with shaders/distortion.glsl
+pub fn distort(distort: &Distort, p: [f64; 2]) -> [f64; 2] {
+    let c = distort.model_coeffs();
+    let tangential = |p: [f64; 2], r2: f64, p1: f64, p2: f64| {
+        [
+            2.0 * p1 * p[0] * p[1] + p2 * (r2 + 2.0 * p[0] * p[0]),
+            p1 * (r2 + 2.0 * p[1] * p[1]) + 2.0 * p2 * p[0] * p[1],
+        ]
+    };
+    match distort.model {
+        DistortionModel::PlumbBob => {
+            let r2 = p[0] * p[0] + p[1] * p[1];
+            let radial = (1.0 + r2 * (c[0] + r2 * (c[1] + r2 * c[4])))
+                / (1.0 + r2 * (c[5] + r2 * (c[6] + r2 * c[7])));
+            let t = tangential(p, r2, c[2], c[3]);
+            [p[0] * radial + t[0], p[1] * radial + t[1]]
+        }
+        DistortionModel::Unified => {
+            let d = 1.0 + c[0] * (p[0] * p[0] + p[1] * p[1] + 1.0).sqrt();
+            let m = p.map(|x| x / d);
+            let r2 = m[0] * m[0] + m[1] * m[1];
+            let radial = 1.0 + r2 * (c[1] + r2 * c[2]);
+            let t = tangential(m, r2, c[3], c[4]);
+            [m[0] * radial + t[0], m[1] * radial + t[1]]
+        }
+        DistortionModel::FTheta | DistortionModel::KannalaBrandt => {
+            let r = p[0].hypot(p[1]);
+            if r == 0.0 {
+                return p;
+            }
+            let theta = r.atan();
+            let theta2 = theta * theta;
+            let poly = c.iter().rev().fold(0.0, |acc, k| k + theta2 * acc);
+            let theta_d = theta * (1.0 + theta2 * poly);
+            p.map(|x| theta_d / r * x)
+        }
+    }
+}
+
 mod vs {
     vulkano_shaders::shader! {
         ty: "vertex",
@@ -440,3 +742,9 @@ mod fs {
         custom_derives: [Copy, Clone, Debug],
     }
 }
+mod fs_lut {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "shaders/stereo_correction_lut.frag",
+    }
+}
@@ -0,0 +1,422 @@
+//! Extra overlays, showing the same processed camera frame as the main overlay, each with its
+//! own position, shape, size and display mode. See `ExtraOverlayConfig`.
+//!
+//! The VR backends copy every processed frame into a shared image before showing it on the
+//! main overlay. Extra overlays with projection project that image like the main overlay
+//! does, the others copy it into their own texture and draw their mask over it. All of that
+//! is chained into the GPU work of the frame, see `ExtraOverlays`, only submitting the
+//! rendered images to the VR runtime is left to the backends.
+use std::sync::Arc;
+
+use nalgebra::Matrix4;
+use vulkano::{
+    command_buffer::{
+        allocator::CommandBufferAllocator, CommandBufferBeginInfo, CommandBufferExecError,
+        CommandBufferLevel, CommandBufferUsage::OneTimeSubmit, CopyImageInfo,
+        RecordingCommandBuffer,
+    },
+    descriptor_set::allocator::DescriptorSetAllocator,
+    device::{Device, Queue},
+    image::{AllocateImageError, Image, ImageLayout},
+    memory::allocator::MemoryAllocator,
+    sync::GpuFuture,
+    Validated, ValidationError, VulkanError,
+};
+
+use crate::{
+    config::{DisplayMode, ExtraOverlayConfig, OverlayShape, PositionMode},
+    out_of_view::OutOfView,
+    overlay_mask::{Mask, MaskError, OverlayMask},
+    projection::{Projection, ProjectorError},
+    vrapi::StereoCamera,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ExtraOverlayError {
+    #[error("vulkan error {0}")]
+    Vulkan(#[from] Validated<VulkanError>),
+    #[error("command buffer execution error: {0}")]
+    CommandBuffer(#[from] CommandBufferExecError),
+    #[error("projector error: {0}")]
+    Projector(#[from] ProjectorError),
+    #[error("overlay mask error: {0}")]
+    Mask(#[from] MaskError),
+    #[error("vulkan image allocation error: {0}")]
+    ImageAllocation(#[from] Validated<AllocateImageError>),
+}
+
+impl From<Box<ValidationError>> for ExtraOverlayError {
+    fn from(value: Box<ValidationError>) -> Self {
+        Self::Vulkan(Validated::from(value))
+    }
+}
+
+/// Replace what extra overlays can't do with the closest thing they can, with a warning.
+/// `Proximity` and `Depth` are baked into the processed frame, so only the main overlay can
+/// use them, and only the main overlay can cover the whole view.
+pub(crate) fn sanitize(name: &str, cfg: &ExtraOverlayConfig) -> ExtraOverlayConfig {
+    let mut cfg = cfg.clone();
+    if cfg.display_mode.changes_frame() {
+        let fallback = match cfg.display_mode {
+            DisplayMode::Proximity {
+                projection_mode, ..
+            } => DisplayMode::Stereo { projection_mode },
+            _ => DisplayMode::Direct,
+        };
+        log::warn!(
+            "Overlay {name}: {:?} is only available for the main overlay, using {:?}",
+            cfg.display_mode,
+            fallback
+        );
+        cfg.display_mode = fallback;
+    }
+    if cfg.shape == OverlayShape::FullView {
+        log::warn!(
+            "Overlay {name}: full view is only available for the main overlay, using a flat \
+             overlay"
+        );
+        cfg.shape = OverlayShape::Flat;
+    }
+    cfg
+}
+
+/// Copy `source` into `output` after `after`.
+fn copy_frame(
+    cmdbuf_allocator: Arc<dyn CommandBufferAllocator>,
+    after: impl GpuFuture,
+    queue: &Arc<Queue>,
+    source: Arc<Image>,
+    output: Arc<Image>,
+) -> Result<impl GpuFuture, ExtraOverlayError> {
+    let mut cmdbuf = RecordingCommandBuffer::new(
+        cmdbuf_allocator,
+        queue.queue_family_index(),
+        CommandBufferLevel::Primary,
+        CommandBufferBeginInfo {
+            usage: OneTimeSubmit,
+            ..Default::default()
+        },
+    )?;
+    cmdbuf.copy_image(CopyImageInfo::images(source, output))?;
+    Ok(after.then_execute(queue.clone(), cmdbuf.end()?)?)
+}
+
+/// The parts of an extra overlay that don't depend on the VR backend: where it is, and how
+/// the shared camera frame is rendered onto it.
+pub(crate) struct ExtraOverlay {
+    pub(crate) name: String,
+    pub(crate) display_mode: DisplayMode,
+    position_mode: PositionMode,
+    reposition: bool,
+    pub(crate) shape: OverlayShape,
+    /// Width and height of the overlay, in meters
+    pub(crate) size: [f32; 2],
+    /// Only in display modes with projection, it draws the mask too
+    projector: Option<Projection>,
+    out_of_view: OutOfView,
+    /// Only in display modes without projection
+    mask: Option<OverlayMask>,
+    has_mask: bool,
+}
+
+impl std::fmt::Debug for ExtraOverlay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExtraOverlay")
+            .field("name", &self.name)
+            .field("display_mode", &self.display_mode)
+            .field("position_mode", &self.position_mode)
+            .field("shape", &self.shape)
+            .field("size", &self.size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ExtraOverlay {
+    /// Set up the overlay `name` for a config that went through `sanitize`. Projections read
+    /// from `shared_frame`, and leave the output in `final_layout`.
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        name: &str,
+        cfg: &ExtraOverlayConfig,
+        device: Arc<Device>,
+        allocator: Arc<dyn MemoryAllocator>,
+        descriptor_set_allocator: Arc<dyn DescriptorSetAllocator>,
+        shared_frame: &Arc<Image>,
+        camera_calib: &Option<StereoCamera>,
+        depth_map: Option<&crate::depth::DepthMap>,
+        lens_remap: Option<&crate::distortion_correction::LensRemap>,
+        mask: Option<&Arc<Mask>>,
+        fixed_depth: f32,
+        final_layout: ImageLayout,
+    ) -> Result<Self, ExtraOverlayError> {
+        let size = cfg.size();
+        let has_mask = mask.is_some();
+        let out_of_view = OutOfView::new(cfg.out_of_view);
+        let (projector, mask) = if let Some(projection_mode) = cfg.display_mode.projection_mode() {
+            let mut projector = Projection::new(
+                device.clone(),
+                allocator.clone(),
+                descriptor_set_allocator.clone(),
+                shared_frame,
+                size,
+                camera_calib,
+                depth_map,
+                lens_remap,
+                mask,
+                final_layout,
+            )?;
+            projector.set_mode(projection_mode);
+            projector.set_overlay_radius(cfg.shape.radius(size[0]));
+            projector.set_fixed_depth(fixed_depth);
+            if out_of_view.needs_indicator() {
+                projector.enable_indicator(device, allocator, descriptor_set_allocator)?;
+            }
+            (Some(projector), None)
+        } else {
+            let mask = mask
+                .map(|mask| OverlayMask::standalone(device, descriptor_set_allocator, mask))
+                .transpose()?;
+            (None, mask)
+        };
+        Ok(Self {
+            name: name.to_owned(),
+            display_mode: cfg.display_mode,
+            position_mode: cfg.position,
+            // Sticky overlays start where the user is looking
+            reposition: matches!(cfg.position, PositionMode::Sticky { .. }),
+            shape: cfg.shape,
+            size,
+            projector,
+            out_of_view,
+            mask,
+            has_mask,
+        })
+    }
+    /// Whether the camera frame is projected onto the overlay, instead of copied
+    pub(crate) fn is_projected(&self) -> bool {
+        self.projector.is_some()
+    }
+    /// Whether the overlay has a mask, so parts of it are transparent
+    pub(crate) fn has_mask(&self) -> bool {
+        self.has_mask
+    }
+    /// Change the shape, e.g. if the VR runtime doesn't support the configured one
+    pub(crate) fn set_shape(&mut self, shape: OverlayShape) {
+        self.shape = shape;
+        if let Some(projector) = &mut self.projector {
+            projector.set_overlay_radius(shape.radius(self.size[0]));
+        }
+    }
+    pub(crate) fn set_fixed_depth(&mut self, distance: f32) {
+        if let Some(projector) = &mut self.projector {
+            projector.set_fixed_depth(distance);
+        }
+    }
+    /// Move a sticky overlay to where the user is looking, the next time it is placed
+    pub(crate) fn reposition(&mut self) {
+        if matches!(self.position_mode, PositionMode::Sticky { .. }) {
+            self.reposition = true;
+        }
+    }
+    /// Where the overlay is shown for the HMD pose at display time, `None` if it is hidden.
+    /// With projection, this also sets up the projector, see `OutOfView::place`.
+    fn placement(
+        &mut self,
+        fov: &[[f32; 2]; 2],
+        view_transforms: &[Matrix4<f32>; 2],
+        display_hmd_transform: &Matrix4<f32>,
+        capture_hmd_transform: &Matrix4<f32>,
+    ) -> Result<Option<Matrix4<f32>>, ExtraOverlayError> {
+        if std::mem::take(&mut self.reposition) {
+            self.position_mode.reposition(*display_hmd_transform);
+        }
+        let transform: Matrix4<f32> = self.position_mode.transform(*display_hmd_transform).into();
+        let Some(projector) = &mut self.projector else {
+            return Ok(Some(transform));
+        };
+        Ok(self.out_of_view.place(
+            projector,
+            transform,
+            fov,
+            view_transforms,
+            display_hmd_transform,
+            capture_hmd_transform,
+        )?)
+    }
+    /// Render the shared frame into `output` after `after`: project it, or copy it and draw
+    /// the mask over it.
+    fn render(
+        &mut self,
+        allocator: Arc<dyn MemoryAllocator>,
+        cmdbuf_allocator: Arc<dyn CommandBufferAllocator>,
+        after: Box<dyn GpuFuture>,
+        queue: &Arc<Queue>,
+        ipd: f32,
+        shared_frame: Arc<Image>,
+        output: Arc<Image>,
+    ) -> Result<Box<dyn GpuFuture>, ExtraOverlayError> {
+        if let Some(projector) = &mut self.projector {
+            projector.set_ipd(ipd);
+            return Ok(projector
+                .project(allocator, cmdbuf_allocator, after, queue, output)?
+                .boxed());
+        }
+        let future = copy_frame(
+            cmdbuf_allocator.clone(),
+            after,
+            queue,
+            shared_frame,
+            output.clone(),
+        )?;
+        Ok(match &self.mask {
+            Some(mask) => mask.apply(cmdbuf_allocator, future, queue, output)?.boxed(),
+            None => future.boxed(),
+        })
+    }
+}
+
+/// The extra overlays of a VR backend, and the copy of the processed frame they show. `T` is
+/// the backend's part of each overlay, which submits it to the VR runtime.
+pub(crate) struct ExtraOverlays<T> {
+    /// Copy of the last processed camera frame, created with the first overlay
+    shared_frame: Option<Arc<Image>>,
+    overlays: Vec<(ExtraOverlay, T)>,
+}
+
+impl<T> Default for ExtraOverlays<T> {
+    fn default() -> Self {
+        Self {
+            shared_frame: None,
+            overlays: Vec::new(),
+        }
+    }
+}
+
+impl<T> ExtraOverlays<T> {
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &(ExtraOverlay, T)> {
+        self.overlays.iter()
+    }
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = &mut (ExtraOverlay, T)> {
+        self.overlays.iter_mut()
+    }
+    /// Set up the overlay `name`, see `ExtraOverlay::new`. It is shown once `push`ed with
+    /// the backend's part.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn create(
+        &mut self,
+        name: &str,
+        cfg: &ExtraOverlayConfig,
+        device: Arc<Device>,
+        allocator: Arc<dyn MemoryAllocator>,
+        descriptor_set_allocator: Arc<dyn DescriptorSetAllocator>,
+        camera_calib: &Option<StereoCamera>,
+        depth_map: Option<&crate::depth::DepthMap>,
+        lens_remap: Option<&crate::distortion_correction::LensRemap>,
+        mask: Option<&Arc<Mask>>,
+        fixed_depth: f32,
+        final_layout: ImageLayout,
+    ) -> Result<ExtraOverlay, ExtraOverlayError> {
+        let shared_frame = match &self.shared_frame {
+            Some(shared_frame) => shared_frame.clone(),
+            None => self
+                .shared_frame
+                .insert(crate::create_submittable_image(device.clone())?)
+                .clone(),
+        };
+        ExtraOverlay::new(
+            name,
+            cfg,
+            device,
+            allocator,
+            descriptor_set_allocator,
+            &shared_frame,
+            camera_calib,
+            depth_map,
+            lens_remap,
+            mask,
+            fixed_depth,
+            final_layout,
+        )
+    }
+    pub(crate) fn push(&mut self, overlay: ExtraOverlay, target: T) {
+        self.overlays.push((overlay, target));
+    }
+    /// Copy the processed frame into the shared frame after `after`, if there are extra
+    /// overlays. Must come before `render`.
+    pub(crate) fn copy_frame(
+        &self,
+        cmdbuf_allocator: Arc<dyn CommandBufferAllocator>,
+        after: Box<dyn GpuFuture>,
+        queue: &Arc<Queue>,
+        frame: Arc<Image>,
+    ) -> Result<Box<dyn GpuFuture>, ExtraOverlayError> {
+        Ok(match &self.shared_frame {
+            Some(shared_frame) => {
+                copy_frame(cmdbuf_allocator, after, queue, frame, shared_frame.clone())?.boxed()
+            }
+            None => after,
+        })
+    }
+    /// Place the overlays for the HMD pose at display time, and render the shared frame onto
+    /// them after `after`. Overlays without projection are only rendered if there is a
+    /// `new_frame`, there is nothing to warp otherwise. See `Projection::update_mvps` for the
+    /// transforms.
+    ///
+    /// `place` gets where each overlay is shown, `None` if it is hidden. `output` gives the
+    /// image to render an overlay into, which the backend can submit once the returned
+    /// future is done.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn render<E: From<ExtraOverlayError>>(
+        &mut self,
+        allocator: Arc<dyn MemoryAllocator>,
+        cmdbuf_allocator: Arc<dyn CommandBufferAllocator>,
+        after: Box<dyn GpuFuture>,
+        queue: &Arc<Queue>,
+        fov: &[[f32; 2]; 2],
+        view_transforms: &[Matrix4<f32>; 2],
+        display_hmd_transform: &Matrix4<f32>,
+        capture_hmd_transform: &Matrix4<f32>,
+        ipd: f32,
+        new_frame: bool,
+        mut place: impl FnMut(&mut T, &ExtraOverlay, Option<Matrix4<f32>>) -> Result<(), E>,
+        mut output: impl FnMut(&mut T) -> Result<Arc<Image>, E>,
+    ) -> Result<Box<dyn GpuFuture>, E> {
+        let Some(shared_frame) = &self.shared_frame else {
+            return Ok(after);
+        };
+        let mut future = after;
+        for (overlay, target) in &mut self.overlays {
+            let placement = overlay.placement(
+                fov,
+                view_transforms,
+                display_hmd_transform,
+                capture_hmd_transform,
+            )?;
+            place(target, overlay, placement)?;
+            if placement.is_none() || (!overlay.is_projected() && !new_frame) {
+                continue;
+            }
+            future = overlay.render(
+                allocator.clone(),
+                cmdbuf_allocator.clone(),
+                future,
+                queue,
+                ipd,
+                shared_frame.clone(),
+                output(target)?,
+            )?;
+        }
+        Ok(future)
+    }
+    /// Move the sticky overlays to where the user is looking, the next time they are placed
+    pub(crate) fn reposition(&mut self) {
+        for (overlay, _) in &mut self.overlays {
+            overlay.reposition();
+        }
+    }
+    pub(crate) fn set_fixed_depth(&mut self, distance: f32) {
+        for (overlay, _) in &mut self.overlays {
+            overlay.set_fixed_depth(distance);
+        }
+    }
+}
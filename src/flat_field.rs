@@ -0,0 +1,328 @@
+//! Flat-field correction, to even out the brightness falloff towards the edges of the camera
+//! images (vignetting).
+//!
+//! The `flat-field` command captures the cameras looking at a uniformly lit surface, and
+//! stores how much each part of the image needs to be brightened as a `GainMap`. The
+//! `FlatField` stage multiplies the camera image with it, right after YUYV conversion.
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::{allocator::CommandBufferAllocator, CopyBufferToImageInfo},
+    descriptor_set::{allocator::DescriptorSetAllocator, DescriptorSet, WriteDescriptorSet},
+    device::{Device, Queue},
+    format::Format,
+    image::{
+        sampler::{Filter, Sampler, SamplerCreateInfo},
+        view::{ImageView, ImageViewCreateInfo},
+        Image, ImageCreateInfo, ImageUsage,
+    },
+    memory::allocator::{
+        AllocationCreateInfo, MemoryAllocatePreference, MemoryAllocator, MemoryTypeFilter,
+    },
+    sync::GpuFuture,
+    Handle, VulkanObject,
+};
+
+use crate::{
+    checkerboard::GrayImage, cli::FlatFieldCalibrate, config::Config, fullscreen::FullscreenPass,
+    utils::DeviceExt as _, CAMERA_SIZE,
+};
+
+/// Frames skipped before capturing, to let the auto exposure settle
+const SETTLE_FRAMES: u32 = 30;
+/// Luma of black in the camera's YUYV output, see yuyv2rgb.frag
+const BLACK_LEVEL: f32 = 0.0625;
+/// The brightest part of the flat-field capture must be at least this bright
+const MIN_BRIGHTNESS: f32 = 0.05;
+/// Brighter than this is probably overexposed
+const MAX_BRIGHTNESS: f32 = 0.9;
+/// Gains are limited to this, so the edges of the image circle are not blown up
+const MAX_GAIN: f32 = 4.0;
+
+/// How much each part of the camera images is brightened
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GainMap {
+    /// Width and height of the map of one eye
+    pub size: u32,
+    /// Gains of the left and right eye, row by row
+    pub gains: [Vec<f32>; 2],
+}
+
+impl GainMap {
+    /// Compute the gain map from the average luma of a flat-field capture, `luma` is
+    /// `image_size` by `image_size` for each eye.
+    fn from_luma(luma: &[Vec<f32>; 2], image_size: usize, size: usize) -> Result<Self> {
+        let gains = [0, 1].try_map(|eye| {
+            // Average over blocks, then smooth out the noise
+            let block = |i: usize| i * image_size / size..(i + 1) * image_size / size;
+            let blocks: Vec<f32> = (0..size)
+                .flat_map(|y| (0..size).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    let (rows, cols) = (block(y), block(x));
+                    let count = rows.len() * cols.len();
+                    rows.flat_map(|r| cols.clone().map(move |c| r * image_size + c))
+                        .map(|i| luma[eye][i])
+                        .sum::<f32>()
+                        / count as f32
+                })
+                .collect();
+            let at = |x: isize, y: isize| {
+                let clamp = |v: isize| v.clamp(0, size as isize - 1) as usize;
+                blocks[clamp(y) * size + clamp(x)]
+            };
+            let smoothed: Vec<f32> = (0..size as isize)
+                .flat_map(|y| (0..size as isize).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    (-1..=1)
+                        .flat_map(|dy| (-1..=1).map(move |dx| at(x + dx, y + dy)))
+                        .sum::<f32>()
+                        / 9.0
+                })
+                .collect();
+            let reference = smoothed.iter().copied().fold(0.0, f32::max);
+            ensure!(
+                reference >= MIN_BRIGHTNESS,
+                "The {} camera image is too dark, light the surface better",
+                ["left", "right"][eye]
+            );
+            if reference > MAX_BRIGHTNESS {
+                log::warn!(
+                    "The {} camera image looks overexposed, the correction will be too weak",
+                    ["left", "right"][eye]
+                );
+            }
+            anyhow::Ok(
+                smoothed
+                    .into_iter()
+                    .map(|v| {
+                        if v > 0.0 {
+                            (reference / v).min(MAX_GAIN)
+                        } else {
+                            MAX_GAIN
+                        }
+                    })
+                    .collect(),
+            )
+        })?;
+        Ok(Self {
+            size: size as u32,
+            gains,
+        })
+    }
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| anyhow!("Cannot read flat-field file {}", path.display()))?;
+        let map: Self = serde_json::from_str(&content)
+            .with_context(|| anyhow!("Invalid flat-field file {}", path.display()))?;
+        let len = map.size as usize * map.size as usize;
+        ensure!(
+            map.size > 0 && map.gains.iter().all(|g| g.len() == len),
+            "Flat-field file {} should have {len} gains for each eye",
+            path.display()
+        );
+        ensure!(
+            map.gains
+                .iter()
+                .flatten()
+                .all(|g| g.is_finite() && *g > 0.0),
+            "Flat-field file {} has gains that are not positive numbers",
+            path.display()
+        );
+        Ok(map)
+    }
+    /// Both eyes side by side, row by row
+    fn side_by_side(&self) -> impl Iterator<Item = f32> + '_ {
+        let size = self.size as usize;
+        (0..size).flat_map(move |row| {
+            self.gains
+                .iter()
+                .flat_map(move |gains| gains[row * size..(row + 1) * size].iter().copied())
+        })
+    }
+}
+
+/// `flat-field` command: capture a flat-field and write the gain map
+pub(crate) fn flat_field_calibrate(args: &FlatFieldCalibrate, cfg: &Config) -> Result<()> {
+    ensure!(args.frames > 0, "At least one frame is needed");
+    ensure!(
+        (1..=CAMERA_SIZE).contains(&args.size),
+        "The gain map size must be between 1 and {CAMERA_SIZE}"
+    );
+    let camera = crate::open_camera(&cfg.camera_device)?;
+    let mut video_stream =
+        v4l::prelude::MmapStream::with_buffers(&camera, v4l::buffer::Type::VideoCapture, 1)
+            .context("cannot open camera mmap stream")?;
+    println!(
+        "Point the cameras at a plain, evenly lit surface, close enough that it fills the whole \
+         view. A white wall or a sheet of paper works. Hold still."
+    );
+    let image_size = CAMERA_SIZE as usize;
+    let mut sums = [0, 1].map(|_| vec![0.0f32; image_size * image_size]);
+    for i in 0..SETTLE_FRAMES + args.frames {
+        let (frame_data, _) = v4l::io::traits::CaptureStream::next(&mut video_stream)?;
+        if i < SETTLE_FRAMES {
+            continue;
+        }
+        for (eye, sum) in sums.iter_mut().enumerate() {
+            let image = GrayImage::from_yuyv_eye(frame_data, image_size * 2, image_size, eye);
+            for (sum, luma) in sum.iter_mut().zip(image.data) {
+                *sum += (luma - BLACK_LEVEL).max(0.0);
+            }
+        }
+    }
+    let luma = sums.map(|sum| {
+        sum.into_iter()
+            .map(|v| v / args.frames as f32)
+            .collect::<Vec<_>>()
+    });
+    let map = GainMap::from_luma(&luma, image_size, args.size as usize)?;
+    for (name, gains) in ["Left", "Right"].into_iter().zip(&map.gains) {
+        let max = gains.iter().copied().fold(1.0, f32::max);
+        println!("{name} camera: edges are brightened up to {max:.2}x");
+    }
+    let content = serde_json::to_string(&map)?;
+    std::fs::write(&args.output, content)
+        .with_context(|| anyhow!("Cannot write gain map to {}", args.output.display()))?;
+    println!(
+        "Gain map written to {}, set `flat_field` in the config file to use it.",
+        args.output.display()
+    );
+    Ok(())
+}
+
+mod fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "shaders/flat_field.frag",
+    }
+}
+
+/// Flat-field correction of the whole side-by-side camera image
+pub struct FlatField {
+    pass: FullscreenPass,
+    desc_set: Arc<DescriptorSet>,
+    gain_texture: Arc<Image>,
+    /// Contents of `gain_texture`, uploaded with the first frame
+    pending_upload: Mutex<Option<Subbuffer<[f32]>>>,
+}
+
+impl std::fmt::Debug for FlatField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FlatField")
+            .field("pass", &self.pass)
+            .field("gain_texture", &self.gain_texture.handle().as_raw())
+            .finish_non_exhaustive()
+    }
+}
+
+impl FlatField {
+    /// Create a flat-field correction stage reading from `input`. The output image must
+    /// have the same size as the input.
+    pub fn new(
+        device: Arc<Device>,
+        allocator: Arc<dyn MemoryAllocator>,
+        descriptor_set_allocator: Arc<dyn DescriptorSetAllocator>,
+        input: &Arc<Image>,
+        gain_map: &GainMap,
+    ) -> Result<Self> {
+        let [w, h, _] = input.extent();
+        if gain_map.size == 0 {
+            bail!("Empty gain map");
+        }
+        let fs = fs::load(device.clone())?.entry_point("main").unwrap();
+        let pass = FullscreenPass::new(device.clone(), fs, [w, h])?;
+        let staging = Buffer::from_iter(
+            allocator,
+            BufferCreateInfo {
+                usage: BufferUsage::TRANSFER_SRC,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::HOST_SEQUENTIAL_WRITE
+                    | MemoryTypeFilter::PREFER_HOST,
+                allocate_preference: MemoryAllocatePreference::Unknown,
+                ..Default::default()
+            },
+            gain_map.side_by_side(),
+        )?;
+        let gain_texture = device.clone().new_image(
+            ImageCreateInfo {
+                extent: [gain_map.size * 2, gain_map.size, 1],
+                format: Format::R32_SFLOAT,
+                usage: ImageUsage::TRANSFER_DST | ImageUsage::SAMPLED,
+                ..Default::default()
+            },
+            MemoryTypeFilter::PREFER_DEVICE,
+        )?;
+        device.set_debug_utils_object_name(&gain_texture, Some("gain_texture"))?;
+        // Both textures are read with texelFetch
+        let sampler = Sampler::new(
+            device.clone(),
+            SamplerCreateInfo {
+                min_filter: Filter::Nearest,
+                mag_filter: Filter::Nearest,
+                ..Default::default()
+            },
+        )?;
+        let desc_set_layout = pass.descriptor_set_layout();
+        let desc_set = DescriptorSet::new(
+            descriptor_set_allocator,
+            desc_set_layout.clone(),
+            [
+                WriteDescriptorSet::image_view_sampler(
+                    0,
+                    ImageView::new(
+                        gain_texture.clone(),
+                        ImageViewCreateInfo::from_image(&gain_texture),
+                    )?,
+                    sampler.clone(),
+                ),
+                WriteDescriptorSet::image_view_sampler(
+                    1,
+                    ImageView::new(input.clone(), ImageViewCreateInfo::from_image(input))?,
+                    sampler,
+                ),
+            ],
+            None,
+        )?;
+        Ok(Self {
+            pass,
+            desc_set,
+            gain_texture,
+            pending_upload: Mutex::new(Some(staging)),
+        })
+    }
+    /// Apply the gain map, writing the result into `output`.
+    pub fn apply(
+        &self,
+        allocator: Arc<dyn MemoryAllocator>,
+        cmdbuf_allocator: Arc<dyn CommandBufferAllocator>,
+        after: impl GpuFuture,
+        queue: &Arc<Queue>,
+        output: Arc<Image>,
+    ) -> Result<impl GpuFuture> {
+        self.pass.apply(
+            allocator,
+            cmdbuf_allocator,
+            after,
+            queue,
+            &self.desc_set,
+            output,
+            |cmdbuf| {
+                if let Some(staging) = self.pending_upload.lock().unwrap().take() {
+                    cmdbuf.copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(
+                        staging,
+                        self.gain_texture.clone(),
+                    ))?;
+                }
+                Ok(())
+            },
+        )
+    }
+}
@@ -0,0 +1,225 @@
+//! A render pass running one fragment shader for every pixel of the output image, shared by
+//! the stages that process the whole side-by-side camera image pixel by pixel.
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use smallvec::smallvec;
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage},
+    command_buffer::{
+        allocator::CommandBufferAllocator, CommandBufferBeginInfo, CommandBufferLevel,
+        CommandBufferUsage::OneTimeSubmit, RecordingCommandBuffer, RenderPassBeginInfo,
+        SubpassBeginInfo, SubpassContents, SubpassEndInfo,
+    },
+    descriptor_set::{layout::DescriptorSetLayout, DescriptorSet},
+    device::{Device, DeviceOwned, Queue},
+    image::{
+        view::{ImageView, ImageViewCreateInfo},
+        Image,
+    },
+    memory::allocator::{
+        AllocationCreateInfo, MemoryAllocatePreference, MemoryAllocator, MemoryTypeFilter,
+    },
+    pipeline::{
+        graphics::{
+            color_blend::ColorBlendState,
+            input_assembly::{InputAssemblyState, PrimitiveTopology},
+            multisample::MultisampleState,
+            rasterization::RasterizationState,
+            vertex_input::{Vertex as VertexTrait, VertexDefinition},
+            viewport::{Viewport, ViewportState},
+            GraphicsPipelineCreateInfo,
+        },
+        layout::PipelineDescriptorSetLayoutCreateInfo,
+        GraphicsPipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    render_pass::{Framebuffer, FramebufferCreateInfo, RenderPass, Subpass},
+    shader::EntryPoint,
+    sync::GpuFuture,
+    Handle, VulkanObject,
+};
+
+mod vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        src: "#version 450
+layout(location = 0) in vec2 position;
+
+void main() {
+    gl_Position = vec4(position, 0, 1);
+}"
+    }
+}
+
+#[derive(VertexTrait, Default, Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+struct Vertex {
+    #[format(R32G32_SFLOAT)]
+    position: [f32; 2],
+}
+
+/// Draws a quad covering the whole output image with a fragment shader
+pub struct FullscreenPass {
+    device: Arc<Device>,
+    render_pass: Arc<RenderPass>,
+    pipeline: Arc<GraphicsPipeline>,
+}
+
+impl std::fmt::Debug for FullscreenPass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FullscreenPass")
+            .field("device", &self.device.handle().as_raw())
+            .field("render_pass", &self.render_pass.handle().as_raw())
+            .field("pipeline", &self.pipeline.handle().as_raw())
+            .finish()
+    }
+}
+
+impl FullscreenPass {
+    /// Create a pass running `fs` for every pixel of an R8G8B8A8_UNORM output image of the
+    /// given width and height.
+    pub fn new(device: Arc<Device>, fs: EntryPoint, [w, h]: [u32; 2]) -> Result<Self> {
+        let vs = vs::load(device.clone())?.entry_point("main").unwrap();
+        let render_pass = vulkano::single_pass_renderpass!(device.clone(),
+            attachments: {
+                color: {
+                    format: vulkano::format::Format::R8G8B8A8_UNORM,
+                    samples: 1,
+                    load_op: DontCare,
+                    store_op: Store,
+                }
+            },
+            pass: {
+                color: [color],
+                depth_stencil: {}
+            }
+        )?;
+        let stages = [
+            PipelineShaderStageCreateInfo::new(vs.clone()),
+            PipelineShaderStageCreateInfo::new(fs),
+        ];
+        let layout = PipelineLayout::new(
+            device.clone(),
+            PipelineDescriptorSetLayoutCreateInfo::from_stages(&stages)
+                .into_pipeline_layout_create_info(device.clone())?,
+        )?;
+        let pipeline = GraphicsPipeline::new(
+            device.clone(),
+            None,
+            GraphicsPipelineCreateInfo {
+                vertex_input_state: Some(
+                    Vertex::per_vertex().definition(&vs.info().input_interface)?,
+                ),
+                stages: stages.into_iter().collect(),
+                input_assembly_state: Some(InputAssemblyState {
+                    topology: PrimitiveTopology::TriangleStrip,
+                    ..Default::default()
+                }),
+                viewport_state: Some(ViewportState {
+                    viewports: smallvec![Viewport {
+                        offset: [0.0, 0.0],
+                        extent: [w as f32, h as f32],
+                        depth_range: 0.0..=1.0,
+                    }],
+                    ..Default::default()
+                }),
+                subpass: Some(Subpass::from(render_pass.clone(), 0).unwrap().into()),
+                rasterization_state: Some(RasterizationState::default()),
+                multisample_state: Some(MultisampleState::default()),
+                color_blend_state: Some(ColorBlendState::with_attachment_states(
+                    1,
+                    Default::default(),
+                )),
+                ..GraphicsPipelineCreateInfo::layout(layout)
+            },
+        )?;
+        Ok(Self {
+            device,
+            render_pass,
+            pipeline,
+        })
+    }
+    /// Layout of the descriptor set passed to `apply`, set 0 of the fragment shader
+    pub fn descriptor_set_layout(&self) -> &Arc<DescriptorSetLayout> {
+        self.pipeline.layout().set_layouts().first().unwrap()
+    }
+    /// Run the fragment shader with `desc_set`, writing the result into `output`. `prepare`
+    /// records commands that must run before the render pass, e.g. texture uploads.
+    #[allow(clippy::too_many_arguments)]
+    pub fn apply(
+        &self,
+        allocator: Arc<dyn MemoryAllocator>,
+        cmdbuf_allocator: Arc<dyn CommandBufferAllocator>,
+        after: impl GpuFuture,
+        queue: &Arc<Queue>,
+        desc_set: &Arc<DescriptorSet>,
+        output: Arc<Image>,
+        prepare: impl FnOnce(&mut RecordingCommandBuffer) -> Result<()>,
+    ) -> Result<impl GpuFuture> {
+        if queue.device() != &self.device || cmdbuf_allocator.device() != &self.device {
+            return Err(anyhow!("Device mismatch"));
+        }
+        if let Some(after_queue) = after.queue() {
+            if queue != &after_queue {
+                return Err(anyhow!("Queue mismatch"));
+            }
+        }
+        let mut cmdbuf = RecordingCommandBuffer::new(
+            cmdbuf_allocator,
+            queue.queue_family_index(),
+            CommandBufferLevel::Primary,
+            CommandBufferBeginInfo {
+                usage: OneTimeSubmit,
+                ..Default::default()
+            },
+        )?;
+        prepare(&mut cmdbuf)?;
+        let vertex_buffer = Buffer::from_iter::<Vertex, _>(
+            allocator,
+            BufferCreateInfo {
+                usage: BufferUsage::VERTEX_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::HOST_SEQUENTIAL_WRITE
+                    | MemoryTypeFilter::PREFER_DEVICE,
+                allocate_preference: MemoryAllocatePreference::Unknown,
+                ..Default::default()
+            },
+            [[-1.0, -1.0], [-1.0, 1.0], [1.0, -1.0], [1.0, 1.0]]
+                .map(|position| Vertex { position }),
+        )?;
+        let framebuffer = Framebuffer::new(
+            self.render_pass.clone(),
+            FramebufferCreateInfo {
+                attachments: vec![ImageView::new(
+                    output.clone(),
+                    ImageViewCreateInfo::from_image(&output),
+                )?],
+                ..Default::default()
+            },
+        )?;
+        let mut render_pass_begin_info = RenderPassBeginInfo::framebuffer(framebuffer);
+        render_pass_begin_info.clear_values = vec![None];
+        cmdbuf
+            .begin_render_pass(
+                render_pass_begin_info,
+                SubpassBeginInfo {
+                    contents: SubpassContents::Inline,
+                    ..Default::default()
+                },
+            )?
+            .bind_pipeline_graphics(self.pipeline.clone())?
+            .bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                self.pipeline.layout().clone(),
+                0,
+                desc_set.clone(),
+            )?
+            .bind_vertex_buffers(0, vertex_buffer.clone())?
+            .draw(vertex_buffer.len() as u32, 1, 0, 0)?
+            .end_render_pass(SubpassEndInfo::default())?;
+        Ok(after.then_execute(queue.clone(), cmdbuf.end()?)?)
+    }
+}
@@ -0,0 +1,279 @@
+//! `latency` command: measure the delay between the camera capturing a frame, and the
+//! timestamp we get for it.
+//!
+//! While the user turns their head around, we record how far the camera image moves between
+//! consecutive frames, and the HMD poses from the tracking system. The image moves when the
+//! head turns, so the image motion and the head rotation correlate best when the poses are
+//! looked up with the right delay.
+
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use xdg::BaseDirectories;
+
+use crate::{
+    checkerboard::GrayImage,
+    cli::LatencyCalibrate,
+    config::{Backend, Config},
+    pose_history::{pose_from_transform, PoseHistory},
+    vrapi::VrExt,
+    CAMERA_SIZE,
+};
+
+/// File in the config directory the measured latency is saved to
+const LATENCY_FILE: &str = "camera_latency.toml";
+/// Camera images are downscaled by this much before estimating their motion
+const DOWNSCALE: usize = 8;
+/// Largest motion between two frames searched for, in downscaled pixels
+const MAX_SHIFT: i32 = 10;
+/// Latencies tried, from 0 to this
+const MAX_LATENCY: Duration = Duration::from_millis(200);
+const LATENCY_STEP: Duration = Duration::from_millis(1);
+/// How often the HMD pose is sampled, if the VR runtime doesn't pace us
+const POSE_INTERVAL: Duration = Duration::from_millis(2);
+/// Below this correlation, the head probably didn't move enough for a measurement
+const MIN_CORRELATION: f32 = 0.5;
+
+/// Measured camera latency, saved in the config directory
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct CameraLatency {
+    #[serde(with = "humantime_serde")]
+    latency: Duration,
+}
+
+/// Load the latency saved by the `latency` command, if it has been measured.
+pub(crate) fn load(config_dir: &Path) -> Result<Option<Duration>> {
+    let path = config_dir.join(LATENCY_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| anyhow!("Cannot read camera latency file {}", path.display()))?;
+    let saved: CameraLatency = toml::from_str(&content)
+        .with_context(|| anyhow!("Invalid camera latency file {}", path.display()))?;
+    Ok(Some(saved.latency))
+}
+
+/// Average `DOWNSCALE` x `DOWNSCALE` blocks of pixels
+fn downscale(image: &GrayImage) -> GrayImage {
+    let width = image.width / DOWNSCALE;
+    let height = image.height / DOWNSCALE;
+    let mut data = vec![0.0; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let sum: f32 = (0..DOWNSCALE)
+                .map(|dy| {
+                    let start = (y * DOWNSCALE + dy) * image.width + x * DOWNSCALE;
+                    image.data[start..start + DOWNSCALE].iter().sum::<f32>()
+                })
+                .sum();
+            data[y * width + x] = sum / (DOWNSCALE * DOWNSCALE) as f32;
+        }
+    }
+    GrayImage {
+        width,
+        height,
+        data,
+    }
+}
+
+/// How far the whole image moved from `a` to `b`, in downscaled pixels. The shift with the
+/// smallest difference between the images is searched for, then refined to sub-pixel
+/// precision by fitting parabolas through its neighbours.
+fn image_motion(a: &GrayImage, b: &GrayImage) -> f32 {
+    let size = 2 * MAX_SHIFT as usize + 1;
+    let margin = MAX_SHIFT as usize;
+    let costs: Vec<f32> = (0..size * size)
+        .map(|i| {
+            let shift_x = (i % size) as i32 - MAX_SHIFT;
+            let shift_y = (i / size) as i32 - MAX_SHIFT;
+            let mut sum = 0.0;
+            for y in margin..a.height - margin {
+                let by = (y as i32 + shift_y) as usize;
+                for x in margin..a.width - margin {
+                    let bx = (x as i32 + shift_x) as usize;
+                    sum += (a.data[y * a.width + x] - b.data[by * b.width + bx]).abs();
+                }
+            }
+            sum
+        })
+        .collect();
+    let best = costs
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(i, _)| i)
+        .unwrap();
+    let refine = |before: f32, at: f32, after: f32| {
+        let curvature = before - 2.0 * at + after;
+        if curvature > 0.0 {
+            0.5 * (before - after) / curvature
+        } else {
+            0.0
+        }
+    };
+    let (x, y) = (best % size, best / size);
+    let dx = if x > 0 && x < size - 1 {
+        refine(costs[best - 1], costs[best], costs[best + 1])
+    } else {
+        0.0
+    };
+    let dy = if y > 0 && y < size - 1 {
+        refine(costs[best - size], costs[best], costs[best + size])
+    } else {
+        0.0
+    };
+    let shift_x = x as f32 + dx - MAX_SHIFT as f32;
+    let shift_y = y as f32 + dy - MAX_SHIFT as f32;
+    (shift_x * shift_x + shift_y * shift_y).sqrt()
+}
+
+/// Pearson correlation coefficient of `a` and `b`
+fn correlation(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len() as f32;
+    let mean_a = a.iter().sum::<f32>() / n;
+    let mean_b = b.iter().sum::<f32>() / n;
+    let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (a, b) in a.iter().zip(b) {
+        cov += (a - mean_a) * (b - mean_b);
+        var_a += (a - mean_a) * (a - mean_a);
+        var_b += (b - mean_b) * (b - mean_b);
+    }
+    cov / (var_a * var_b).sqrt().max(f32::EPSILON)
+}
+
+/// Capture downscaled left camera images until `stop` is set. The frames are timestamped
+/// the same way as in the main loop, so the measured latency applies there.
+fn capture(camera: v4l::Device, stop: &AtomicBool) -> Result<Vec<(Instant, GrayImage)>> {
+    let mut video_stream =
+        v4l::prelude::MmapStream::with_buffers(&camera, v4l::buffer::Type::VideoCapture, 1)
+            .context("cannot open camera mmap stream")?;
+    let mut first_frame_time = None;
+    let mut frames = Vec::new();
+    while !stop.load(Ordering::Relaxed) {
+        let (frame_data, metadata) = v4l::io::traits::CaptureStream::next(&mut video_stream)?;
+        let frame_time = if let Some((camera_reference, reference)) = first_frame_time {
+            let camera_elapsed = Duration::from(metadata.timestamp) - camera_reference;
+            reference + camera_elapsed
+        } else {
+            let now = Instant::now();
+            first_frame_time = Some((Duration::from(metadata.timestamp), now));
+            now
+        };
+        let image = GrayImage::from_yuyv_eye(
+            frame_data,
+            CAMERA_SIZE as usize * 2,
+            CAMERA_SIZE as usize,
+            0,
+        );
+        frames.push((frame_time, downscale(&image)));
+    }
+    Ok(frames)
+}
+
+pub(crate) fn measure_latency(
+    args: &LatencyCalibrate,
+    cfg: &Config,
+    xdg: &BaseDirectories,
+) -> Result<()> {
+    ensure!(args.seconds > 0, "Recording time must not be 0");
+    let mut vrsys = match cfg.backend {
+        Backend::OpenVR => crate::vrapi::OpenVr::new(xdg)?.boxed(),
+        Backend::OpenXR => crate::vrapi::OpenXr::new(cfg.z_order)?.boxed(),
+    };
+    let camera = crate::open_camera(&cfg.camera_device)?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let camera_thread = {
+        let stop = stop.clone();
+        std::thread::spawn(move || capture(camera, &stop))
+    };
+    println!(
+        "Put on the HMD and look around a room with some detail in it. Turn your head left and \
+         right, and up and down, at different speeds, with pauses in between."
+    );
+    // Nothing is submitted to it, but the OpenXR session only starts running once the
+    // overlay is shown
+    vrsys.show_overlay()?;
+    let record_time = Duration::from_secs(args.seconds.into());
+    let mut poses = PoseHistory::<1>::with_max_age(record_time + MAX_LATENCY);
+    let start = Instant::now();
+    while start.elapsed() < record_time {
+        if let Some(crate::vrapi::Event::RequestExit) = vrsys.poll_next_event()? {
+            vrsys.acknowledge_quit();
+            stop.store(true, Ordering::Relaxed);
+            bail!("The VR runtime asked us to quit");
+        }
+        if vrsys.is_synchronized() {
+            vrsys.refresh()?;
+        } else {
+            std::thread::sleep(POSE_INTERVAL);
+        }
+        match vrsys.current_hmd_transform()? {
+            Some(hmd_transform) => {
+                poses.push(Instant::now(), [pose_from_transform(&hmd_transform)])
+            }
+            // The session isn't running yet, so `refresh` didn't wait for a frame either
+            None => std::thread::sleep(POSE_INTERVAL),
+        }
+    }
+    stop.store(true, Ordering::Relaxed);
+    let frames = camera_thread
+        .join()
+        .map_err(|_| anyhow!("Camera thread panicked"))??;
+
+    // Only frames late enough that the poses are known for every latency tried
+    let frames: Vec<_> = frames
+        .into_iter()
+        .filter(|(time, _)| *time >= start + MAX_LATENCY)
+        .collect();
+    ensure!(frames.len() > 2, "Not enough camera frames captured");
+    ensure!(
+        poses.at(start).is_some(),
+        "No HMD poses received from the VR runtime"
+    );
+    let motion: Vec<f32> = frames
+        .windows(2)
+        .map(|pair| image_motion(&pair[0].1, &pair[1].1))
+        .collect();
+    let rotation_at = |latency: Duration| -> Vec<f32> {
+        frames
+            .windows(2)
+            .map(|pair| {
+                let [(from, _)] = poses.at(pair[0].0 - latency).unwrap();
+                let [(to, _)] = poses.at(pair[1].0 - latency).unwrap();
+                from.angle_to(&to)
+            })
+            .collect()
+    };
+    let steps = MAX_LATENCY.as_millis() / LATENCY_STEP.as_millis();
+    let (latency, best) = (0..=steps as u32)
+        .map(|step| {
+            let latency = LATENCY_STEP * step;
+            (latency, correlation(&motion, &rotation_at(latency)))
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .unwrap();
+    println!("Camera latency: {latency:?}, correlation: {best:.2}");
+    ensure!(
+        best >= MIN_CORRELATION,
+        "The camera motion doesn't match the head motion well enough, the latency was not \
+         saved. Try again, turning your head more, in a well lit room."
+    );
+    let path = xdg.get_config_home().join(LATENCY_FILE);
+    let content = toml::to_string(&CameraLatency { latency })?;
+    std::fs::write(&path, content)
+        .with_context(|| anyhow!("Cannot write camera latency to {}", path.display()))?;
+    println!(
+        "Saved to {}, it is used unless `camera_latency` is set in the config file.",
+        path.display()
+    );
+    Ok(())
+}
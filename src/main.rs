@@ -7,11 +7,26 @@
     maybe_uninit_array_assume_init
 )]
 #![deny(rust_2018_idioms)]
+mod bench;
+mod calibrate;
+mod calibration;
+mod checkerboard;
+mod cli;
+mod color;
+mod color_match;
 mod config;
+mod depth;
 mod distortion_correction;
 mod events;
+mod extra_overlay;
+mod flat_field;
+mod fullscreen;
+mod latency;
 mod openvr;
+mod out_of_view;
+mod overlay_mask;
 mod pipeline;
+mod pose_history;
 mod projection;
 mod steam;
 mod utils;
@@ -32,6 +47,14 @@ use vulkano::{
 use xdg::BaseDirectories;
 /// Camera image will be (size * 2, size)
 const CAMERA_SIZE: u32 = 960;
+/// Thumbstick positions closer to the centre than this are ignored
+const THUMBSTICK_DEADZONE: f32 = 0.2;
+/// Range the fixed depth can be adjusted in, in meters
+const MIN_FIXED_DEPTH: f32 = 0.1;
+const MAX_FIXED_DEPTH: f32 = 10.0;
+/// Range the overlay width and height can be set to, in meters
+const MIN_OVERLAY_SIZE: f32 = 0.1;
+const MAX_OVERLAY_SIZE: f32 = 10.0;
 #[allow(unused_imports)]
 use log::info;
 
@@ -57,6 +80,33 @@ fn find_index_camera() -> Result<std::path::PathBuf> {
     Ok(devnode.to_owned())
 }
 
+/// Open the camera and set it up to capture side-by-side YUYV frames.
+///
+/// - camera_device: device path, auto detect if empty
+fn open_camera(camera_device: &str) -> Result<v4l::Device> {
+    let camera = v4l::Device::with_path(if camera_device.is_empty() {
+        find_index_camera()?
+    } else {
+        std::path::Path::new(camera_device).to_owned()
+    })
+    .context("cannot open camera device")?;
+    if !camera
+        .query_caps()?
+        .capabilities
+        .contains(v4l::capability::Flags::VIDEO_CAPTURE)
+    {
+        return Err(anyhow!("Cannot capture from index camera"));
+    }
+    let format = camera.set_format(&v4l::Format::new(
+        CAMERA_SIZE * 2,
+        CAMERA_SIZE,
+        v4l::FourCC::new(b"YUYV"),
+    ))?;
+    log::info!("{}", format);
+    camera.set_params(&v4l::video::capture::Parameters::with_fps(54))?;
+    Ok(camera)
+}
+
 static SPLASH_IMAGE: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/splash.png"));
 
 fn first_run(xdg: &BaseDirectories) -> Result<()> {
@@ -252,6 +302,7 @@ impl CameraThread {
 }
 
 fn main() -> Result<()> {
+    let args: cli::Args = argh::from_env();
     let xdg = xdg::BaseDirectories::with_prefix("index_camera_passthrough")?;
     first_run(&xdg)?;
 
@@ -259,26 +310,20 @@ fn main() -> Result<()> {
     let env =
         env_logger::Env::default().default_filter_or(if cfg.debug { "debug" } else { "info" });
     env_logger::init_from_env(env);
-    let camera = v4l::Device::with_path(if cfg.camera_device.is_empty() {
-        find_index_camera()?
-    } else {
-        std::path::Path::new(&cfg.camera_device).to_owned()
-    })
-    .context("cannot open camera device")?;
-    if !camera
-        .query_caps()?
-        .capabilities
-        .contains(v4l::capability::Flags::VIDEO_CAPTURE)
-    {
-        return Err(anyhow!("Cannot capture from index camera"));
+    if let Some(command) = args.command {
+        return match command {
+            cli::Command::BenchCorrection(args) => {
+                bench::bench_correction(&args, &cfg, &xdg.get_config_home())
+            }
+            cli::Command::Calibrate(args) => calibrate::calibrate(&args, &cfg),
+            cli::Command::FlatField(args) => flat_field::flat_field_calibrate(&args, &cfg),
+            cli::Command::Latency(args) => latency::measure_latency(&args, &cfg, &xdg),
+            cli::Command::DumpCalibration(_) => {
+                calibration::dump_calibration(&cfg, &xdg.get_config_home())
+            }
+        };
     }
-    let format = camera.set_format(&v4l::Format::new(
-        CAMERA_SIZE * 2,
-        CAMERA_SIZE,
-        v4l::FourCC::new(b"YUYV"),
-    ))?;
-    log::info!("{}", format);
-    camera.set_params(&v4l::video::capture::Parameters::with_fps(54))?;
+    let camera = open_camera(&cfg.camera_device)?;
     let splash = load_splash()?;
     let frame = Arc::new(Mutex::new(Some(FrameInfo {
         frame: splash.clone(),
@@ -314,20 +359,49 @@ fn main() -> Result<()> {
     // Create a VROverlay
     vrsys.set_display_mode(config::DisplayMode::Direct)?;
     // load camera config
-    let camera_config = if let Some(cfg) = vrsys.load_camera_paramter() {
-        Some(cfg)
-    } else if let Some(cfg) = steam::find_steam_config() {
-        // if the backend doesn't give us the parameters, we try
-        // to search in the steam config for whatever that looks
-        // like a camera parameter file
-        vrsys.set_fallback_camera_config(cfg);
-        Some(cfg)
-    } else {
-        log::warn!("No camera parameters found");
-        None
+    let hmd_serial = vrsys.hmd_serial();
+    let calib = calibration::resolve(&xdg.get_config_home(), &cfg, hmd_serial.as_deref())?;
+    let camera_config = match calib {
+        Some((calib, source)) => {
+            log::info!("Using camera calibration from {source}");
+            for warning in calibration::diagnose(&calib) {
+                log::warn!("Camera calibration: {warning}");
+            }
+            vrsys.set_fallback_camera_config(calib);
+            Some(calib)
+        }
+        None => {
+            log::warn!("No camera parameters found");
+            None
+        }
     };
 
     vrsys.set_position_mode(cfg.overlay.position)?;
+    vrsys.set_overlay_shape(cfg.overlay.shape)?;
+    let mut overlay_size = cfg
+        .overlay
+        .size()
+        .map(|x| x.clamp(MIN_OVERLAY_SIZE, MAX_OVERLAY_SIZE));
+    vrsys.set_overlay_size(overlay_size)?;
+    let mut fixed_depth = cfg.fixed_depth.distance;
+    vrsys.set_fixed_depth(fixed_depth);
+    vrsys.set_reprojection(cfg.overlay.reprojection);
+    let overlay_mask = overlay_mask::Mask::new(
+        device.clone(),
+        vrsys.vk_allocator(),
+        &cfg.overlay.mask,
+        &xdg.get_config_home(),
+    )?;
+    if let Some(mask) = &overlay_mask {
+        mask.set_overlay_size(overlay_size)?;
+    }
+    vrsys.set_overlay_mask(overlay_mask)?;
+    vrsys.set_out_of_view(cfg.overlay.out_of_view);
+    let camera_latency = match cfg.camera_latency {
+        Some(latency) => latency,
+        None => latency::load(&xdg.get_config_home())?.unwrap_or_default(),
+    };
+    log::info!("Camera latency: {camera_latency:?}");
 
     // Show overlay
     log::debug!("showing overlay");
@@ -342,18 +416,84 @@ fn main() -> Result<()> {
         need_yuv_conversion: true,
     };
 
+    let extra_overlays: Vec<_> = cfg
+        .overlays
+        .iter()
+        .map(|(name, overlay)| {
+            let mut overlay = extra_overlay::sanitize(name, overlay);
+            [overlay.width, overlay.height] = overlay
+                .size()
+                .map(|x| x.clamp(MIN_OVERLAY_SIZE, MAX_OVERLAY_SIZE));
+            (name, overlay)
+        })
+        .collect();
+    // Depth estimation is enabled if configured, or if a display mode needs it
+    let depth_config = cfg.depth.clone().or_else(|| {
+        let needs_depth = cfg.display_mode.needs_depth()
+            || extra_overlays
+                .iter()
+                .any(|(_, overlay)| overlay.display_mode.needs_depth());
+        needs_depth.then(config::DepthConfig::default)
+    });
+    // Lens correction can only be fused into the projection if nothing else needs the
+    // undistorted image
+    let fuse_correction = cfg.correction.fused
+        && depth_config.is_none()
+        && cfg.display_mode.can_fuse_correction()
+        && extra_overlays
+            .iter()
+            .all(|(_, overlay)| overlay.display_mode.can_fuse_correction());
+    if cfg.correction.fused && !fuse_correction {
+        log::warn!("Fused lens correction is not supported with this configuration, disabled");
+    }
+    let correction_config = config::CorrectionConfig {
+        fused: fuse_correction,
+        ..cfg.correction
+    };
+    let gain_map = cfg
+        .flat_field
+        .as_ref()
+        .map(|path| flat_field::GainMap::load(&xdg.get_config_home().join(path)))
+        .transpose()?;
     let mut pipeline = pipeline::Pipeline::new(
         device.clone(),
         vrsys.vk_allocator(),
         vrsys.vk_descriptor_set_allocator(),
         config.need_yuv_conversion,
         camera_config,
+        gain_map.as_ref(),
+        cfg.color_match.as_ref(),
+        cfg.color_filter.as_ref(),
+        &correction_config,
+        depth_config.as_ref(),
     )?;
 
     log::debug!("pipeline: {pipeline:?}");
+    vrsys.set_depth_map(pipeline.depth_map());
+    vrsys.set_lens_remap(pipeline.lens_remap());
+    for (name, overlay) in &extra_overlays {
+        let mask = overlay_mask::Mask::new(
+            device.clone(),
+            vrsys.vk_allocator(),
+            &overlay.mask,
+            &xdg.get_config_home(),
+        )?;
+        if let Some(mask) = &mask {
+            mask.set_overlay_size(overlay.size())?;
+        }
+        vrsys.add_overlay(name, overlay, mask)?;
+    }
 
     let mut ui_state = events::State::new(cfg.open_delay);
     let mut debug_pressed = false;
+    let mut cycle_filter_pressed = false;
+    let adjust_fixed_depth = cfg.fixed_depth.thumbstick
+        && cfg.display_mode.projection_mode() == Some(config::ProjectionMode::FixedDepth);
+    if cfg.overlay.resize_thumbstick && adjust_fixed_depth {
+        log::warn!("The thumbstick adjusts the fixed depth, resizing the overlay is disabled");
+    }
+    let resize_overlay = cfg.overlay.resize_thumbstick && !adjust_fixed_depth;
+    let mut last_input = std::time::Instant::now();
     let mut maybe_current_frame: Option<FrameInfo> = None;
     let mut frame_changed = false;
     let is_synchronized = vrsys.is_synchronized();
@@ -379,6 +519,9 @@ fn main() -> Result<()> {
                 }
             }
             drop(other_frame);
+            // Sample the HMD pose right after the wait, so the history covers the time the
+            // camera frame was captured.
+            vrsys.record_pose()?;
             let Some(current_frame) = maybe_current_frame.as_ref() else {
                 // Should never happen, because we should at least have the splash screen
                 log::error!("No frame");
@@ -392,12 +535,11 @@ fn main() -> Result<()> {
             }
 
             if frame_changed {
-                // We try to get the pose at the time when the camera frame is captured. GetDeviceToAbsoluteTrackingPose
-                // doesn't specifically say if a negative time offset will work...
-                // also, do this as early as possible.
+                // The pose at the time the camera frame is captured is looked up from the pose
+                // history, do this as early as possible.
                 let elapsed = current_frame
                     .frame_time
-                    .map(|frame_time| std::time::Instant::now() - frame_time)
+                    .map(|frame_time| std::time::Instant::now() - frame_time + camera_latency)
                     .unwrap_or_default();
                 log::trace!("elapsed: {elapsed:?}");
                 // Allocate final image
@@ -418,6 +560,7 @@ fn main() -> Result<()> {
                             vrsys.vk_command_buffer_allocator(),
                             &current_frame.frame,
                             output.clone(),
+                            cfg.display_mode,
                         )?;
                         //println!("submission: {:?}", submission);
                         future.flush()?; // can't use then_signal_fence_and_flush() because of a vulkano bug
@@ -468,6 +611,44 @@ fn main() -> Result<()> {
         } else {
             debug_pressed = false;
         }
+        if vrsys.get_action_state(vrapi::Action::CycleFilter)? {
+            if !cycle_filter_pressed {
+                if let Some(mode) = pipeline.cycle_color_filter()? {
+                    log::info!("Colour filter: {mode:?}");
+                }
+                cycle_filter_pressed = true;
+            }
+        } else {
+            cycle_filter_pressed = false;
+        }
+        if adjust_fixed_depth {
+            let now = std::time::Instant::now();
+            let adjust = vrsys.get_adjust_state()?;
+            if adjust.abs() > THUMBSTICK_DEADZONE {
+                let step = adjust * cfg.fixed_depth.adjust_speed * (now - last_input).as_secs_f32();
+                fixed_depth = (fixed_depth + step).clamp(MIN_FIXED_DEPTH, MAX_FIXED_DEPTH);
+                log::debug!("Fixed depth: {fixed_depth}");
+                vrsys.set_fixed_depth(fixed_depth);
+            }
+            last_input = now;
+        }
+        if resize_overlay {
+            let now = std::time::Instant::now();
+            let adjust = vrsys.get_adjust_state()?;
+            if adjust.abs() > THUMBSTICK_DEADZONE {
+                // Scale both sides, so the aspect ratio, and the mask, stay the same
+                let scale = (adjust * cfg.overlay.resize_speed * (now - last_input).as_secs_f32())
+                    .exp()
+                    .clamp(
+                        MIN_OVERLAY_SIZE / overlay_size[0].min(overlay_size[1]),
+                        MAX_OVERLAY_SIZE / overlay_size[0].max(overlay_size[1]),
+                    );
+                overlay_size = overlay_size.map(|x| x * scale);
+                log::debug!("Overlay size: {overlay_size:?}");
+                vrsys.set_overlay_size(overlay_size)?;
+            }
+            last_input = now;
+        }
         ui_state.handle(&*vrsys)?;
         match ui_state.turn() {
             events::Action::ShowOverlay => {
@@ -493,6 +674,7 @@ fn main() -> Result<()> {
         }
         if vrsys.get_action_state(vrapi::Action::Reposition)? {
             vrsys.set_position_mode(cfg.overlay.position)?;
+            vrsys.reposition_extra_overlays();
         }
     }
     camera_thread.join().unwrap()?;
@@ -56,14 +56,16 @@ impl<'a> VRCompositor<'a> {
 
 impl VRSystem {
     pub fn init() -> Result<Self, EVRInitError> {
+        Self::init_as(openvr_sys2::EVRApplicationType::VRApplication_Overlay)
+    }
+    /// Connect to SteamVR for querying device properties. Fails instead of starting SteamVR
+    /// if it is not running.
+    pub fn init_background() -> Result<Self, EVRInitError> {
+        Self::init_as(openvr_sys2::EVRApplicationType::VRApplication_Background)
+    }
+    fn init_as(app_type: openvr_sys2::EVRApplicationType) -> Result<Self, EVRInitError> {
         let mut error = openvr_sys2::EVRInitError::VRInitError_None;
-        let isystem_raw = unsafe {
-            openvr_sys2::VR_Init(
-                &mut error,
-                openvr_sys2::EVRApplicationType::VRApplication_Overlay,
-                std::ptr::null(),
-            )
-        };
+        let isystem_raw = unsafe { openvr_sys2::VR_Init(&mut error, app_type, std::ptr::null()) };
         error.into_result()?;
         Ok(Self(isystem_raw, Cell::new(None)))
     }
@@ -82,6 +84,28 @@ impl VRSystem {
                 == openvr_sys2::ETrackedDeviceClass::TrackedDeviceClass_HMD
         })
     }
+    pub fn hmd_serial(&self) -> Option<String> {
+        let hmd_id = self.find_hmd()?;
+        let mut serial_number = [0u8; 32];
+        let mut error = openvr_sys2::ETrackedPropertyError::TrackedProp_Success;
+        let serial_number_len = unsafe {
+            self.pin_mut().GetStringTrackedDeviceProperty(
+                hmd_id,
+                openvr_sys2::ETrackedDeviceProperty::Prop_SerialNumber_String,
+                serial_number.as_mut_ptr() as *mut _,
+                32,
+                &mut error,
+            )
+        };
+        if error != openvr_sys2::ETrackedPropertyError::TrackedProp_Success
+            || serial_number_len == 0
+        {
+            return None;
+        }
+        std::str::from_utf8(&serial_number[..serial_number_len as usize - 1])
+            .ok()
+            .map(str::to_owned)
+    }
     pub fn hmd_transform(&self, time_offset: f32) -> nalgebra::Matrix4<f64> {
         let mut hmd_transform = std::mem::MaybeUninit::<openvr_sys2::TrackedDevicePose_t>::uninit();
         unsafe {
@@ -94,6 +118,34 @@ impl VRSystem {
             (&hmd_transform.assume_init().mDeviceToAbsoluteTracking).into()
         }
     }
+    /// Seconds until the next frame submitted to the compositor reaches the display
+    pub fn seconds_to_photons(&self) -> f32 {
+        let mut error = openvr_sys2::ETrackedPropertyError::TrackedProp_Success;
+        let (frequency, vsync_to_photons) = unsafe {
+            (
+                self.pin_mut().GetFloatTrackedDeviceProperty(
+                    0,
+                    openvr_sys2::ETrackedDeviceProperty::Prop_DisplayFrequency_Float,
+                    &mut error,
+                ),
+                self.pin_mut().GetFloatTrackedDeviceProperty(
+                    0,
+                    openvr_sys2::ETrackedDeviceProperty::Prop_SecondsFromVsyncToPhotons_Float,
+                    &mut error,
+                ),
+            )
+        };
+        let mut since_vsync = 0.0f32;
+        let mut frame_counter = 0u64;
+        unsafe {
+            self.pin_mut()
+                .GetTimeSinceLastVsync(&mut since_vsync, &mut frame_counter);
+        }
+        if frequency <= 0.0 {
+            return vsync_to_photons.max(0.0);
+        }
+        (1.0 / frequency - since_vsync).max(0.0) + vsync_to_photons
+    }
     pub fn pin_mut(&self) -> Pin<&mut openvr_sys2::IVRSystem> {
         unsafe { Pin::new_unchecked(&mut *self.0) }
     }
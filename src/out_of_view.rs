@@ -0,0 +1,254 @@
+//! What happens when the cameras can't see enough of an overlay, see `OutOfViewConfig`.
+//!
+//! A "Sticky" or "Absolute" overlay can be somewhere the cameras don't see, e.g. when the user
+//! turns away from it. The projection would leave it transparent. After the projection is
+//! set up for where the overlay belongs, `Projection::coverage` estimates how much of it the
+//! cameras see. Below the threshold, the overlay gets an `Indicator` drawn over it, is moved
+//! in front of the user, or is hidden.
+use std::sync::Arc;
+
+use nalgebra::Matrix4;
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::RecordingCommandBuffer,
+    descriptor_set::{allocator::DescriptorSetAllocator, DescriptorSet, WriteDescriptorSet},
+    device::Device,
+    memory::allocator::{
+        AllocationCreateInfo, MemoryAllocatePreference, MemoryAllocator, MemoryTypeFilter,
+    },
+    pipeline::{
+        graphics::{
+            color_blend::ColorBlendState,
+            input_assembly::{InputAssemblyState, PrimitiveTopology},
+            multisample::MultisampleState,
+            rasterization::RasterizationState,
+            vertex_input::VertexInputState,
+            viewport::ViewportState,
+            GraphicsPipelineCreateInfo,
+        },
+        layout::PipelineDescriptorSetLayoutCreateInfo,
+        DynamicState, GraphicsPipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    render_pass::{RenderPass, Subpass},
+    sync::HostAccessError,
+    Handle, VulkanObject,
+};
+
+use crate::{
+    config::{OutOfViewAction, OutOfViewConfig, PositionMode},
+    projection::{Projection, ProjectorError},
+};
+
+mod vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "shaders/overlay_mask.vert",
+    }
+}
+
+mod fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "shaders/out_of_view.frag",
+        custom_derives: [Copy, Clone, Debug],
+    }
+}
+
+/// How far the coverage has to rise above the threshold before the overlay is back in view,
+/// so it doesn't flicker when the coverage is right at the threshold
+pub(crate) const HYSTERESIS: f32 = 0.05;
+
+/// Border and arrow drawn over the overlay while it is out of view
+pub(crate) struct Indicator {
+    pipeline: Arc<GraphicsPipeline>,
+    parameters: Subbuffer<fs::Indicator>,
+    desc_set: Arc<DescriptorSet>,
+    visible: bool,
+}
+
+impl std::fmt::Debug for Indicator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Indicator")
+            .field("pipeline", &self.pipeline.handle().as_raw())
+            .field("visible", &self.visible)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Indicator {
+    /// Create a pipeline drawing the indicator in the first subpass of `render_pass`, whose
+    /// only attachment is the overlay image.
+    pub(crate) fn new(
+        device: Arc<Device>,
+        allocator: Arc<dyn MemoryAllocator>,
+        descriptor_set_allocator: Arc<dyn DescriptorSetAllocator>,
+        render_pass: &Arc<RenderPass>,
+    ) -> Result<Self, ProjectorError> {
+        let vs = vs::load(device.clone())?.entry_point("main").unwrap();
+        let fs = fs::load(device.clone())?.entry_point("main").unwrap();
+        let stages = [
+            PipelineShaderStageCreateInfo::new(vs),
+            PipelineShaderStageCreateInfo::new(fs),
+        ];
+        let layout = PipelineLayout::new(
+            device.clone(),
+            PipelineDescriptorSetLayoutCreateInfo::from_stages(&stages)
+                .into_pipeline_layout_create_info(device.clone())?,
+        )?;
+        let pipeline = GraphicsPipeline::new(
+            device,
+            None,
+            GraphicsPipelineCreateInfo {
+                vertex_input_state: Some(VertexInputState::default()),
+                stages: stages.into_iter().collect(),
+                input_assembly_state: Some(InputAssemblyState {
+                    topology: PrimitiveTopology::TriangleStrip,
+                    ..Default::default()
+                }),
+                viewport_state: Some(ViewportState::default()),
+                dynamic_state: [DynamicState::Viewport].into_iter().collect(),
+                subpass: Some(Subpass::from(render_pass.clone(), 0).unwrap().into()),
+                rasterization_state: Some(RasterizationState::default()),
+                multisample_state: Some(MultisampleState::default()),
+                color_blend_state: Some(ColorBlendState::with_attachment_states(
+                    1,
+                    Default::default(),
+                )),
+                ..GraphicsPipelineCreateInfo::layout(layout)
+            },
+        )?;
+        let parameters = Buffer::from_data(
+            allocator,
+            BufferCreateInfo {
+                usage: BufferUsage::UNIFORM_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::HOST_SEQUENTIAL_WRITE
+                    | MemoryTypeFilter::PREFER_DEVICE,
+                allocate_preference: MemoryAllocatePreference::Unknown,
+                ..Default::default()
+            },
+            fs::Indicator {
+                direction: [0.0; 2],
+                aspect: 1.0,
+            },
+        )?;
+        let desc_set = DescriptorSet::new(
+            descriptor_set_allocator,
+            pipeline.layout().set_layouts().first().unwrap().clone(),
+            [WriteDescriptorSet::buffer(0, parameters.clone())],
+            None,
+        )?;
+        Ok(Self {
+            pipeline,
+            parameters,
+            desc_set,
+            visible: false,
+        })
+    }
+    /// Show the indicator with the arrow pointing towards `direction`, in overlay space with
+    /// y up, on an overlay of the given width and height. `None` hides it.
+    pub(crate) fn set(
+        &mut self,
+        direction: Option<[f32; 2]>,
+        [width, height]: [f32; 2],
+    ) -> Result<(), HostAccessError> {
+        self.visible = direction.is_some();
+        if let Some(direction) = direction {
+            let mut parameters = self.parameters.write()?;
+            parameters.direction = direction;
+            parameters.aspect = height / width;
+        }
+        Ok(())
+    }
+    /// Draw the indicator over the current viewport, if it is shown, inside the render pass
+    /// this was created for.
+    pub(crate) fn draw(&self, cmdbuf: &mut RecordingCommandBuffer) -> Result<(), ProjectorError> {
+        if !self.visible {
+            return Ok(());
+        }
+        cmdbuf
+            .bind_pipeline_graphics(self.pipeline.clone())?
+            .bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                self.pipeline.layout().clone(),
+                0,
+                self.desc_set.clone(),
+            )?
+            .draw(4, 1, 0, 0)?;
+        Ok(())
+    }
+}
+
+/// Decides where an overlay with projection is shown, from how much of it the cameras see
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct OutOfView {
+    cfg: OutOfViewConfig,
+    /// Whether the overlay was out of view the last time it was placed
+    out_of_view: bool,
+}
+
+impl OutOfView {
+    pub(crate) fn new(cfg: OutOfViewConfig) -> Self {
+        Self {
+            cfg,
+            out_of_view: false,
+        }
+    }
+    /// Whether projections need an `Indicator`, see `Projection::enable_indicator`
+    pub(crate) fn needs_indicator(&self) -> bool {
+        self.cfg.action == OutOfViewAction::Indicator
+    }
+    /// Set up `projector` for the overlay at `transform`, see `Projection::update_mvps`, and
+    /// decide where the overlay is shown: where it belongs, or in front of the user at
+    /// `display_hmd_transform`. `None` if it should be hidden.
+    pub(crate) fn place(
+        &mut self,
+        projector: &mut Projection,
+        transform: Matrix4<f32>,
+        fov: &[[f32; 2]; 2],
+        view_transforms: &[Matrix4<f32>; 2],
+        display_hmd_transform: &Matrix4<f32>,
+        capture_hmd_transform: &Matrix4<f32>,
+    ) -> Result<Option<Matrix4<f32>>, ProjectorError> {
+        projector.update_mvps(&[transform; 2], fov, view_transforms, capture_hmd_transform)?;
+        if self.cfg.action == OutOfViewAction::None {
+            return Ok(Some(transform));
+        }
+        let (coverage, direction) = projector.coverage();
+        let threshold = if self.out_of_view {
+            self.cfg.threshold + HYSTERESIS
+        } else {
+            self.cfg.threshold
+        };
+        let out_of_view = coverage < threshold;
+        if out_of_view != self.out_of_view {
+            log::debug!("overlay out of view: {out_of_view}, coverage {coverage}");
+            self.out_of_view = out_of_view;
+        }
+        projector.set_indicator(out_of_view.then_some(direction))?;
+        if !out_of_view {
+            return Ok(Some(transform));
+        }
+        Ok(match self.cfg.action {
+            OutOfViewAction::None | OutOfViewAction::Indicator => Some(transform),
+            OutOfViewAction::HeadLocked => {
+                let head_locked: Matrix4<f32> = PositionMode::Hmd {
+                    distance: self.cfg.distance,
+                }
+                .transform(*display_hmd_transform)
+                .into();
+                projector.update_mvps(
+                    &[head_locked; 2],
+                    fov,
+                    view_transforms,
+                    capture_hmd_transform,
+                )?;
+                Some(head_locked)
+            }
+            OutOfViewAction::Hide => None,
+        })
+    }
+}
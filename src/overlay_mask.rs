@@ -0,0 +1,406 @@
+//! Shape, soft edges and opacity of the overlay, see `OverlayMaskConfig`.
+//!
+//! The mask is drawn over the finished overlay image, in place: the blend state keeps the
+//! colour, and multiplies the alpha by the mask. So it works the same for every display mode.
+//! The projection draws it right after each eye, otherwise the VR backend draws it over the
+//! overlay image before submitting it. The processed camera frame itself is never masked, so
+//! every overlay showing it can have its own mask.
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context};
+use vulkano::{
+    buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
+    command_buffer::{
+        allocator::CommandBufferAllocator, CommandBufferBeginInfo, CommandBufferExecError,
+        CommandBufferLevel, CommandBufferUsage::OneTimeSubmit, CopyBufferToImageInfo,
+        RecordingCommandBuffer, RenderPassBeginInfo, SubpassBeginInfo, SubpassContents,
+        SubpassEndInfo,
+    },
+    descriptor_set::{allocator::DescriptorSetAllocator, DescriptorSet, WriteDescriptorSet},
+    device::{Device, Queue},
+    format::Format,
+    image::{
+        sampler::{Filter, Sampler, SamplerCreateInfo},
+        view::{ImageView, ImageViewCreateInfo},
+        Image, ImageCreateInfo, ImageUsage,
+    },
+    memory::allocator::{
+        AllocationCreateInfo, MemoryAllocatePreference, MemoryAllocator, MemoryTypeFilter,
+    },
+    pipeline::{
+        graphics::{
+            color_blend::{
+                AttachmentBlend, BlendFactor, BlendOp, ColorBlendAttachmentState, ColorBlendState,
+            },
+            input_assembly::{InputAssemblyState, PrimitiveTopology},
+            multisample::MultisampleState,
+            rasterization::RasterizationState,
+            vertex_input::VertexInputState,
+            viewport::{Viewport, ViewportState},
+            GraphicsPipelineCreateInfo,
+        },
+        layout::{IntoPipelineLayoutCreateInfoError, PipelineDescriptorSetLayoutCreateInfo},
+        DynamicState, GraphicsPipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo,
+    },
+    render_pass::{Framebuffer, FramebufferCreateInfo, RenderPass, Subpass},
+    sync::{GpuFuture, HostAccessError},
+    Handle, Validated, ValidationError, VulkanError, VulkanObject,
+};
+
+use crate::{
+    config::{MaskShape, OverlayMaskConfig},
+    utils::DeviceExt as _,
+};
+
+mod vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "shaders/overlay_mask.vert",
+    }
+}
+
+mod fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "shaders/overlay_mask.frag",
+        custom_derives: [Copy, Clone, Debug],
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum MaskError {
+    #[error("vulkan error {0}")]
+    Vulkan(#[from] Validated<VulkanError>),
+    #[error("{0}")]
+    CreateInfo(#[from] IntoPipelineLayoutCreateInfoError),
+    #[error("command buffer execution error: {0}")]
+    CommandBuffer(#[from] CommandBufferExecError),
+}
+
+impl From<Box<ValidationError>> for MaskError {
+    fn from(value: Box<ValidationError>) -> Self {
+        Self::Vulkan(Validated::from(value))
+    }
+}
+
+/// The alpha channel of the image at `path`, or its brightness if it has no alpha channel
+fn load_mask_image(path: &Path) -> anyhow::Result<([u32; 2], Vec<u8>)> {
+    let image = image::open(path)
+        .with_context(|| anyhow!("Cannot load overlay mask {}", path.display()))?;
+    let pixels = if image.color().has_alpha() {
+        image.to_rgba8().pixels().map(|p| p.0[3]).collect()
+    } else {
+        image.to_luma8().into_raw()
+    };
+    Ok(([image.width(), image.height()], pixels))
+}
+
+/// Parameters and image of the mask, shared by the pipeline and the projection
+pub(crate) struct Mask {
+    parameters: Subbuffer<fs::Parameters>,
+    image: Arc<Image>,
+    /// Contents of `image`, uploaded by whoever draws the mask first
+    pending_upload: Mutex<Option<Subbuffer<[u8]>>>,
+}
+
+impl std::fmt::Debug for Mask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Mask")
+            .field("image", &self.image.handle().as_raw())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Mask {
+    /// `None` if the mask shows the whole overlay, fully opaque.
+    pub(crate) fn new(
+        device: Arc<Device>,
+        allocator: Arc<dyn MemoryAllocator>,
+        cfg: &OverlayMaskConfig,
+        config_dir: &Path,
+    ) -> anyhow::Result<Option<Arc<Self>>> {
+        if cfg.is_opaque() {
+            return Ok(None);
+        }
+        let (shape, corner_radius) = match &cfg.shape {
+            MaskShape::Rectangle => (0, 0.0),
+            MaskShape::Circle => (1, 0.0),
+            MaskShape::RoundedRectangle { corner_radius } => (2, corner_radius.max(0.0)),
+            MaskShape::Image { .. } => (3, 0.0),
+        };
+        let ([width, height], pixels) = match &cfg.shape {
+            MaskShape::Image { path } => load_mask_image(&config_dir.join(path))?,
+            _ => ([1, 1], vec![u8::MAX]),
+        };
+        let parameters = Buffer::from_data(
+            allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::UNIFORM_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::HOST_SEQUENTIAL_WRITE
+                    | MemoryTypeFilter::PREFER_DEVICE,
+                allocate_preference: MemoryAllocatePreference::Unknown,
+                ..Default::default()
+            },
+            fs::Parameters {
+                shape,
+                feather: cfg.feather.max(0.0),
+                cornerRadius: corner_radius,
+                opacity: cfg.opacity.clamp(0.0, 1.0),
+                aspect: 1.0,
+            },
+        )?;
+        let staging = Buffer::from_iter(
+            allocator,
+            BufferCreateInfo {
+                usage: BufferUsage::TRANSFER_SRC,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::HOST_SEQUENTIAL_WRITE
+                    | MemoryTypeFilter::PREFER_HOST,
+                allocate_preference: MemoryAllocatePreference::Unknown,
+                ..Default::default()
+            },
+            pixels,
+        )?;
+        let image = device.clone().new_image(
+            ImageCreateInfo {
+                extent: [width, height, 1],
+                format: Format::R8_UNORM,
+                usage: ImageUsage::TRANSFER_DST | ImageUsage::SAMPLED,
+                ..Default::default()
+            },
+            MemoryTypeFilter::PREFER_DEVICE,
+        )?;
+        device.set_debug_utils_object_name(&image, Some("overlay_mask"))?;
+        Ok(Some(Arc::new(Self {
+            parameters,
+            image,
+            pending_upload: Mutex::new(Some(staging)),
+        })))
+    }
+    /// Keep the shape in proportion on an overlay of the given width and height.
+    pub(crate) fn set_overlay_size(
+        &self,
+        [width, height]: [f32; 2],
+    ) -> Result<(), HostAccessError> {
+        self.parameters.write()?.aspect = height / width;
+        Ok(())
+    }
+    fn record_upload(&self, cmdbuf: &mut RecordingCommandBuffer) -> Result<(), MaskError> {
+        if let Some(staging) = self.pending_upload.lock().unwrap().take() {
+            cmdbuf.copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(
+                staging,
+                self.image.clone(),
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+/// Draws a `Mask` over the overlay image
+pub(crate) struct OverlayMask {
+    mask: Arc<Mask>,
+    render_pass: Arc<RenderPass>,
+    pipeline: Arc<GraphicsPipeline>,
+    desc_set: Arc<DescriptorSet>,
+}
+
+impl std::fmt::Debug for OverlayMask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OverlayMask")
+            .field("mask", &self.mask)
+            .field("pipeline", &self.pipeline.handle().as_raw())
+            .finish_non_exhaustive()
+    }
+}
+
+impl OverlayMask {
+    /// Create a pipeline drawing `mask` in the first subpass of `render_pass`, whose only
+    /// attachment is the overlay image.
+    pub(crate) fn new(
+        device: Arc<Device>,
+        descriptor_set_allocator: Arc<dyn DescriptorSetAllocator>,
+        mask: &Arc<Mask>,
+        render_pass: Arc<RenderPass>,
+    ) -> Result<Self, MaskError> {
+        let vs = vs::load(device.clone())?.entry_point("main").unwrap();
+        let fs = fs::load(device.clone())?.entry_point("main").unwrap();
+        let stages = [
+            PipelineShaderStageCreateInfo::new(vs),
+            PipelineShaderStageCreateInfo::new(fs),
+        ];
+        let layout = PipelineLayout::new(
+            device.clone(),
+            PipelineDescriptorSetLayoutCreateInfo::from_stages(&stages)
+                .into_pipeline_layout_create_info(device.clone())?,
+        )?;
+        // Keep the colour of the overlay, multiply its alpha by the mask
+        let blend = AttachmentBlend {
+            src_color_blend_factor: BlendFactor::Zero,
+            dst_color_blend_factor: BlendFactor::One,
+            color_blend_op: BlendOp::Add,
+            src_alpha_blend_factor: BlendFactor::Zero,
+            dst_alpha_blend_factor: BlendFactor::SrcAlpha,
+            alpha_blend_op: BlendOp::Add,
+        };
+        let pipeline = GraphicsPipeline::new(
+            device.clone(),
+            None,
+            GraphicsPipelineCreateInfo {
+                vertex_input_state: Some(VertexInputState::default()),
+                stages: stages.into_iter().collect(),
+                input_assembly_state: Some(InputAssemblyState {
+                    topology: PrimitiveTopology::TriangleStrip,
+                    ..Default::default()
+                }),
+                viewport_state: Some(ViewportState::default()),
+                dynamic_state: [DynamicState::Viewport].into_iter().collect(),
+                subpass: Some(Subpass::from(render_pass.clone(), 0).unwrap().into()),
+                rasterization_state: Some(RasterizationState::default()),
+                multisample_state: Some(MultisampleState::default()),
+                color_blend_state: Some(ColorBlendState::with_attachment_states(
+                    1,
+                    ColorBlendAttachmentState {
+                        blend: Some(blend),
+                        ..Default::default()
+                    },
+                )),
+                ..GraphicsPipelineCreateInfo::layout(layout)
+            },
+        )?;
+        let sampler = Sampler::new(
+            device,
+            SamplerCreateInfo {
+                min_filter: Filter::Linear,
+                mag_filter: Filter::Linear,
+                ..Default::default()
+            },
+        )?;
+        let desc_set_layout = pipeline.layout().set_layouts().first().unwrap();
+        let desc_set = DescriptorSet::new(
+            descriptor_set_allocator,
+            desc_set_layout.clone(),
+            [
+                WriteDescriptorSet::buffer(0, mask.parameters.clone()),
+                WriteDescriptorSet::image_view_sampler(
+                    1,
+                    ImageView::new(
+                        mask.image.clone(),
+                        ImageViewCreateInfo::from_image(&mask.image),
+                    )?,
+                    sampler,
+                ),
+            ],
+            None,
+        )?;
+        Ok(Self {
+            mask: mask.clone(),
+            render_pass,
+            pipeline,
+            desc_set,
+        })
+    }
+    /// Create a pipeline drawing `mask` in a render pass of its own, for `apply`.
+    pub(crate) fn standalone(
+        device: Arc<Device>,
+        descriptor_set_allocator: Arc<dyn DescriptorSetAllocator>,
+        mask: &Arc<Mask>,
+    ) -> Result<Self, MaskError> {
+        // Draws over what is already in the overlay image
+        let render_pass = vulkano::single_pass_renderpass!(device.clone(),
+            attachments: {
+                color: {
+                    format: Format::R8G8B8A8_UNORM,
+                    samples: 1,
+                    load_op: Load,
+                    store_op: Store,
+                }
+            },
+            pass: {
+                color: [color],
+                depth_stencil: {}
+            }
+        )?;
+        Self::new(device, descriptor_set_allocator, mask, render_pass)
+    }
+    /// Upload the mask image if it hasn't been. Must be recorded before the render pass
+    /// the mask is drawn in.
+    pub(crate) fn prepare(&self, cmdbuf: &mut RecordingCommandBuffer) -> Result<(), MaskError> {
+        self.mask.record_upload(cmdbuf)
+    }
+    /// Draw the mask over the current viewport, inside the render pass this was created for.
+    pub(crate) fn draw(&self, cmdbuf: &mut RecordingCommandBuffer) -> Result<(), MaskError> {
+        cmdbuf
+            .bind_pipeline_graphics(self.pipeline.clone())?
+            .bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                self.pipeline.layout().clone(),
+                0,
+                self.desc_set.clone(),
+            )?
+            .draw(4, 1, 0, 0)?;
+        Ok(())
+    }
+    /// Draw the mask over each eye of the side-by-side `output`, in its own render pass.
+    pub(crate) fn apply(
+        &self,
+        cmdbuf_allocator: Arc<dyn CommandBufferAllocator>,
+        after: impl GpuFuture,
+        queue: &Arc<Queue>,
+        output: Arc<Image>,
+    ) -> Result<impl GpuFuture, MaskError> {
+        let [w, h, _] = output.extent();
+        let mut cmdbuf = RecordingCommandBuffer::new(
+            cmdbuf_allocator,
+            queue.queue_family_index(),
+            CommandBufferLevel::Primary,
+            CommandBufferBeginInfo {
+                usage: OneTimeSubmit,
+                ..Default::default()
+            },
+        )?;
+        self.prepare(&mut cmdbuf)?;
+        let framebuffer = Framebuffer::new(
+            self.render_pass.clone(),
+            FramebufferCreateInfo {
+                attachments: vec![ImageView::new(
+                    output.clone(),
+                    ImageViewCreateInfo::from_image(&output),
+                )?],
+                ..Default::default()
+            },
+        )?;
+        let mut render_pass_begin_info = RenderPassBeginInfo::framebuffer(framebuffer);
+        render_pass_begin_info.clear_values = vec![None];
+        cmdbuf.begin_render_pass(
+            render_pass_begin_info,
+            SubpassBeginInfo {
+                contents: SubpassContents::Inline,
+                ..Default::default()
+            },
+        )?;
+        for eye in 0..2 {
+            cmdbuf.set_viewport(
+                0,
+                Some(Viewport {
+                    offset: [(eye * w / 2) as f32, 0.0],
+                    extent: [(w / 2) as f32, h as f32],
+                    depth_range: 0.0..=1.0,
+                })
+                .into_iter()
+                .collect(),
+            )?;
+            self.draw(&mut cmdbuf)?;
+        }
+        cmdbuf.end_render_pass(SubpassEndInfo::default())?;
+        Ok(after.then_execute(queue.clone(), cmdbuf.end()?)?)
+    }
+}
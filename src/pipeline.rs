@@ -1,12 +1,15 @@
 use std::sync::Arc;
 
-use crate::utils::DeviceExt as _;
+use crate::{
+    config::{DisplayMode, OutputFov},
+    utils::DeviceExt as _,
+};
 use anyhow::Result;
 use vulkano::{
     buffer::{Buffer, BufferCreateInfo, BufferUsage, Subbuffer},
     command_buffer::{
         allocator::CommandBufferAllocator, CommandBufferBeginInfo, CommandBufferLevel,
-        CommandBufferUsage, CopyBufferToImageInfo, RecordingCommandBuffer,
+        CommandBufferUsage, CopyBufferToImageInfo, CopyImageInfo, RecordingCommandBuffer,
     },
     descriptor_set::allocator::DescriptorSetAllocator,
     device::{Device, DeviceOwned},
@@ -19,20 +22,34 @@ use vulkano::{
 
 pub(crate) struct Pipeline {
     yuv: Option<crate::yuv::GpuYuyvConverter>,
+    flat_field: Option<crate::flat_field::FlatField>,
+    color_match: Option<crate::color_match::ColorMatch>,
+    color_filter: Option<crate::color::ColorFilter>,
     correction: Option<crate::distortion_correction::StereoCorrection>,
+    /// Lens correction is done by the projection, see `CorrectionConfig::fused`
+    fused: bool,
+    depth: Option<crate::depth::StereoDepth>,
     capture: bool,
     render_doc: Option<renderdoc::RenderDoc<renderdoc::V100>>,
     cpu_image_buffer: Arc<Buffer>,
     yuv_texture: Arc<VkImage>,
     textures: [Arc<VkImage>; 2],
+    /// Output of the lens correction, when it is needed by the depth stage
+    undistorted: Option<Arc<VkImage>>,
     camera_config: Option<crate::vrapi::StereoCamera>,
+    fov: [[f32; 2]; 2],
 }
 
 impl std::fmt::Debug for Pipeline {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Pipeline")
             .field("yuv", &self.yuv)
+            .field("flat_field", &self.flat_field)
+            .field("color_match", &self.color_match)
+            .field("color_filter", &self.color_filter)
             .field("correction", &self.correction)
+            .field("fused", &self.fused)
+            .field("depth", &self.depth)
             .field("capture", &self.capture)
             .field("render_doc", &self.render_doc)
             .field("yuv_texture", &self.yuv_texture.handle().as_raw())
@@ -41,6 +58,7 @@ impl std::fmt::Debug for Pipeline {
                 &self.textures.each_ref().map(|t| t.handle().as_raw()),
             )
             .field("camera_config", &self.camera_config)
+            .field("fov", &self.fov)
             .finish_non_exhaustive()
     }
 }
@@ -189,18 +207,44 @@ impl Pipeline {
         Ok(cmdbuf.end()?.execute(queue.clone())?)
     }
 
+    /// Where the `n`th stage before the lens correction writes to, the YUYV conversion
+    /// is stage 0. The last one writes into `output` directly if there is no lens
+    /// correction to do, the others alternate between the intermediate textures.
+    fn stage_output(&self, n: usize, output: &Arc<VkImage>) -> Arc<VkImage> {
+        let last = self.flat_field.is_some() as usize
+            + self.color_match.is_some() as usize
+            + self.color_filter.is_some() as usize;
+        let correction = self.correction.is_some() && !self.fused;
+        if n == last && !correction {
+            output.clone()
+        } else {
+            self.textures[n % 2].clone()
+        }
+    }
+
     /// Create post-processing stages
     ///
     /// Camera data -> upload -> internal texture
     /// internal texture -> YUYV conversion -> textures[0]
-    /// textures[0] -> Lens correction -> textures[1]
-    /// textures[1] -> projection -> Final output
+    /// textures[0] -> Flat-field correction -> textures[1]
+    /// textures[0 or 1] -> Colour matching -> textures[1 or 0]
+    /// textures[0 or 1] -> Colour filter -> textures[1 or 0]
+    /// textures[0 or 1] -> Lens correction -> Final output, or undistorted
+    /// textures[0 or 1] -> Final output, if lens correction is fused into the projection
+    /// undistorted -> Depth estimation -> depth map
+    /// undistorted -> copy -> Final output, or
+    /// depth map -> Depth visualisation -> Final output
     pub(crate) fn new(
         device: Arc<Device>,
         allocator: Arc<dyn MemoryAllocator>,
         descriptor_set_allocator: Arc<dyn DescriptorSetAllocator>,
         source_is_yuv: bool,
         camera_config: Option<crate::vrapi::StereoCamera>,
+        gain_map: Option<&crate::flat_field::GainMap>,
+        color_match: Option<&crate::config::ColorMatchConfig>,
+        color_filter: Option<&crate::config::ColorFilterConfig>,
+        correction_cfg: &crate::config::CorrectionConfig,
+        depth: Option<&crate::config::DepthConfig>,
     ) -> Result<Self> {
         let render_doc = renderdoc::RenderDoc::new().ok();
         if render_doc.is_some() {
@@ -246,19 +290,102 @@ impl Pipeline {
                 )
             })
             .transpose()?;
-        // if correction is enabled: output_a -> correction -> textures[1]
-        // otherwise: output_a
+        // the stages before the lens correction alternate between textures[0] and
+        // textures[1], `current` is the one the next stage reads from.
+        let mut current = 0;
+        // if flat-field correction is enabled: output_a -> flat field -> textures[1]
+        let flat_field = gain_map
+            .map(|gain_map| {
+                crate::flat_field::FlatField::new(
+                    device.clone(),
+                    allocator.clone(),
+                    descriptor_set_allocator.clone(),
+                    &textures[current],
+                    gain_map,
+                )
+            })
+            .transpose()?;
+        if flat_field.is_some() {
+            current = 1 - current;
+        }
+        // if colour matching is enabled: textures[current] -> matching -> the other texture
+        let color_match = color_match
+            .map(|cfg| {
+                crate::color_match::ColorMatch::new(
+                    device.clone(),
+                    allocator.clone(),
+                    descriptor_set_allocator.clone(),
+                    &textures[current],
+                    source_is_yuv,
+                    cfg,
+                )
+            })
+            .transpose()?;
+        if color_match.is_some() {
+            current = 1 - current;
+        }
+        // if colour filter is enabled: textures[current] -> filter -> the other texture
+        let color_filter = color_filter
+            .map(|cfg| {
+                crate::color::ColorFilter::new(
+                    device.clone(),
+                    allocator.clone(),
+                    descriptor_set_allocator.clone(),
+                    &textures[current],
+                    cfg,
+                )
+            })
+            .transpose()?;
+        if color_filter.is_some() {
+            current = 1 - current;
+        }
+        // if correction is enabled: textures[current] -> correction -> output
+        let correction_input = textures[current].clone();
         let correction = camera_config
             .map(|cfg| {
                 crate::distortion_correction::StereoCorrection::new(
                     device.clone(),
-                    allocator,
-                    descriptor_set_allocator,
-                    textures[0].clone(),
+                    allocator.clone(),
+                    descriptor_set_allocator.clone(),
+                    correction_input,
                     &cfg,
+                    correction_cfg,
                 )
             })
             .transpose()?;
+        // if depth is enabled: correction -> undistorted -> depth estimation
+        // depth estimation needs undistorted images, so it requires the correction stage.
+        let fused = correction.is_some() && correction_cfg.fused;
+        if depth.is_some() && correction.is_none() {
+            log::warn!("Depth estimation requires camera parameters, disabled");
+        }
+        let (depth, undistorted) = match (depth, camera_config.as_ref(), &correction) {
+            (Some(depth_cfg), Some(camera_config), Some(correction)) => {
+                let undistorted = device.clone().new_image(
+                    ImageCreateInfo {
+                        extent: [CAMERA_SIZE * 2, CAMERA_SIZE, 1],
+                        format: Format::R8G8B8A8_UNORM,
+                        usage: ImageUsage::SAMPLED
+                            | ImageUsage::COLOR_ATTACHMENT
+                            | ImageUsage::TRANSFER_SRC,
+                        ..Default::default()
+                    },
+                    MemoryTypeFilter::PREFER_DEVICE,
+                )?;
+                device.set_debug_utils_object_name(&undistorted, Some("undistorted"))?;
+                let depth = crate::depth::StereoDepth::new(
+                    device.clone(),
+                    allocator,
+                    descriptor_set_allocator,
+                    &undistorted,
+                    camera_config,
+                    &correction.fov(),
+                    depth_cfg,
+                )?;
+                (Some(depth), Some(undistorted))
+            }
+            _ => (None, None),
+        };
         let cpu_buffer = device.clone().new_buffer(
             BufferCreateInfo {
                 usage: BufferUsage::TRANSFER_SRC,
@@ -270,13 +397,31 @@ impl Pipeline {
             MemoryTypeFilter::HOST_SEQUENTIAL_WRITE | MemoryTypeFilter::PREFER_DEVICE,
         )?;
         log::debug!("correction fov: {:?}", correction.as_ref().map(|x| x.fov()));
+        // The correction crops the image for the zoom, and reports the fov of the cropped
+        // image. Projecting it with the fov of the uncropped image magnifies it.
         let fov = correction
             .as_ref()
             .map(|c| c.fov())
-            .unwrap_or([[1.19; 2]; 2]); // default to roughly 100 degrees fov, hopefully this is sensible
+            .unwrap_or_else(|| {
+                let fov = match correction_cfg.fov {
+                    OutputFov::Degrees(degrees) => {
+                        0.5 / (degrees.clamp(1.0, 179.0).to_radians() / 2.0).tan()
+                    }
+                    // default to roughly 100 degrees fov, hopefully this is sensible
+                    OutputFov::Fill | OutputFov::Fit => 1.19,
+                };
+                [[fov; 2]; 2]
+            })
+            .map(|eye| eye.map(|fov| fov / correction_cfg.zoom()));
         log::info!("Adjusted FOV: {:?}", fov);
         Ok(Self {
+            flat_field,
+            color_match,
             correction,
+            fused,
+            color_filter,
+            depth,
+            undistorted,
             yuv: converter,
             capture: false,
             render_doc,
@@ -284,19 +429,21 @@ impl Pipeline {
             yuv_texture,
             camera_config,
             cpu_image_buffer: cpu_buffer,
+            fov,
         })
     }
+    /// The fov of the output image, as passed to `Vr::submit_texture`
     pub fn fov(&self) -> [[f32; 2]; 2] {
-        self.correction
-            .as_ref()
-            .map(|c| c.fov())
-            .unwrap_or([[1.19; 2]; 2])
+        self.fov
     }
     /// Run the pipeline
     ///
     /// # Arguments
     ///
     /// - time: Time offset into the past when the camera frame is captured
+    /// - display_mode: in `Depth` mode, the depth visualisation is rendered into `output`
+    ///   instead of the camera image. in `Proximity` mode, far away pixels are made
+    ///   transparent.
     pub(crate) fn run(
         &mut self,
         queue: &Arc<vulkano::device::Queue>,
@@ -304,6 +451,7 @@ impl Pipeline {
         cmdbuf_allocator: Arc<dyn CommandBufferAllocator>,
         input: &[u8],
         output: Arc<vulkano::image::Image>,
+        display_mode: DisplayMode,
     ) -> Result<impl GpuFuture> {
         if self.capture {
             if let Some(rd) = self.render_doc.as_mut() {
@@ -312,13 +460,15 @@ impl Pipeline {
             }
         }
 
+        // In the fused path, lens correction is done by the projection
+        let correction = self.correction.as_ref().filter(|_| !self.fused);
+        if let Some(color_match) = &mut self.color_match {
+            color_match.update(input)?;
+        }
         // 1. submit image to GPU
         // 2. convert YUYV to RGB
-        let texture = if self.correction.is_some() {
-            self.textures[0].clone()
-        } else {
-            output.clone()
-        };
+        let mut stage = 0;
+        let texture = self.stage_output(stage, &output);
         let future = if let Some(converter) = &self.yuv {
             let future = self.submit_cpu_image(
                 input,
@@ -340,22 +490,117 @@ impl Pipeline {
             EitherGpuFuture::Right(future)
         };
         future.flush()?;
-        // 3. lens correction
-        let future = if let Some(correction) = &self.correction {
+        // 3. flat-field correction
+        let future = if let Some(flat_field) = &self.flat_field {
+            stage += 1;
+            let flat_field_output = self.stage_output(stage, &output);
+            let future = flat_field.apply(
+                allocator.clone(),
+                cmdbuf_allocator.clone(),
+                future,
+                queue,
+                flat_field_output,
+            )?;
+            future.flush()?;
+            EitherGpuFuture::Left(future)
+        } else {
+            EitherGpuFuture::Right(future)
+        };
+        // 4. colour matching
+        let future = if let Some(color_match) = &self.color_match {
+            stage += 1;
+            let match_output = self.stage_output(stage, &output);
+            let future = color_match.apply(
+                allocator.clone(),
+                cmdbuf_allocator.clone(),
+                future,
+                queue,
+                match_output,
+            )?;
+            future.flush()?;
+            EitherGpuFuture::Left(future)
+        } else {
+            EitherGpuFuture::Right(future)
+        };
+        // 5. colour filter
+        let future = if let Some(color_filter) = &self.color_filter {
+            stage += 1;
+            let filter_output = self.stage_output(stage, &output);
+            let future = color_filter.apply(
+                allocator.clone(),
+                cmdbuf_allocator.clone(),
+                future,
+                queue,
+                filter_output,
+            )?;
+            future.flush()?;
+            EitherGpuFuture::Left(future)
+        } else {
+            EitherGpuFuture::Right(future)
+        };
+        // 6. lens correction
+        let future = if let Some(correction) = correction {
+            let correction_output = self.undistorted.as_ref().unwrap_or(&output).clone();
             let mut future = correction.correct(
-                cmdbuf_allocator,
+                cmdbuf_allocator.clone(),
                 allocator.clone(),
                 future,
                 queue,
-                output.clone(),
+                correction_output,
             )?;
             future.flush()?;
             future.cleanup_finished();
+            EitherGpuFuture::Left(EitherGpuFuture::Left(future))
+        } else if let Some(correction) = self.correction.as_ref().filter(|c| c.has_pending_upload())
+        {
+            // The projection still needs the lookup tables
+            let future =
+                correction.upload_lookup_tables(cmdbuf_allocator.clone(), future, queue)?;
+            future.flush()?;
+            EitherGpuFuture::Left(EitherGpuFuture::Right(future))
+        } else {
+            EitherGpuFuture::Right(future)
+        };
+        // 7. depth estimation
+        let future = if let (Some(depth), Some(undistorted)) = (&self.depth, &self.undistorted) {
+            let future = depth.estimate(cmdbuf_allocator.clone(), future, queue)?;
+            future.flush()?;
+            let future = if let DisplayMode::Proximity { distance, fade, .. } = display_mode {
+                EitherGpuFuture::Left(EitherGpuFuture::Left(depth.mask(
+                    allocator,
+                    cmdbuf_allocator,
+                    future,
+                    queue,
+                    output,
+                    distance,
+                    fade,
+                )?))
+            } else if display_mode == DisplayMode::Depth {
+                EitherGpuFuture::Left(EitherGpuFuture::Right(depth.visualize(
+                    allocator,
+                    cmdbuf_allocator,
+                    future,
+                    queue,
+                    output,
+                )?))
+            } else {
+                let mut cmdbuf = RecordingCommandBuffer::new(
+                    cmdbuf_allocator,
+                    queue.queue_family_index(),
+                    CommandBufferLevel::Primary,
+                    CommandBufferBeginInfo {
+                        usage: CommandBufferUsage::OneTimeSubmit,
+                        ..Default::default()
+                    },
+                )?;
+                cmdbuf.copy_image(CopyImageInfo::images(undistorted.clone(), output))?;
+                EitherGpuFuture::Right(future.then_execute(queue.clone(), cmdbuf.end()?)?)
+            };
+            future.flush()?;
             EitherGpuFuture::Left(future)
         } else {
             EitherGpuFuture::Right(future)
         };
-        // TODO combine correction and projection
 
         if self.capture {
             if let Some(rd) = self.render_doc.as_mut() {
@@ -366,7 +611,24 @@ impl Pipeline {
         }
         Ok(future)
     }
+    /// The estimated depth map, if depth estimation is enabled. It is updated every time
+    /// `run` is called.
+    pub(crate) fn depth_map(&self) -> Option<crate::depth::DepthMap> {
+        self.depth.as_ref().map(|d| d.depth_map())
+    }
+    /// The lens correction lookup tables, if lens correction is fused into the projection.
+    pub(crate) fn lens_remap(&self) -> Option<crate::distortion_correction::LensRemap> {
+        self.correction
+            .as_ref()
+            .filter(|_| self.fused)
+            .and_then(|c| c.lens_remap())
+    }
     pub(crate) fn capture_next_frame(&mut self) {
         self.capture = true;
     }
+    /// Switch to the next colour filter, returns the new filter mode. Does nothing
+    /// if the colour filter stage is not enabled.
+    pub(crate) fn cycle_color_filter(&mut self) -> Result<Option<crate::config::ColorFilterMode>> {
+        self.color_filter.as_mut().map(|f| f.cycle()).transpose()
+    }
 }
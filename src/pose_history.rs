@@ -0,0 +1,166 @@
+//! History of recent poses, to look up where the HMD was when a camera frame was captured.
+//!
+//! The camera frames arrive tens of milliseconds after they are exposed, and neither runtime
+//! promises to answer pose queries in the past. So we sample the poses ourselves, and
+//! interpolate between the two samples around the time we are interested in.
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use nalgebra::{Matrix4, Translation3, UnitQuaternion, Vector3};
+
+/// A rotation and a position
+pub(crate) type Pose = (UnitQuaternion<f32>, Vector3<f32>);
+
+/// Samples older than this are dropped by default. Camera frames are never this late.
+const MAX_AGE: Duration = Duration::from_millis(500);
+
+/// Ring buffer of timestamped poses. Each sample has `N` poses, e.g. one for each eye.
+pub(crate) struct PoseHistory<const N: usize> {
+    samples: VecDeque<(Instant, [Pose; N])>,
+    max_age: Duration,
+}
+
+impl<const N: usize> Default for PoseHistory<N> {
+    fn default() -> Self {
+        Self::with_max_age(MAX_AGE)
+    }
+}
+
+impl<const N: usize> PoseHistory<N> {
+    /// A history that keeps the samples of the last `max_age`.
+    pub(crate) fn with_max_age(max_age: Duration) -> Self {
+        Self {
+            samples: VecDeque::new(),
+            max_age,
+        }
+    }
+    /// Record the poses at `time`. Samples must be pushed in time order.
+    pub(crate) fn push(&mut self, time: Instant, poses: [Pose; N]) {
+        if self
+            .samples
+            .back()
+            .is_some_and(|(last_time, _)| *last_time >= time)
+        {
+            return;
+        }
+        while self
+            .samples
+            .front()
+            .is_some_and(|(first_time, _)| time.duration_since(*first_time) > self.max_age)
+        {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((time, poses));
+    }
+    /// The poses at `time`, interpolated between the samples around it. Times outside of
+    /// the recorded range get the oldest or the newest sample. `None` if nothing has been
+    /// recorded yet.
+    pub(crate) fn at(&self, time: Instant) -> Option<[Pose; N]> {
+        let after = self.samples.partition_point(|(t, _)| *t <= time);
+        if after == 0 {
+            return self.samples.front().map(|(_, poses)| *poses);
+        }
+        let (t0, poses0) = &self.samples[after - 1];
+        let Some((t1, poses1)) = self.samples.get(after) else {
+            return Some(*poses0);
+        };
+        let s = (time - *t0).as_secs_f32() / (*t1 - *t0).as_secs_f32();
+        Some(std::array::from_fn(|i| {
+            let (r0, p0) = &poses0[i];
+            let (r1, p1) = &poses1[i];
+            // Only undefined for opposite rotations, which two samples this close never are
+            let rotation = r0.try_slerp(r1, s, 1e-6).unwrap_or(*r0);
+            (rotation, p0.lerp(p1, s))
+        }))
+    }
+}
+
+/// Split a rigid transform into a pose.
+pub(crate) fn pose_from_transform(m: &Matrix4<f32>) -> Pose {
+    let rotation = UnitQuaternion::from_matrix(&m.fixed_view::<3, 3>(0, 0).into_owned());
+    (rotation, m.fixed_view::<3, 1>(0, 3).into_owned())
+}
+
+/// Rebuild the rigid transform of a pose.
+pub(crate) fn pose_to_transform((rotation, position): &Pose) -> Matrix4<f32> {
+    Translation3::from(*position).to_homogeneous() * rotation.to_homogeneous()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+
+    use super::*;
+
+    fn pose(angle: f32, x: f32) -> [Pose; 1] {
+        [(
+            UnitQuaternion::from_axis_angle(&Vector3::z_axis(), angle),
+            Vector3::new(x, 0.0, 0.0),
+        )]
+    }
+
+    fn assert_pose_eq(
+        [(rotation, position)]: [Pose; 1],
+        [(expected_rotation, expected_position)]: [Pose; 1],
+    ) {
+        // q and -q are the same rotation
+        assert!(
+            (rotation.coords - expected_rotation.coords).norm() < 1e-5
+                || (rotation.coords + expected_rotation.coords).norm() < 1e-5,
+            "rotation {rotation:?}, expected {expected_rotation:?}"
+        );
+        assert!(
+            (position - expected_position).norm() < 1e-5,
+            "position {position:?}, expected {expected_position:?}"
+        );
+    }
+
+    /// Two samples 100ms apart: no rotation at x = 0, then 90° at x = 1
+    fn history() -> (Instant, PoseHistory<1>) {
+        let start = Instant::now();
+        let mut history = PoseHistory::default();
+        history.push(start, pose(0.0, 0.0));
+        history.push(start + Duration::from_millis(100), pose(FRAC_PI_2, 1.0));
+        (start, history)
+    }
+
+    #[test]
+    fn empty() {
+        assert!(PoseHistory::<1>::default().at(Instant::now()).is_none());
+    }
+
+    #[test]
+    fn interpolates_between_samples() {
+        let (start, history) = history();
+        let pose_at = |ms| history.at(start + Duration::from_millis(ms)).unwrap();
+        assert_pose_eq(pose_at(0), pose(0.0, 0.0));
+        assert_pose_eq(pose_at(25), pose(FRAC_PI_2 * 0.25, 0.25));
+        assert_pose_eq(pose_at(50), pose(FRAC_PI_2 * 0.5, 0.5));
+        assert_pose_eq(pose_at(100), pose(FRAC_PI_2, 1.0));
+    }
+
+    #[test]
+    fn clamps_outside_of_the_samples() {
+        let (start, history) = history();
+        let before = start.checked_sub(Duration::from_millis(10)).unwrap();
+        assert_pose_eq(history.at(before).unwrap(), pose(0.0, 0.0));
+        let after = start + Duration::from_millis(200);
+        assert_pose_eq(history.at(after).unwrap(), pose(FRAC_PI_2, 1.0));
+    }
+
+    #[test]
+    fn slerps_the_shorter_way() {
+        // 350° to 10° is a 20° turn through 0°, not a 340° turn back
+        let start = Instant::now();
+        let mut history = PoseHistory::default();
+        history.push(start, pose(350f32.to_radians(), 0.0));
+        history.push(
+            start + Duration::from_millis(100),
+            pose(10f32.to_radians(), 0.0),
+        );
+        let middle = history.at(start + Duration::from_millis(50)).unwrap();
+        assert_pose_eq(middle, pose(0.0, 0.0));
+    }
+}
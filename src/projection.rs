@@ -5,10 +5,26 @@
 //! Overlay vertex * Overlay Model * HMD View * Camera Project -> Texture coordinates used to
 //! sample the camera's view.
 //!
-//! Overlay vertex: calculate based on Overlay width we set
+//! Overlay vertex: calculate based on Overlay width and height we set, bent into a cylinder if the
+//! overlay is curved. The overlay is drawn as a strip of narrow quads in that case, so the
+//! interpolation between vertices stays close to the curved surface.
 //! Overlay Model: the overlay transform matrix we set
 //! HMD View: inverse of HMD pose
 //! Camera Project: estimated from camera calibration.
+//!
+//! With `ProjectionMode::FromDepth`, instead of treating every camera pixel as if it were on the
+//! overlay plane, we march along the ray from the eye through each overlay pixel, and use the
+//! estimated depth map to find where the ray hits the real world.
+//!
+//! With `ProjectionMode::FixedDepth`, everything is assumed to be on a plane at a fixed distance
+//! in front of the eyes. Mapping the overlay onto that plane, as seen from the eye, is a
+//! projective transform, so it is simply added to the MVP.
+//!
+//! If given the lens correction lookup tables, the source image is not lens corrected, and the
+//! correction is done here instead, see `CorrectionConfig::fused`.
+//!
+//! If the overlay has a mask, it is drawn over each eye right after the projection, and the
+//! out of view indicator after that, see `out_of_view`.
 use anyhow::Result;
 use std::sync::Arc;
 use vulkano::{
@@ -46,7 +62,7 @@ use vulkano::{
     },
     render_pass::{Framebuffer, RenderPass, Subpass},
     sync::{GpuFuture, HostAccessError},
-    Validated, VulkanError,
+    Handle, Validated, VulkanError, VulkanObject,
 };
 mod vs {
     vulkano_shaders::shader! {
@@ -64,14 +80,57 @@ mod fs {
     }
 }
 
+mod fused_fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "shaders/projection_fused.frag",
+    }
+}
+
+mod depth_vs {
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "shaders/projection_depth.vert",
+        custom_derives: [Copy, Clone, Debug, Default],
+    }
+}
+
+mod depth_fs {
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "shaders/projection_depth.frag",
+        custom_derives: [Copy, Clone, Debug, Default],
+    }
+}
+
+/// Number of quads a curved overlay is split into
+const CYLINDER_SEGMENTS: usize = 32;
+
+/// `coverage` samples a grid of this many by this many points on the overlay, for each eye
+const COVERAGE_SAMPLES: usize = 16;
+
 #[derive(PartialEq, Debug)]
 pub struct ProjectionParameters {
     pub ipd: f32,
-    pub overlay_width: f32,
+    /// Width and height of the overlay, in meters
+    pub overlay_size: [f32; 2],
+    /// Radius of the cylinder the overlay is bent into, 0 if it is flat
+    pub overlay_radius: f32,
     /// MVP matrices for the left and right eye, respectively.
     pub mvps: [Matrix4<f32>; 2],
     pub camera_calib: Option<crate::vrapi::StereoCamera>,
     pub mode: ProjectionMode,
+    /// Overlay to camera space transforms for the left and right camera, respectively. Only
+    /// used by `ProjectionMode::FromDepth`.
+    pub depth_views: [Matrix4<f32>; 2],
+    /// Positions of the left and right eye, in the camera space of the camera on the same
+    /// side. Only used by `ProjectionMode::FromDepth`.
+    pub eye_positions: [Vector3<f32>; 2],
+    /// Adjusted fovs of the camera images. Only used by `ProjectionMode::FromDepth`.
+    pub fov: [[f32; 2]; 2],
+    /// Distance of the plane in front of the eyes, in meters. Only used by
+    /// `ProjectionMode::FixedDepth`.
+    pub fixed_depth: f32,
 }
 
 struct Uniforms {
@@ -86,6 +145,22 @@ impl std::fmt::Debug for Uniforms {
     }
 }
 
+/// Pipeline used by `ProjectionMode::FromDepth`
+struct DepthProjection {
+    pipeline: Arc<GraphicsPipeline>,
+    transforms: [Subbuffer<depth_vs::DepthTransform>; 2],
+    infos: [Subbuffer<depth_fs::DepthInfo>; 2],
+    desc_sets: [Arc<DescriptorSet>; 2],
+}
+
+impl std::fmt::Debug for DepthProjection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DepthProjection")
+            .field("pipeline", &self.pipeline.handle().as_raw())
+            .finish_non_exhaustive()
+    }
+}
+
 #[derive(Debug)]
 pub struct Projection {
     extent: [u32; 2],
@@ -96,7 +171,11 @@ pub struct Projection {
     saved_parameters: ProjectionParameters,
     mode_ipd_changed: bool,
     mvps_changed: bool,
+    overlay_changed: bool,
     desc_sets: [Arc<DescriptorSet>; 2],
+    depth: Option<DepthProjection>,
+    mask: Option<crate::overlay_mask::OverlayMask>,
+    indicator: Option<crate::out_of_view::Indicator>,
 }
 use crate::config::ProjectionMode;
 #[derive(VertexTrait, Default, Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
@@ -143,6 +222,8 @@ pub enum ProjectorError {
     HostAccess(#[from] HostAccessError),
     #[error("command buffer execution error: {0}")]
     CommandBuffer(#[from] CommandBufferExecError),
+    #[error("overlay mask error: {0}")]
+    Mask(#[from] crate::overlay_mask::MaskError),
 }
 
 impl From<Box<vulkano::ValidationError>> for ProjectorError {
@@ -151,43 +232,56 @@ impl From<Box<vulkano::ValidationError>> for ProjectorError {
     }
 }
 
-use nalgebra::{matrix, Matrix4, RawStorage, Scalar};
+use nalgebra::{matrix, Matrix2, Matrix3, Matrix4, RawStorage, Scalar, Vector2, Vector3};
+
+/// Maps a point X on the overlay to where the ray from `eye` through X hits the plane
+/// `{ Y | normal · Y = offset }`. This is a central projection from one plane to another,
+/// so it can be written as a projective transform:
+///
+/// Y = E + k (X - E) / (n · (X - E)), with k = offset - n · E
+///
+/// Multiplying by n · (X - E) gives the homogeneous form below.
+fn plane_projection(eye: &Vector3<f32>, normal: &Vector3<f32>, offset: f32) -> Matrix4<f32> {
+    let k = offset - normal.dot(eye);
+    let mut m = Matrix4::zeros();
+    m.fixed_view_mut::<3, 3>(0, 0)
+        .copy_from(&(eye * normal.transpose() + Matrix3::identity() * k));
+    m.fixed_view_mut::<3, 1>(0, 3).copy_from(&(-eye * offset));
+    m.fixed_view_mut::<1, 3>(3, 0)
+        .copy_from(&normal.transpose());
+    m[(3, 3)] = -normal.dot(eye);
+    m
+}
+
 impl Projection {
     /// Calculate the _physical_ camera's MVP, for each eye.
+    /// overlay_transforms = where the overlay is, as seen by the left and right eye. These
+    /// are the same, unless each eye has its own overlay covering its view.
     /// camera_calib = camera calibration data.
     /// fov_left/right = adjusted fovs, in ratio (not in pixels)
-    /// frame_time = how long after the first frame is the current frame taken
-    /// time_origin = instant when the first frame is taken
+    /// view_transforms = where the eyes are when the overlay is displayed.
+    /// hmd_transform = where the HMD was when the camera frame was captured, interpolated from
+    /// the pose history.
     pub(crate) fn update_mvps(
         &mut self,
-        overlay_transform: &Matrix4<f32>,
+        overlay_transforms: &[Matrix4<f32>; 2],
         fov: &[[f32; 2]; 2],
         view_tranforms: &[Matrix4<f32>; 2],
         hmd_transform: &Matrix4<f32>,
     ) -> Result<(), ProjectorError> {
-        let left_extrinsics_position = self
-            .saved_parameters
-            .camera_calib
-            .map(|c| c.left.extrinsics.position.map(|x| x as f32))
-            .unwrap_or_default();
         // Camera space to HMD space transform, based on physical measurements
-        let left_cam: Matrix4<_> = matrix![
-            1.0, 0.0, 0.0, -left_extrinsics_position[0];
-            0.0, 1.0, 0.0, -left_extrinsics_position[1];
-            0.0, 0.0, 1.0, -left_extrinsics_position[2];
-            0.0, 0.0, 0.0, 1.0;
-        ];
-        let right_extrinsics_position = self
+        let [left_cam, right_cam] = self
             .saved_parameters
             .camera_calib
-            .map(|c| c.right.extrinsics.position.map(|x| x as f32))
-            .unwrap_or_default();
-        let right_cam: Matrix4<_> = matrix![
-            1.0, 0.0, 0.0, -right_extrinsics_position[0];
-            0.0, 1.0, 0.0, -right_extrinsics_position[1];
-            0.0, 0.0, 1.0, -right_extrinsics_position[2];
-            0.0, 0.0, 0.0, 1.0;
-        ];
+            .map(|c| [c.left, c.right].map(|c| c.extrinsics.camera_to_hmd()))
+            .unwrap_or([Matrix4::identity(); 2]);
+        // The camera images are in the cameras' orientation, even when we assume the cameras
+        // are at the eyes.
+        let [left_rotation, right_rotation] = [left_cam, right_cam].map(|m| {
+            let mut m = m;
+            m.fixed_view_mut::<3, 1>(0, 3).fill(0.0);
+            m
+        });
 
         log::trace!(
             "eye to head: left: {:?} right: {:?}",
@@ -195,9 +289,46 @@ impl Projection {
             view_tranforms[1]
         );
 
+        if self.depth.is_some() && self.saved_parameters.mode == ProjectionMode::FromDepth {
+            let camera_views = [hmd_transform * left_cam, hmd_transform * right_cam]
+                .map(|m| m.try_inverse().expect("HMD transform not invertable?"));
+            let eye_positions = [0, 1].map(|i| {
+                (camera_views[i] * view_tranforms[i].column(3))
+                    .fixed_rows::<3>(0)
+                    .into_owned()
+            });
+            self.set_depth_views(
+                [0, 1].map(|i| camera_views[i] * overlay_transforms[i]),
+                eye_positions,
+                *fov,
+            );
+        }
+
+        // Without a depth map, `FromDepth` falls back to projecting the camera image from the
+        // eyes, onto the overlay plane.
         let (left_eye, right_eye) = match self.saved_parameters.mode {
-            ProjectionMode::FromEye => (view_tranforms[0], view_tranforms[1]),
-            ProjectionMode::FromCamera => (hmd_transform * left_cam, hmd_transform * right_cam),
+            ProjectionMode::FromEye | ProjectionMode::FromDepth => (
+                view_tranforms[0] * left_rotation,
+                view_tranforms[1] * right_rotation,
+            ),
+            ProjectionMode::FromCamera | ProjectionMode::FixedDepth => {
+                (hmd_transform * left_cam, hmd_transform * right_cam)
+            }
+        };
+        // With `FixedDepth`, the overlay is first projected onto the plane, as seen from the
+        // eyes. The real cameras then see that plane.
+        let overlay_transforms = if self.saved_parameters.mode == ProjectionMode::FixedDepth {
+            let eyes = view_tranforms.map(|m| m.fixed_view::<3, 1>(0, 3).into_owned());
+            // Facing the eyes, `fixed_depth` in front of the point between them
+            let normal = -view_tranforms[0]
+                .fixed_view::<3, 1>(0, 2)
+                .into_owned()
+                .normalize();
+            let center = (eyes[0] + eyes[1]) / 2.0;
+            let offset = normal.dot(&center) + self.saved_parameters.fixed_depth;
+            [0, 1].map(|i| plane_projection(&eyes[i], &normal, offset) * overlay_transforms[i])
+        } else {
+            *overlay_transforms
         };
         let left_view = left_eye
             .try_inverse()
@@ -223,8 +354,8 @@ impl Projection {
             0.0, 0.0, 0.0, 1.0;
         ];
         self.set_mvps([
-            (camera_projection_left * left_view * overlay_transform).cast(),
-            (camera_projection_right * right_view * overlay_transform).cast(),
+            (camera_projection_left * left_view * overlay_transforms[0]).cast(),
+            (camera_projection_right * right_view * overlay_transforms[1]).cast(),
         ]);
         Ok(())
     }
@@ -243,6 +374,47 @@ impl Projection {
         self.saved_parameters.mvps = mvps;
         self.mvps_changed = true;
     }
+    fn set_depth_views(
+        &mut self,
+        depth_views: [Matrix4<f32>; 2],
+        eye_positions: [Vector3<f32>; 2],
+        fov: [[f32; 2]; 2],
+    ) {
+        let params = &mut self.saved_parameters;
+        if params.depth_views == depth_views
+            && params.eye_positions == eye_positions
+            && params.fov == fov
+        {
+            return;
+        }
+        params.depth_views = depth_views;
+        params.eye_positions = eye_positions;
+        params.fov = fov;
+        self.mvps_changed = true;
+    }
+    /// Set the width and height of the overlay, in meters. Must match the size the overlay
+    /// is displayed with.
+    pub fn set_overlay_size(&mut self, size: [f32; 2]) {
+        if self.saved_parameters.overlay_size == size {
+            return;
+        }
+        self.saved_parameters.overlay_size = size;
+        self.overlay_changed = true;
+    }
+    /// Bend the overlay into a cylinder with the given radius, 0 for a flat overlay. Must
+    /// match the shape the overlay is displayed with.
+    pub fn set_overlay_radius(&mut self, radius: f32) {
+        if self.saved_parameters.overlay_radius == radius {
+            return;
+        }
+        self.saved_parameters.overlay_radius = radius;
+        self.overlay_changed = true;
+    }
+    /// Set the distance of the plane used by `ProjectionMode::FixedDepth`, takes effect
+    /// with the next `update_mvps`.
+    pub fn set_fixed_depth(&mut self, distance: f32) {
+        self.saved_parameters.fixed_depth = distance;
+    }
     pub fn set_mode(&mut self, mode: ProjectionMode) {
         if self.saved_parameters.mode == mode {
             return;
@@ -250,40 +422,188 @@ impl Projection {
         self.saved_parameters.mode = mode;
         self.mode_ipd_changed = true;
     }
+    /// Offsets of the overlay vertices for the left and right eye, see `eyeOffset` in the
+    /// vertex shader
+    fn eye_offsets(&self) -> [[f32; 2]; 2] {
+        let ProjectionParameters {
+            mode,
+            ipd,
+            camera_calib,
+            ..
+        } = &self.saved_parameters;
+        let left_extrinsics_position = camera_calib
+            .map(|c| c.left.extrinsics.position)
+            .unwrap_or_default();
+        let eye_offset_left = if *mode == ProjectionMode::FromEye {
+            [
+                -left_extrinsics_position[0] as f32 - ipd / 2.0,
+                left_extrinsics_position[1] as f32,
+            ]
+        } else {
+            [0.0, 0.0]
+        };
+        [eye_offset_left, [-eye_offset_left[0], eye_offset_left[1]]]
+    }
+    /// How much of the overlay the cameras see, from 0 to 1, averaged over both eyes, and the
+    /// direction from the centre of the overlay towards the part they see, in meters in
+    /// overlay space with y up. The direction is a rough guess if they see none of it, or
+    /// zero if the overlay is behind them.
+    ///
+    /// Samples the overlay the way the vertex shader maps it onto the camera images, with the
+    /// MVPs from the last `update_mvps`. The depth map is not taken into account.
+    pub(crate) fn coverage(&self) -> (f32, [f32; 2]) {
+        let ProjectionParameters {
+            mvps,
+            overlay_size: [width, height],
+            overlay_radius: radius,
+            ..
+        } = &self.saved_parameters;
+        // Where a point on the overlay is in the camera image, `None` if it is behind the
+        // camera. See `overlaySurface`.
+        let texture_coordinates = |mvp: &Matrix4<f32>, x: f32, y: f32| {
+            let surface = if *radius > 0.0 {
+                let angle = x / radius;
+                Vector3::new(radius * angle.sin(), y, radius * (1.0 - angle.cos()))
+            } else {
+                Vector3::new(x, y, 0.0)
+            };
+            let coord = mvp * surface.push(1.0);
+            (coord.z > 0.0).then(|| Vector2::new(coord.x / coord.z + 0.25, coord.y / coord.z + 0.5))
+        };
+        let mut visible = 0;
+        let mut centroid = Vector2::zeros();
+        for (mvp, eye_offset) in mvps.iter().zip(self.eye_offsets()) {
+            for i in 0..COVERAGE_SAMPLES {
+                for j in 0..COVERAGE_SAMPLES {
+                    let [u, v] =
+                        [i, j].map(|k| (k as f32 + 0.5) / COVERAGE_SAMPLES as f32 * 2.0 - 1.0);
+                    let is_visible = texture_coordinates(
+                        mvp,
+                        (u + eye_offset[0]) * width / 2.0,
+                        -(v + eye_offset[1]) * height / 2.0,
+                    )
+                    .is_some_and(|tex| {
+                        (0.0..=0.5).contains(&tex.x) && (0.0..=1.0).contains(&tex.y)
+                    });
+                    if is_visible {
+                        visible += 1;
+                        centroid += Vector2::new(u * width / 2.0, -v * height / 2.0);
+                    }
+                }
+            }
+        }
+        if visible > 0 {
+            let total = 2 * COVERAGE_SAMPLES * COVERAGE_SAMPLES;
+            return (
+                visible as f32 / total as f32,
+                (centroid / visible as f32).into(),
+            );
+        }
+        // Nothing is seen, go by how far the centre of the overlay is outside of the camera
+        // image. That offset is in texture coordinates, map it back into overlay space with
+        // the inverse of how the texture coordinates change around the centre. No arrow if
+        // the centre is behind the camera.
+        let step = (width + height) / 100.0;
+        let mut direction = Vector2::zeros();
+        for mvp in mvps {
+            let (Some(center), Some(right), Some(up)) = (
+                texture_coordinates(mvp, 0.0, 0.0),
+                texture_coordinates(mvp, step, 0.0),
+                texture_coordinates(mvp, 0.0, step),
+            ) else {
+                continue;
+            };
+            let jacobian = Matrix2::from_columns(&[right - center, up - center]);
+            let offset = Vector2::new(
+                center.x.clamp(0.0, 0.5) - center.x,
+                center.y.clamp(0.0, 1.0) - center.y,
+            );
+            if let Some(inverse) = jacobian.try_inverse() {
+                direction += (inverse * offset).normalize();
+            }
+        }
+        if !direction.iter().all(|v| v.is_finite()) {
+            direction = Vector2::zeros();
+        }
+        (0.0, direction.into())
+    }
+    /// Draw a border and an arrow over the overlay while it is out of view, see
+    /// `set_indicator`.
+    pub(crate) fn enable_indicator(
+        &mut self,
+        device: Arc<Device>,
+        allocator: Arc<dyn MemoryAllocator>,
+        descriptor_set_allocator: Arc<dyn DescriptorSetAllocator>,
+    ) -> Result<(), ProjectorError> {
+        if self.indicator.is_none() {
+            self.indicator = Some(crate::out_of_view::Indicator::new(
+                device,
+                allocator,
+                descriptor_set_allocator,
+                &self.render_pass,
+            )?);
+        }
+        Ok(())
+    }
+    /// Show the indicator with the arrow pointing towards `direction`, see `coverage`, or hide
+    /// it. Does nothing unless `enable_indicator` was called.
+    pub(crate) fn set_indicator(
+        &mut self,
+        direction: Option<[f32; 2]>,
+    ) -> Result<(), ProjectorError> {
+        if let Some(indicator) = &mut self.indicator {
+            indicator.set(direction, self.saved_parameters.overlay_size)?;
+        }
+        Ok(())
+    }
     pub fn recalculate_uniforms(&mut self) -> Result<(), ProjectorError> {
-        if !self.mode_ipd_changed && !self.mvps_changed {
+        if !self.mode_ipd_changed && !self.mvps_changed && !self.overlay_changed {
             return Ok(());
         }
 
         let ProjectionParameters {
-            mode,
-            ipd,
             mvps,
-            camera_calib,
+            depth_views,
+            eye_positions,
+            fov,
+            overlay_size,
+            overlay_radius,
             ..
         } = &self.saved_parameters;
         let mut transforms_write = self.uniforms.transforms.each_ref().try_map(|u| u.write())?;
         if self.mode_ipd_changed {
-            let left_extrinsics_position = camera_calib
-                .map(|c| c.left.extrinsics.position)
-                .unwrap_or_default();
-            let eye_offset_left = if *mode == ProjectionMode::FromEye {
-                [
-                    -left_extrinsics_position[0] as f32 - ipd / 2.0,
-                    left_extrinsics_position[1] as f32,
-                ]
-            } else {
-                [0.0, 0.0]
-            };
-            let eye_offset_right = [-eye_offset_left[0], eye_offset_left[1]];
+            let [eye_offset_left, eye_offset_right] = self.eye_offsets();
             transforms_write[0].eyeOffset = eye_offset_left;
             transforms_write[1].eyeOffset = eye_offset_right;
             self.mode_ipd_changed = false;
         }
+        if self.overlay_changed {
+            for write in transforms_write.iter_mut() {
+                write.overlaySize = *overlay_size;
+                write.overlayRadius = (*overlay_radius).into();
+            }
+            if let Some(depth) = &self.depth {
+                for transform in &depth.transforms {
+                    let mut transform = transform.write()?;
+                    transform.overlaySize = *overlay_size;
+                    transform.overlayRadius = (*overlay_radius).into();
+                }
+            }
+            self.overlay_changed = false;
+        }
         if self.mvps_changed {
             for (mvp, write) in mvps.iter().zip(transforms_write.iter_mut()) {
                 write.mvp = *mvp.as_ref();
             }
+            if let Some(depth) = &self.depth {
+                for (i, (transform, info)) in depth.transforms.iter().zip(&depth.infos).enumerate()
+                {
+                    transform.write()?.overlayToCamera = *depth_views[i].as_ref();
+                    let mut info = info.write()?;
+                    info.eyePosition = eye_positions[i].push(1.0).into();
+                    info.fov = fov[i].into();
+                }
+            }
             self.mvps_changed = false;
         }
 
@@ -309,13 +629,17 @@ impl Projection {
             uniform,
         )
     }
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         device: Arc<Device>,
         allocator: Arc<dyn MemoryAllocator>,
         descriptor_set_allocator: Arc<dyn DescriptorSetAllocator>,
         source: &Arc<Image>,
-        overlay_width: f32,
+        overlay_size: [f32; 2],
         camera_calib: &Option<crate::vrapi::StereoCamera>,
+        depth_map: Option<&crate::depth::DepthMap>,
+        lens_remap: Option<&crate::distortion_correction::LensRemap>,
+        mask: Option<&Arc<crate::overlay_mask::Mask>>,
         final_layout: ImageLayout,
     ) -> Result<Self, ProjectorError> {
         let [w, h, _] = source.extent();
@@ -323,7 +647,11 @@ impl Projection {
             return Err(ProjectorError::NotSquare(w, h));
         }
         let vs = vs::load(device.clone())?;
-        let fs = fs::load(device.clone())?;
+        let fs = if lens_remap.is_some() {
+            fused_fs::load(device.clone())?
+        } else {
+            fs::load(device.clone())?
+        };
         let render_pass = vulkano::single_pass_renderpass!(device.clone(),
             attachments: {
                 color: {
@@ -352,31 +680,135 @@ impl Projection {
         let vs = vs.entry_point("main").unwrap();
         let fs = fs.entry_point("main").unwrap();
         let stages = [
-            PipelineShaderStageCreateInfo::new(vs.clone()),
+            PipelineShaderStageCreateInfo::new(vs),
             PipelineShaderStageCreateInfo::new(fs),
         ];
-        let layout = PipelineLayout::new(
-            device.clone(),
-            PipelineDescriptorSetLayoutCreateInfo::from_stages(&stages)
-                .into_pipeline_layout_create_info(device.clone())?,
-        )?;
+        let layout = Self::create_pipeline_layout(device.clone(), &stages)?;
         let transforms = [vs::Transform::default(); 2]
             .try_map(|u| Self::make_uniform_buffer(allocator.clone(), u))?;
         {
             let mut transform_writes = [transforms[0].write()?, transforms[1].write()?];
-            transform_writes[0].overlayWidth = overlay_width.into();
-            transform_writes[1].overlayWidth = overlay_width.into();
+            transform_writes[0].overlaySize = overlay_size;
+            transform_writes[1].overlaySize = overlay_size;
         }
         log::info!("before");
-        let pipeline = GraphicsPipeline::new(
+        let pipeline = Self::create_pipeline(device.clone(), &render_pass, stages, layout)?;
+        log::info!("after");
+        let init_params = ProjectionParameters {
+            mode: ProjectionMode::FromCamera, // This means `eyeOffset` should be zero, which would
+            // be what is returned by `bytemuck::Zeroable`
+            ipd: f32::NAN,
+            overlay_size,
+            overlay_radius: 0.0,
+            camera_calib: *camera_calib,
+            mvps: [Matrix4::identity(), Matrix4::identity()],
+            depth_views: [Matrix4::identity(), Matrix4::identity()],
+            eye_positions: [Vector3::zeros(), Vector3::zeros()],
+            fov: [[0.0; 2]; 2],
+            fixed_depth: crate::config::default_fixed_depth_distance(),
+        };
+        let layout = pipeline.layout().set_layouts().first().unwrap();
+        let sampler = Sampler::new(
+            device.clone(),
+            SamplerCreateInfo {
+                min_filter: Filter::Linear,
+                mag_filter: Filter::Linear,
+                ..Default::default()
+            },
+        )?;
+        let desc_sets = [0, 1].try_map(|i| {
+            let source_view =
+                ImageView::new(source.clone(), ImageViewCreateInfo::from_image(source))?;
+            let writes = if let Some(lens_remap) = lens_remap {
+                let lut = &lens_remap.lookup_tables[i];
+                [
+                    WriteDescriptorSet::buffer(0, transforms[i].clone()),
+                    WriteDescriptorSet::image_view_sampler(1, source_view, sampler.clone()),
+                    WriteDescriptorSet::image_view_sampler(
+                        3,
+                        ImageView::new(lut.clone(), ImageViewCreateInfo::from_image(lut))?,
+                        lens_remap.sampler.clone(),
+                    ),
+                ]
+            } else {
+                [
+                    WriteDescriptorSet::buffer(0, transforms[i].clone()),
+                    WriteDescriptorSet::image_view_sampler(1, source_view, sampler.clone()),
+                    WriteDescriptorSet::buffer(2, tex_offsets[i].clone()),
+                ]
+            };
+            DescriptorSet::new(
+                descriptor_set_allocator.clone(),
+                layout.clone(),
+                writes,
+                None,
+            )
+            .map_err(ProjectorError::from)
+        })?;
+        let mask = mask
+            .map(|mask| {
+                crate::overlay_mask::OverlayMask::new(
+                    device.clone(),
+                    descriptor_set_allocator.clone(),
+                    mask,
+                    render_pass.clone(),
+                )
+            })
+            .transpose()?;
+        let depth = depth_map
+            .map(|depth_map| {
+                Self::create_depth_projection(
+                    device,
+                    allocator,
+                    descriptor_set_allocator,
+                    &render_pass,
+                    source,
+                    sampler,
+                    depth_map,
+                    overlay_size,
+                )
+            })
+            .transpose()?;
+        let source_extent = source.extent();
+        Ok(Self {
+            saved_parameters: init_params,
+            uniforms: Uniforms { transforms },
+            desc_sets,
+            render_pass,
+            pipeline,
+            extent: [source_extent[0], source_extent[1]],
+            mode_ipd_changed: true,
+            mvps_changed: true,
+            overlay_changed: false,
+            depth,
+            mask,
+            indicator: None,
+        })
+    }
+    fn create_pipeline_layout(
+        device: Arc<Device>,
+        stages: &[PipelineShaderStageCreateInfo],
+    ) -> Result<Arc<PipelineLayout>, ProjectorError> {
+        Ok(PipelineLayout::new(
             device.clone(),
+            PipelineDescriptorSetLayoutCreateInfo::from_stages(stages)
+                .into_pipeline_layout_create_info(device)?,
+        )?)
+    }
+    fn create_pipeline(
+        device: Arc<Device>,
+        render_pass: &Arc<RenderPass>,
+        stages: [PipelineShaderStageCreateInfo; 2],
+        layout: Arc<PipelineLayout>,
+    ) -> Result<Arc<GraphicsPipeline>, ProjectorError> {
+        let vertex_input_state = Vertex::per_vertex()
+            .definition(&stages[0].entry_point.info().input_interface)
+            .map_err(Validated::<VulkanError>::from)?;
+        Ok(GraphicsPipeline::new(
+            device,
             None,
             GraphicsPipelineCreateInfo {
-                vertex_input_state: Some(
-                    Vertex::per_vertex()
-                        .definition(&vs.info().input_interface)
-                        .map_err(Validated::<VulkanError>::from)?,
-                ),
+                vertex_input_state: Some(vertex_input_state),
                 stages: stages.into_iter().collect(),
                 input_assembly_state: Some(InputAssemblyState {
                     topology: PrimitiveTopology::TriangleStrip,
@@ -393,25 +825,60 @@ impl Projection {
                 )),
                 ..GraphicsPipelineCreateInfo::layout(layout)
             },
-        )?;
-        log::info!("after");
-        let init_params = ProjectionParameters {
-            mode: ProjectionMode::FromCamera, // This means `eyeOffset` should be zero, which would
-            // be what is returned by `bytemuck::Zeroable`
-            ipd: f32::NAN,
-            overlay_width,
-            camera_calib: *camera_calib,
-            mvps: [Matrix4::identity(), Matrix4::identity()],
-        };
-        let layout = pipeline.layout().set_layouts().first().unwrap();
-        let sampler = Sampler::new(
+        )?)
+    }
+    #[allow(clippy::too_many_arguments)]
+    fn create_depth_projection(
+        device: Arc<Device>,
+        allocator: Arc<dyn MemoryAllocator>,
+        descriptor_set_allocator: Arc<dyn DescriptorSetAllocator>,
+        render_pass: &Arc<RenderPass>,
+        source: &Arc<Image>,
+        sampler: Arc<Sampler>,
+        depth_map: &crate::depth::DepthMap,
+        overlay_size: [f32; 2],
+    ) -> Result<DepthProjection, ProjectorError> {
+        let vs = depth_vs::load(device.clone())?.entry_point("main").unwrap();
+        let fs = depth_fs::load(device.clone())?.entry_point("main").unwrap();
+        let stages = [
+            PipelineShaderStageCreateInfo::new(vs),
+            PipelineShaderStageCreateInfo::new(fs),
+        ];
+        let layout = Self::create_pipeline_layout(device.clone(), &stages)?;
+        let pipeline = Self::create_pipeline(device.clone(), render_pass, stages, layout)?;
+        let transforms = [0, 1].try_map(|_| {
+            Self::make_uniform_buffer(
+                allocator.clone(),
+                depth_vs::DepthTransform {
+                    overlaySize: overlay_size,
+                    ..Default::default()
+                },
+            )
+        })?;
+        let infos = [0, 1].try_map(|i| {
+            let camera_to_rect = depth_map.rectification.rotations[i]
+                .transpose()
+                .to_homogeneous();
+            Self::make_uniform_buffer(
+                allocator.clone(),
+                depth_fs::DepthInfo {
+                    cameraToRect: *camera_to_rect.as_ref(),
+                    texOffset: (0.5 * i as f32).into(),
+                    rectFov: depth_map.rectification.fov.into(),
+                    ..Default::default()
+                },
+            )
+        })?;
+        // Linear filtering of R32_SFLOAT is not universally supported
+        let depth_sampler = Sampler::new(
             device,
             SamplerCreateInfo {
-                min_filter: Filter::Linear,
-                mag_filter: Filter::Linear,
+                min_filter: Filter::Nearest,
+                mag_filter: Filter::Nearest,
                 ..Default::default()
             },
         )?;
+        let layout = pipeline.layout().set_layouts().first().unwrap();
         let desc_sets = [0, 1].try_map(|i| {
             DescriptorSet::new(
                 descriptor_set_allocator.clone(),
@@ -423,22 +890,25 @@ impl Projection {
                         ImageView::new(source.clone(), ImageViewCreateInfo::from_image(source))?,
                         sampler.clone(),
                     ),
-                    WriteDescriptorSet::buffer(2, tex_offsets[i].clone()),
+                    WriteDescriptorSet::buffer(2, infos[i].clone()),
+                    WriteDescriptorSet::image_view_sampler(
+                        3,
+                        ImageView::new(
+                            depth_map.image.clone(),
+                            ImageViewCreateInfo::from_image(&depth_map.image),
+                        )?,
+                        depth_sampler.clone(),
+                    ),
                 ],
                 None,
             )
             .map_err(ProjectorError::from)
         })?;
-        let source_extent = source.extent();
-        Ok(Self {
-            saved_parameters: init_params,
-            uniforms: Uniforms { transforms },
-            desc_sets,
-            render_pass,
+        Ok(DepthProjection {
             pipeline,
-            extent: [source_extent[0], source_extent[1]],
-            mode_ipd_changed: true,
-            mvps_changed: true,
+            transforms,
+            infos,
+            desc_sets,
         })
     }
     pub fn project(
@@ -460,7 +930,16 @@ impl Projection {
                 ..Default::default()
             },
         )?;
-        let ProjectionParameters { overlay_width, .. } = &self.saved_parameters;
+        let ProjectionParameters {
+            overlay_size: [overlay_width, overlay_height],
+            overlay_radius,
+            mode,
+            ..
+        } = &self.saved_parameters;
+        let (pipeline, desc_sets) = match (&self.depth, mode) {
+            (Some(depth), ProjectionMode::FromDepth) => (&depth.pipeline, &depth.desc_sets),
+            _ => (&self.pipeline, &self.desc_sets),
+        };
         let [w, h] = self.extent;
         let mut cmdbuf = RecordingCommandBuffer::new(
             cmdbuf_allocator,
@@ -472,8 +951,17 @@ impl Projection {
             },
         )?;
         //cmdbuf.copy_image(CopyImageInfo::images(self.source.clone(), output.clone()))?;
+        if let Some(mask) = &self.mask {
+            mask.prepare(&mut cmdbuf)?;
+        }
 
-        // Y is flipped from the vertex Y because texture coordinate is top-down
+        // Y is flipped from the vertex Y because texture coordinate is top-down. A curved
+        // overlay is split into narrow quads, a flat one is a single quad.
+        let segments = if *overlay_radius > 0.0 {
+            CYLINDER_SEGMENTS
+        } else {
+            1
+        };
         let vertex_buffer = Buffer::from_iter::<Vertex, _>(
             allocator,
             BufferCreateInfo {
@@ -486,26 +974,15 @@ impl Projection {
                 allocate_preference: MemoryAllocatePreference::Unknown,
                 ..Default::default()
             },
-            [
-                Vertex {
-                    position: [-1.0, -1.0],
-                    in_tex_coord: [-overlay_width / 2.0, overlay_width / 2.0, 0.0],
-                },
-                Vertex {
-                    position: [-1.0, 1.0],
-                    in_tex_coord: [-overlay_width / 2.0, -overlay_width / 2.0, 0.0],
-                },
-                Vertex {
-                    position: [1.0, -1.0],
-                    in_tex_coord: [overlay_width / 2.0, overlay_width / 2.0, 0.0],
-                },
-                Vertex {
-                    position: [1.0, 1.0],
-                    in_tex_coord: [overlay_width / 2.0, -overlay_width / 2.0, 0.0],
-                },
-            ]
-            .iter()
-            .cloned(),
+            (0..=segments)
+                .flat_map(|i| {
+                    let x = i as f32 / segments as f32 * 2.0 - 1.0;
+                    [-1.0, 1.0].map(|y| Vertex {
+                        position: [x, y],
+                        in_tex_coord: [x * overlay_width / 2.0, -y * overlay_height / 2.0, 0.0],
+                    })
+                })
+                .collect::<Vec<_>>(),
         )
         .unwrap();
         // Left
@@ -530,16 +1007,22 @@ impl Projection {
                 .into_iter()
                 .collect(),
             )?
-            .bind_pipeline_graphics(self.pipeline.clone())?
+            .bind_pipeline_graphics(pipeline.clone())?
             .bind_descriptor_sets(
                 PipelineBindPoint::Graphics,
-                self.pipeline.layout().clone(),
+                pipeline.layout().clone(),
                 0,
-                self.desc_sets[0].clone(),
+                desc_sets[0].clone(),
             )?
             .bind_vertex_buffers(0, vertex_buffer.clone())?
-            .draw(vertex_buffer.len() as u32, 1, 0, 0)?
-            .end_render_pass(SubpassEndInfo::default())?;
+            .draw(vertex_buffer.len() as u32, 1, 0, 0)?;
+        if let Some(mask) = &self.mask {
+            mask.draw(&mut cmdbuf)?;
+        }
+        if let Some(indicator) = &self.indicator {
+            indicator.draw(&mut cmdbuf)?;
+        }
+        cmdbuf.end_render_pass(SubpassEndInfo::default())?;
 
         // Right
         let mut render_pass_begin_info = RenderPassBeginInfo::framebuffer(framebuffer);
@@ -562,16 +1045,22 @@ impl Projection {
                 .into_iter()
                 .collect(),
             )?
-            .bind_pipeline_graphics(self.pipeline.clone())?
+            .bind_pipeline_graphics(pipeline.clone())?
             .bind_descriptor_sets(
                 PipelineBindPoint::Graphics,
-                self.pipeline.layout().clone(),
+                pipeline.layout().clone(),
                 0,
-                self.desc_sets[1].clone(),
+                desc_sets[1].clone(),
             )?
             .bind_vertex_buffers(0, vertex_buffer.clone())?
-            .draw(vertex_buffer.len() as u32, 1, 0, 0)?
-            .end_render_pass(SubpassEndInfo::default())?;
+            .draw(vertex_buffer.len() as u32, 1, 0, 0)?;
+        if let Some(mask) = &self.mask {
+            mask.draw(&mut cmdbuf)?;
+        }
+        if let Some(indicator) = &self.indicator {
+            indicator.draw(&mut cmdbuf)?;
+        }
+        cmdbuf.end_render_pass(SubpassEndInfo::default())?;
         Ok(after.then_execute(queue.clone(), cmdbuf.end()?)?)
     }
 }
@@ -1,3 +1,10 @@
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use itertools::Itertools;
+
 use serde::{Deserialize, Serialize};
 
 use crate::vrapi::{Camera, StereoCamera, TrackedCamera};
@@ -8,62 +15,136 @@ pub struct LighthouseConfig {
     pub tracked_cameras: Vec<TrackedCamera>,
 }
 use anyhow::{anyhow, Context, Result};
-/// Try to find the config file for index
-pub fn find_steam_config() -> Option<StereoCamera> {
-    let xdg = xdg::BaseDirectories::new().ok()?;
-    log::debug!("Base directories: {:?}", xdg);
-    let steam = xdg.find_data_file("steam")?;
-    log::debug!("Steam directory: {:?}", steam);
-    let steam_config = steam.join("config").join("lighthouse");
-    log::debug!("Enumerating steam config dir {:?}", steam_config);
-    let mut files = steam_config.read_dir().ok()?;
-    files.find_map(|dir| {
-        log::debug!("Trying to find config in {:?}", dir);
-        let dir = dir.ok()?;
-        let config = dir.path().join("config.json");
-        log::debug!("Trying to config from {:?}", config);
-        let json = std::fs::read_to_string(config).ok()?;
-        log::debug!("Trying to parse config");
-        let lhconfig: LighthouseConfig = serde_json::from_str(&json).ok()?;
-        log::debug!("Trying to find left camera");
-        let left = lhconfig
+
+impl LighthouseConfig {
+    /// Find the left and right cameras
+    pub fn stereo_camera(&self) -> Result<StereoCamera> {
+        let left = *self
             .tracked_cameras
             .iter()
-            .copied()
-            .find(|p| p.name == Camera::Left)?;
-        log::debug!("Trying to find right camera");
-        let right = lhconfig
+            .find(|p| p.name == Camera::Left)
+            .with_context(|| anyhow!("No left camera found"))?;
+        let right = *self
             .tracked_cameras
             .iter()
-            .copied()
-            .find(|p| p.name == Camera::Right)?;
-        Some(StereoCamera { left, right })
-    })
+            .find(|p| p.name == Camera::Right)
+            .with_context(|| anyhow!("No right camera found"))?;
+        Ok(StereoCamera { left, right })
+    }
+}
+/// A Steam lighthouse config that has camera calibration in it
+#[derive(Clone, Debug)]
+pub struct SteamConfig {
+    /// Serial number of the HMD, from the name of the directory the config is in
+    pub serial: String,
+    pub path: PathBuf,
+    pub camera: StereoCamera,
+    modified: Option<SystemTime>,
+}
+
+/// Directories Steam may be installed in, `steam_root` first if set. Symlinked installs
+/// are only listed once.
+pub fn steam_roots(steam_root: Option<&Path>) -> Vec<PathBuf> {
+    let mut candidates: Vec<PathBuf> = steam_root.into_iter().map(Path::to_owned).collect();
+    if let Ok(xdg) = xdg::BaseDirectories::new() {
+        let data_home = xdg.get_data_home();
+        candidates.extend([data_home.join("steam"), data_home.join("Steam")]);
+    }
+    if let Some(home) = std::env::var_os("HOME").map(PathBuf::from) {
+        candidates.extend([
+            home.join(".steam").join("steam"),
+            home.join(".steam").join("root"),
+            // Flatpak
+            home.join(".var/app/com.valvesoftware.Steam/.local/share/Steam"),
+            home.join(".var/app/com.valvesoftware.Steam/data/Steam"),
+        ]);
+    }
+    let mut roots: Vec<PathBuf> = Vec::new();
+    for candidate in candidates {
+        let Ok(canonical) = candidate.canonicalize() else {
+            log::debug!("Steam directory {} does not exist", candidate.display());
+            continue;
+        };
+        if !roots.contains(&canonical) {
+            roots.push(canonical);
+        }
+    }
+    roots
 }
-pub fn load_steam_config(hmd_serial: &str) -> Result<StereoCamera> {
-    let xdg = xdg::BaseDirectories::new()?;
-    let steam = xdg
-        .find_data_file("steam")
-        .or_else(|| xdg.find_data_file("Steam"))
-        .with_context(|| anyhow!("Cannot find steam directory"))?;
-    let lhconfig = std::fs::read_to_string(
-        steam
-            .join("config")
-            .join("lighthouse")
-            .join(hmd_serial.to_lowercase())
-            .join("config.json"),
-    )?;
-    let lhconfig: LighthouseConfig = serde_json::from_str(&lhconfig)?;
-    let left = *lhconfig
-        .tracked_cameras
-        .iter()
-        .find(|p| p.name == Camera::Left)
-        .with_context(|| anyhow!("No left camera found"))?;
-    let right = *lhconfig
-        .tracked_cameras
-        .iter()
-        .find(|p| p.name == Camera::Right)
-        .with_context(|| anyhow!("No right camera found"))?;
 
-    Ok(StereoCamera { left, right })
+fn load_steam_config(path: &Path) -> Result<StereoCamera> {
+    let json = std::fs::read_to_string(path)?;
+    let lhconfig: LighthouseConfig = serde_json::from_str(&json)?;
+    lhconfig.stereo_camera()
+}
+
+/// Find all Steam lighthouse configs with camera calibration, in all Steam installs. Steam
+/// keeps one for every HMD that has been connected to the machine.
+pub fn find_steam_configs(steam_root: Option<&Path>) -> Vec<SteamConfig> {
+    let mut configs: Vec<SteamConfig> = Vec::new();
+    for root in steam_roots(steam_root) {
+        let lighthouse = root.join("config").join("lighthouse");
+        log::debug!("Enumerating steam config dir {}", lighthouse.display());
+        let Ok(dirs) = lighthouse.read_dir() else {
+            continue;
+        };
+        for dir in dirs.flatten() {
+            let path = dir.path().join("config.json");
+            let serial = dir.file_name().to_string_lossy().to_uppercase();
+            if configs.iter().any(|c| c.serial == serial) {
+                continue;
+            }
+            match load_steam_config(&path) {
+                Ok(camera) => configs.push(SteamConfig {
+                    serial,
+                    modified: path.metadata().and_then(|m| m.modified()).ok(),
+                    path,
+                    camera,
+                }),
+                Err(e) => log::debug!("No camera calibration in {}: {e:#}", path.display()),
+            }
+        }
+    }
+    configs
+}
+
+/// Find the Steam lighthouse config for the HMD with serial number `hmd_serial`. Without a
+/// serial number, the most recently updated config is used, with a warning if there is more
+/// than one.
+pub fn find_steam_config(
+    steam_root: Option<&Path>,
+    hmd_serial: Option<&str>,
+) -> Option<SteamConfig> {
+    let mut configs = find_steam_configs(steam_root);
+    for config in &configs {
+        log::info!(
+            "Found camera calibration for HMD {} in {}",
+            config.serial,
+            config.path.display()
+        );
+    }
+    let serials = configs.iter().map(|c| c.serial.as_str()).join(", ");
+    if let Some(hmd_serial) = hmd_serial {
+        let found = configs
+            .iter()
+            .find(|c| c.serial.eq_ignore_ascii_case(hmd_serial))
+            .cloned();
+        if found.is_none() && !configs.is_empty() {
+            log::warn!(
+                "No camera calibration for HMD {hmd_serial} in the Steam config, only for {}",
+                serials
+            );
+        }
+        return found;
+    }
+    configs.sort_by_key(|c| std::cmp::Reverse(c.modified));
+    if configs.len() > 1 {
+        log::warn!(
+            "Found camera calibration for several HMDs ({}), using the most recent one, {}. \
+             Set `hmd_serial` in the config file to choose.",
+            serials,
+            configs[0].serial
+        );
+    }
+    configs.into_iter().next()
 }
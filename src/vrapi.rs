@@ -2,16 +2,16 @@ use itertools::Itertools;
 use nalgebra::{Affine3, Matrix3, Matrix4, Translation3, UnitQuaternion, Vector3};
 use openvr_sys2::{ETrackedPropertyError, EVRInitError, EVRInputError, EVROverlayError};
 use openxr::{
-    ApplicationInfo, EnvironmentBlendMode, EventDataBuffer, Extent2Df, Extent2Di, EyeVisibility,
-    Offset2Di, OverlaySessionCreateFlagsEXTX, Rect2Di, ReferenceSpaceType, SwapchainSubImage,
-    ViewConfigurationType, ViewStateFlags,
+    ApplicationInfo, CompositionLayerBase, CompositionLayerFlags, EnvironmentBlendMode,
+    EventDataBuffer, Extent2Df, Extent2Di, EyeVisibility, Offset2Di, OverlaySessionCreateFlagsEXTX,
+    Rect2Di, ReferenceSpaceType, SwapchainSubImage, ViewConfigurationType, ViewStateFlags,
 };
 use std::{
     ffi::CString,
     mem::MaybeUninit,
     pin::Pin,
     sync::{Arc, OnceLock},
-    time::Duration,
+    time::{Duration, Instant},
 };
 use vulkano::{
     command_buffer::{
@@ -34,19 +34,119 @@ use vulkano::{
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    config::{DisplayMode, Eye, PositionMode},
+    config::{DisplayMode, Eye, OverlayShape, PositionMode},
+    pose_history::{pose_from_transform, pose_to_transform, PoseHistory},
     utils::DeviceExt,
     APP_KEY, APP_NAME, CAMERA_SIZE,
 };
+const fn default_plus_x() -> [f64; 3] {
+    [1.0, 0.0, 0.0]
+}
+
+const fn default_plus_z() -> [f64; 3] {
+    [0.0, 0.0, 1.0]
+}
+
 #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
 pub struct Extrinsics {
     /// Offset of the camera from Hmd
     pub position: [f64; 3],
+    /// The camera's X axis, in Hmd space
+    #[serde(default = "default_plus_x")]
+    pub plus_x: [f64; 3],
+    /// The camera's Z axis, in Hmd space. The camera looks at -Z.
+    #[serde(default = "default_plus_z")]
+    pub plus_z: [f64; 3],
+}
+
+impl Extrinsics {
+    /// Rotation from camera space to Hmd space. The axes from the calibration data are
+    /// not exactly orthogonal, so they are orthonormalized, keeping the Z axis.
+    pub fn rotation(&self) -> Matrix3<f32> {
+        let z = Vector3::from(self.plus_z.map(|x| x as f32)).normalize();
+        let x = Vector3::from(self.plus_x.map(|x| x as f32));
+        let x = (x - z * z.dot(&x)).normalize();
+        let y = z.cross(&x);
+        Matrix3::from_columns(&[x, y, z])
+    }
+    /// Camera space to Hmd space transform
+    pub fn camera_to_hmd(&self) -> Matrix4<f32> {
+        let mut m = self.rotation().to_homogeneous();
+        m.fixed_view_mut::<3, 1>(0, 3)
+            .copy_from(&-Vector3::from(self.position.map(|x| x as f32)));
+        m
+    }
+}
+
+/// Lens distortion model of a camera, see shaders/distortion.glsl
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum DistortionModel {
+    /// Equidistant fisheye, with 4 coefficients. This is what the Index uses.
+    #[default]
+    #[serde(rename = "DISTORT_FTHETA", alias = "ftheta")]
+    FTheta,
+    /// Kannala-Brandt fisheye, with up to 8 coefficients
+    #[serde(rename = "kannala_brandt")]
+    KannalaBrandt,
+    /// Brown-Conrady radial-tangential: k1, k2, p1, p2, k3, and optionally the rational
+    /// terms k4, k5, k6
+    #[serde(rename = "plumb_bob", alias = "radtan")]
+    PlumbBob,
+    /// Unified omnidirectional camera model: xi, k1, k2, p1, p2
+    #[serde(rename = "unified", alias = "omni")]
+    Unified,
+}
+
+impl DistortionModel {
+    /// Model identifier used by the shaders
+    pub(crate) fn shader_id(self) -> i32 {
+        match self {
+            Self::FTheta | Self::KannalaBrandt => 0,
+            Self::PlumbBob => 1,
+            Self::Unified => 2,
+        }
+    }
+    /// Number of coefficients used by this model
+    pub fn num_coeffs(self) -> usize {
+        match self {
+            Self::FTheta => 4,
+            Self::KannalaBrandt | Self::PlumbBob => 8,
+            Self::Unified => 5,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
 pub struct Distort {
-    pub coeffs: [f64; 4],
+    #[serde(rename = "type", default)]
+    pub model: DistortionModel,
+    /// Distortion coefficients, missing ones are zero. Their meaning depends on `model`.
+    #[serde(deserialize_with = "deserialize_coeffs")]
+    pub coeffs: [f64; 8],
+}
+
+impl Distort {
+    /// Coefficients with the ones not used by the model zeroed out
+    pub fn model_coeffs(&self) -> [f64; 8] {
+        let mut coeffs = self.coeffs;
+        coeffs[self.model.num_coeffs()..].fill(0.0);
+        coeffs
+    }
+}
+
+fn deserialize_coeffs<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<[f64; 8], D::Error> {
+    let v = Vec::<f64>::deserialize(deserializer)?;
+    if v.len() > 8 {
+        return Err(serde::de::Error::invalid_length(
+            v.len(),
+            &"at most 8 coefficients",
+        ));
+    }
+    let mut coeffs = [0.0; 8];
+    coeffs[..v.len()].copy_from_slice(&v);
+    Ok(coeffs)
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
@@ -102,6 +202,7 @@ pub enum Action {
     Button2 = 1,
     Debug = 2,
     Reposition = 3,
+    CycleFilter = 4,
 }
 
 pub(crate) trait VkContext {
@@ -115,7 +216,38 @@ pub(crate) trait VkContext {
 pub(crate) trait Vr: VkContext {
     type Error: Send + Sync + 'static;
     fn load_camera_paramter(&mut self) -> Option<StereoCamera>;
+    /// Serial number of the HMD, used to find its calibration in the Steam config
+    fn hmd_serial(&self) -> Option<String>;
     fn set_fallback_camera_config(&mut self, cfg: StereoCamera);
+    /// Set the depth map used by `ProjectionMode::FromDepth`.
+    ///
+    /// Must be called before switching to a display mode that uses it.
+    fn set_depth_map(&mut self, depth_map: Option<crate::depth::DepthMap>);
+    /// Set the lens correction lookup tables. If set, the render texture is not lens
+    /// corrected, and the projection does the correction.
+    ///
+    /// Must be called before switching to a display mode that uses projection.
+    fn set_lens_remap(&mut self, lens_remap: Option<crate::distortion_correction::LensRemap>);
+    /// Set the shape and opacity of the overlay, `None` for a fully opaque rectangle. The
+    /// projection draws it, so it must be called before switching to a display mode that
+    /// uses projection.
+    fn set_overlay_mask(
+        &mut self,
+        mask: Option<Arc<crate::overlay_mask::Mask>>,
+    ) -> Result<(), Self::Error>;
+    /// Set what happens when the cameras can't see enough of the overlay. Must be called
+    /// before switching to a display mode that uses projection.
+    fn set_out_of_view(&mut self, cfg: crate::config::OutOfViewConfig);
+    /// Add an extra overlay showing the same camera view, see `ExtraOverlayConfig`. `cfg`
+    /// must have gone through `extra_overlay::sanitize`.
+    ///
+    /// Must be called after `set_depth_map`, `set_lens_remap` and `set_reprojection`.
+    fn add_overlay(
+        &mut self,
+        name: &str,
+        cfg: &crate::config::ExtraOverlayConfig,
+        mask: Option<Arc<crate::overlay_mask::Mask>>,
+    ) -> Result<(), Self::Error>;
     /// Submit the render texture to overlay.
     ///
     /// Must have called `render_texture` before calling this function. The render texture must have
@@ -128,12 +260,25 @@ pub(crate) trait Vr: VkContext {
         -> Result<(), Self::Error>;
     /// Refresh the overlay using the latest submitted camera texture.
     fn refresh(&mut self) -> Result<(), Self::Error>;
+    /// Enable or disable reprojection. When enabled, `refresh` re-warps the last camera frame
+    /// to the latest head pose, and the render loop is synchronized with the VR runtime.
+    ///
+    /// Must be called before `is_synchronized` is queried.
+    fn set_reprojection(&mut self, enabled: bool);
     /// Whether our render loop is synchronized with the VR runtime.
     ///
     /// If this is true, `get_render_texture`, `submit_texture` and `refresh` should be synchronized with
     /// the VR runtime.
     fn is_synchronized(&self) -> bool;
     fn set_position_mode(&mut self, mode: PositionMode) -> Result<(), Self::Error>;
+    /// Move the sticky extra overlays to where the user is looking.
+    fn reposition_extra_overlays(&mut self);
+    /// Change the shape of the overlay. The projection is adjusted to match, so the camera
+    /// view still lines up on a curved overlay.
+    fn set_overlay_shape(&mut self, shape: OverlayShape) -> Result<(), Self::Error>;
+    /// Change the width and height of the overlay, in meters. The projection is adjusted to
+    /// match. Can be called at any time.
+    fn set_overlay_size(&mut self, size: [f32; 2]) -> Result<(), Self::Error>;
     /// Change the display mode of the overlay.
     ///
     /// This invalidates previously returned render texture.
@@ -148,7 +293,17 @@ pub(crate) trait Vr: VkContext {
     fn get_render_texture(&mut self) -> Result<Option<Arc<Image>>, Self::Error>;
     fn poll_next_event(&mut self) -> Result<Option<Event>, Self::Error>;
     fn update_action_state(&mut self) -> Result<(), Self::Error>;
+    /// Record the current HMD pose, so the pose at the time a camera frame was captured can
+    /// be looked up when it is submitted. Should be called every iteration of the main loop.
+    fn record_pose(&mut self) -> Result<(), Self::Error>;
+    /// Current HMD pose, `None` if it's not available, e.g. the session is not running.
+    fn current_hmd_transform(&mut self) -> Result<Option<Matrix4<f32>>, Self::Error>;
     fn get_action_state(&self, action: Action) -> Result<bool, Self::Error>;
+    /// Position of the input used to adjust the distance of `ProjectionMode::FixedDepth`, or
+    /// the size of the overlay, the vertical axis of the right thumbstick, between -1 and 1.
+    fn get_adjust_state(&self) -> Result<f32, Self::Error>;
+    /// Set the distance of the plane used by `ProjectionMode::FixedDepth`.
+    fn set_fixed_depth(&mut self, distance: f32);
 }
 
 struct VrMapError<T, F>(T, F);
@@ -179,9 +334,35 @@ impl<T: Vr, E: Send + Sync + 'static, F: Fn(<T as Vr>::Error) -> E> Vr for VrMap
     fn load_camera_paramter(&mut self) -> Option<StereoCamera> {
         self.0.load_camera_paramter()
     }
+    fn hmd_serial(&self) -> Option<String> {
+        self.0.hmd_serial()
+    }
     fn set_fallback_camera_config(&mut self, cfg: StereoCamera) {
         self.0.set_fallback_camera_config(cfg)
     }
+    fn set_depth_map(&mut self, depth_map: Option<crate::depth::DepthMap>) {
+        self.0.set_depth_map(depth_map)
+    }
+    fn set_lens_remap(&mut self, lens_remap: Option<crate::distortion_correction::LensRemap>) {
+        self.0.set_lens_remap(lens_remap)
+    }
+    fn set_overlay_mask(
+        &mut self,
+        mask: Option<Arc<crate::overlay_mask::Mask>>,
+    ) -> Result<(), Self::Error> {
+        self.0.set_overlay_mask(mask).map_err(&self.1)
+    }
+    fn set_out_of_view(&mut self, cfg: crate::config::OutOfViewConfig) {
+        self.0.set_out_of_view(cfg)
+    }
+    fn add_overlay(
+        &mut self,
+        name: &str,
+        cfg: &crate::config::ExtraOverlayConfig,
+        mask: Option<Arc<crate::overlay_mask::Mask>>,
+    ) -> Result<(), Self::Error> {
+        self.0.add_overlay(name, cfg, mask).map_err(&self.1)
+    }
     fn submit_texture(
         &mut self,
         elapsed: Duration,
@@ -195,6 +376,9 @@ impl<T: Vr, E: Send + Sync + 'static, F: Fn(<T as Vr>::Error) -> E> Vr for VrMap
     fn is_synchronized(&self) -> bool {
         self.0.is_synchronized()
     }
+    fn set_reprojection(&mut self, enabled: bool) {
+        self.0.set_reprojection(enabled)
+    }
     fn show_overlay(&mut self) -> Result<(), Self::Error> {
         self.0.show_overlay().map_err(&self.1)
     }
@@ -204,6 +388,15 @@ impl<T: Vr, E: Send + Sync + 'static, F: Fn(<T as Vr>::Error) -> E> Vr for VrMap
     fn set_position_mode(&mut self, mode: PositionMode) -> Result<(), Self::Error> {
         self.0.set_position_mode(mode).map_err(&self.1)
     }
+    fn reposition_extra_overlays(&mut self) {
+        self.0.reposition_extra_overlays()
+    }
+    fn set_overlay_shape(&mut self, shape: OverlayShape) -> Result<(), Self::Error> {
+        self.0.set_overlay_shape(shape).map_err(&self.1)
+    }
+    fn set_overlay_size(&mut self, size: [f32; 2]) -> Result<(), Self::Error> {
+        self.0.set_overlay_size(size).map_err(&self.1)
+    }
     fn get_render_texture(&mut self) -> Result<Option<Arc<Image>>, Self::Error> {
         self.0.get_render_texture().map_err(&self.1)
     }
@@ -216,9 +409,21 @@ impl<T: Vr, E: Send + Sync + 'static, F: Fn(<T as Vr>::Error) -> E> Vr for VrMap
     fn get_action_state(&self, action: Action) -> Result<bool, Self::Error> {
         self.0.get_action_state(action).map_err(&self.1)
     }
+    fn get_adjust_state(&self) -> Result<f32, Self::Error> {
+        self.0.get_adjust_state().map_err(&self.1)
+    }
+    fn set_fixed_depth(&mut self, distance: f32) {
+        self.0.set_fixed_depth(distance)
+    }
     fn update_action_state(&mut self) -> Result<(), Self::Error> {
         self.0.update_action_state().map_err(&self.1)
     }
+    fn record_pose(&mut self) -> Result<(), Self::Error> {
+        self.0.record_pose().map_err(&self.1)
+    }
+    fn current_hmd_transform(&mut self) -> Result<Option<Matrix4<f32>>, Self::Error> {
+        self.0.current_hmd_transform().map_err(&self.1)
+    }
 }
 pub(crate) trait VrExt: Sized {
     fn boxed(self) -> Box<dyn Vr<Error = anyhow::Error>>;
@@ -240,18 +445,44 @@ struct TextureState {
     _instance: Arc<Instance>,
 }
 
+/// The OpenVR part of an extra overlay, with its own overlay handle
+struct OpenVrExtraOverlay {
+    handle: openvr_sys2::VROverlayHandle_t,
+    double_buffer: [Arc<Image>; 2],
+    texture_in_use: u64,
+    /// Rendered by the GPU work of the current frame, submitted once that is done
+    rendered: Option<Arc<Image>>,
+}
+
 pub(crate) struct OpenVr {
     sys: crate::openvr::VRSystem,
     handle: openvr_sys2::VROverlayHandle_t,
-    buttons: [openvr_sys2::VRActionHandle_t; 4],
+    overlay_visible: bool,
+    buttons: [openvr_sys2::VRActionHandle_t; 5],
+    adjust: openvr_sys2::VRActionHandle_t,
     action_set: openvr_sys2::VRActionSetHandle_t,
     texture: Option<TextureState>,
     camera_config: Option<StereoCamera>,
     position_mode: PositionMode,
     reposition: bool,
     display_mode: DisplayMode,
+    overlay_shape: OverlayShape,
+    /// Width and height of the overlay, in meters
+    overlay_size: [f32; 2],
     overlay_transform: Matrix4<f32>,
     projector: Option<crate::projection::Projection>,
+    out_of_view: crate::out_of_view::OutOfView,
+    depth_map: Option<crate::depth::DepthMap>,
+    lens_remap: Option<crate::distortion_correction::LensRemap>,
+    overlay_mask: Option<Arc<crate::overlay_mask::Mask>>,
+    /// Draws `overlay_mask` in display modes without projection
+    mask_pass: Option<crate::overlay_mask::OverlayMask>,
+    extra_overlays: crate::extra_overlay::ExtraOverlays<OpenVrExtraOverlay>,
+    reprojection: bool,
+    fixed_depth: f32,
+    /// HMD pose when the last camera frame was captured, and the fov used for it
+    saved_capture: Option<(Matrix4<f32>, [[f32; 2]; 2])>,
+    pose_history: PoseHistory<1>,
     device: Arc<Device>,
     queue: Arc<Queue>,
     instance: Arc<Instance>,
@@ -347,7 +578,7 @@ impl OpenVr {
                 .SetActionManifestPath(action_manifest.as_ptr())
         }
         .into_result()?;
-        let mut button = [const { MaybeUninit::uninit() }; 4];
+        let mut button = [const { MaybeUninit::uninit() }; 5];
         for i in 0..2 {
             let name = CString::new(format!("/actions/main/in/button{}", i + 1)).unwrap();
             unsafe {
@@ -371,7 +602,23 @@ impl OpenVr {
                 .GetActionHandle(name.as_ptr(), button[3].as_mut_ptr())
                 .into_result()?;
         };
+        unsafe {
+            let name = CString::new("/actions/main/in/cycle_filter").unwrap();
+            input
+                .as_mut()
+                .GetActionHandle(name.as_ptr(), button[4].as_mut_ptr())
+                .into_result()?;
+        };
         let button = unsafe { MaybeUninit::array_assume_init(button) };
+        let adjust = unsafe {
+            let mut adjust = MaybeUninit::uninit();
+            let name = CString::new("/actions/main/in/adjust").unwrap();
+            input
+                .as_mut()
+                .GetActionHandle(name.as_ptr(), adjust.as_mut_ptr())
+                .into_result()?;
+            adjust.assume_init()
+        };
 
         log::debug!("buttons: {:?}", button);
         let action_set = unsafe {
@@ -397,13 +644,27 @@ impl OpenVr {
         Ok(Self {
             sys,
             handle: vroverlay,
+            overlay_visible: false,
             action_set,
             buttons: button,
+            adjust,
             texture: None,
             display_mode: DisplayMode::default(),
             position_mode: PositionMode::default(),
             reposition: false,
+            overlay_shape: OverlayShape::default(),
+            overlay_size: [crate::config::default_overlay_size(); 2],
             projector: None,
+            out_of_view: Default::default(),
+            depth_map: None,
+            lens_remap: None,
+            overlay_mask: None,
+            mask_pass: None,
+            extra_overlays: Default::default(),
+            reprojection: false,
+            fixed_depth: crate::config::default_fixed_depth_distance(),
+            saved_capture: None,
+            pose_history: PoseHistory::default(),
             overlay_transform: Matrix4::identity(),
             camera_config: None,
             instance,
@@ -447,7 +708,11 @@ impl OpenVr {
             .map(|cstr| cstr.to_str().unwrap())
             .collect()
     }
-    fn set_overlay_texture_bounds_internal(&mut self, bounds: Bounds) -> Result<(), OpenVrError> {
+    fn set_overlay_texture_bounds_internal(
+        sys: &crate::openvr::VRSystem,
+        handle: openvr_sys2::VROverlayHandle_t,
+        bounds: Bounds,
+    ) -> Result<(), OpenVrError> {
         let bounds = openvr_sys2::VRTextureBounds_t {
             uMin: bounds.umin,
             vMin: bounds.vmin,
@@ -455,27 +720,297 @@ impl OpenVr {
             vMax: bounds.vmax,
         };
         unsafe {
-            self.sys
-                .overlay()
+            sys.overlay()
+                .pin_mut()
+                .SetOverlayTextureBounds(handle, &bounds)
+                .into_result()
+                .map_err(Into::into)
+        }
+    }
+    /// Show the part of the texture `mode` renders to on the overlay `handle`
+    fn set_overlay_display_mode(
+        sys: &crate::openvr::VRSystem,
+        handle: openvr_sys2::VROverlayHandle_t,
+        mode: DisplayMode,
+    ) -> Result<(), OpenVrError> {
+        sys.overlay()
+            .pin_mut()
+            .SetOverlayFlag(
+                handle,
+                openvr_sys2::VROverlayFlags::VROverlayFlags_SideBySide_Parallel,
+                mode.is_stereo(),
+            )
+            .into_result()?;
+        let bounds = match mode {
+            DisplayMode::Flat { eye: Eye::Left } => crate::vrapi::Bounds {
+                umin: 0.0,
+                umax: 0.5,
+                vmin: 0.0,
+                vmax: 1.0,
+            },
+            DisplayMode::Flat { eye: Eye::Right } => crate::vrapi::Bounds {
+                umin: 0.5,
+                umax: 1.0,
+                vmin: 0.0,
+                vmax: 1.0,
+            },
+            DisplayMode::Stereo { .. }
+            | DisplayMode::Proximity { .. }
+            | DisplayMode::Direct
+            | DisplayMode::Depth => crate::vrapi::Bounds {
+                umin: 0.0,
+                umax: 1.0,
+                vmin: 0.0,
+                vmax: 1.0,
+            },
+        };
+        Self::set_overlay_texture_bounds_internal(sys, handle, bounds)
+    }
+    /// Set the width, aspect ratio and curvature of the overlay `handle`
+    fn set_overlay_geometry(
+        sys: &crate::openvr::VRSystem,
+        handle: openvr_sys2::VROverlayHandle_t,
+        shape: OverlayShape,
+        [width, height]: [f32; 2],
+    ) -> Result<(), OpenVrError> {
+        sys.overlay()
+            .pin_mut()
+            .SetOverlayWidthInMeters(handle, width)
+            .into_result()?;
+        // Each eye gets a square half of the texture, its texels are stretched to the
+        // aspect ratio of the overlay
+        sys.overlay()
+            .pin_mut()
+            .SetOverlayTexelAspect(handle, width / height)
+            .into_result()?;
+        let radius = shape.radius(width);
+        // OpenVR wants the fraction of a full circle the overlay covers
+        let curvature = if radius > 0.0 {
+            width / (std::f32::consts::TAU * radius)
+        } else {
+            0.0
+        };
+        sys.overlay()
+            .pin_mut()
+            .SetOverlayCurvature(handle, curvature)
+            .into_result()?;
+        Ok(())
+    }
+    /// HMD pose at the time the next frame is displayed
+    fn predicted_hmd_transform(&self) -> Matrix4<f32> {
+        self.sys
+            .hmd_transform(self.sys.seconds_to_photons())
+            .cast::<f32>()
+    }
+    /// Update the overlay transform for the given HMD pose, if the position mode needs it.
+    fn place_overlay(&mut self, hmd_transform: Matrix4<f32>) -> Result<(), OpenVrError> {
+        if self.reposition {
+            self.reposition = false;
+            self.position_mode.reposition(hmd_transform);
+
+            let transform: Matrix4<f32> = self.position_mode.transform(hmd_transform).into();
+            self.set_overlay_transformation(transform)?;
+        } else if matches!(self.position_mode, PositionMode::Hmd { .. }) {
+            let transform: Matrix4<f32> = self.position_mode.transform(hmd_transform).into();
+            self.set_overlay_transformation(transform)?;
+        }
+        Ok(())
+    }
+    /// Project the camera image in the render texture onto the overlay, returns the
+    /// projected image, `None` if the overlay is hidden because the cameras can't see it.
+    ///
+    /// # Arguments
+    ///
+    /// - `display_hmd_transform`: HMD pose when the overlay is displayed
+    /// - `capture_hmd_transform`: HMD pose when the camera image was captured
+    fn project(
+        &mut self,
+        display_hmd_transform: &Matrix4<f32>,
+        capture_hmd_transform: &Matrix4<f32>,
+        fov: &[[f32; 2]; 2],
+    ) -> Result<Option<Arc<Image>>, OpenVrError> {
+        let new_texture = self.double_buffer[(self.texture_in_use ^ 1) as usize].clone();
+        let eye_to_head = self.eye_to_head();
+        let view_transforms = eye_to_head.map(|m| display_hmd_transform * m);
+        let ipd = self.ipd()?;
+        let projector = self.projector.as_mut().unwrap();
+        let placement = self.out_of_view.place(
+            projector,
+            self.overlay_transform,
+            fov,
+            &view_transforms,
+            display_hmd_transform,
+            capture_hmd_transform,
+        )?;
+        Self::set_overlay_placement(&self.sys, self.handle, placement)?;
+        if placement.is_none() {
+            return Ok(None);
+        }
+        projector.set_ipd(ipd);
+        let future = projector.project(
+            self.allocator.clone(),
+            self.cmdbuf_allocator.clone(),
+            vulkano::sync::future::now(self.device.clone()),
+            &self.queue,
+            new_texture.clone(),
+        )?;
+        future.flush()?;
+        future.then_signal_fence().wait(None)?;
+        Ok(Some(new_texture))
+    }
+    fn set_overlay_texture(&mut self, output: Arc<Image>) -> Result<(), OpenVrError> {
+        let texture = TextureState {
+            _image: output.clone() as Arc<_>,
+            _device: self.device.clone(),
+            _queue: self.queue.clone(),
+            _instance: self.instance.clone(),
+        };
+        self.texture.replace(texture);
+        let ret = Self::set_overlay_texture_internal(
+            &self.sys,
+            &self.device,
+            &self.queue,
+            &self.instance,
+            self.handle,
+            &output,
+        );
+        self.texture_in_use ^= 1;
+        ret
+    }
+    fn set_overlay_texture_internal(
+        sys: &crate::openvr::VRSystem,
+        device: &Arc<Device>,
+        queue: &Arc<Queue>,
+        instance: &Arc<Instance>,
+        handle: openvr_sys2::VROverlayHandle_t,
+        output: &Arc<Image>,
+    ) -> Result<(), OpenVrError> {
+        let vroverlay = sys.overlay();
+        // Once we set a texture, the VRSystem starts to depend on Vulkan
+        // instance being alive.
+        sys.hold_vulkan_device(device.clone());
+        let mut vrimage = openvr_sys2::VRVulkanTextureData_t {
+            m_nWidth: crate::CAMERA_SIZE * 2,
+            m_nHeight: crate::CAMERA_SIZE,
+            m_nFormat: output.format() as u32,
+            m_nSampleCount: output.samples() as u32,
+            m_nImage: output.handle().as_raw(),
+            m_pPhysicalDevice: device.physical_device().handle().as_raw() as *mut _,
+            m_pDevice: device.handle().as_raw() as *mut _,
+            m_pQueue: queue.handle().as_raw() as *mut _,
+            m_pInstance: instance.handle().as_raw() as *mut _,
+            m_nQueueFamilyIndex: queue.queue_family_index(),
+        };
+        let vrtexture = openvr_sys2::Texture_t {
+            handle: &mut vrimage as *mut _ as *mut std::ffi::c_void,
+            eType: openvr_sys2::ETextureType::TextureType_Vulkan,
+            eColorSpace: openvr_sys2::EColorSpace::ColorSpace_Auto,
+        };
+        unsafe {
+            vroverlay
                 .pin_mut()
-                .SetOverlayTextureBounds(self.handle, &bounds)
+                .SetOverlayTexture(handle, &vrtexture)
                 .into_result()
                 .map_err(Into::into)
         }
     }
     fn set_overlay_transformation(&mut self, transform: Matrix4<f32>) -> Result<(), OpenVrError> {
         self.overlay_transform = transform;
-        let vroverlay = self.sys.overlay();
+        Self::set_overlay_transformation_internal(&self.sys, self.handle, &transform)
+    }
+    fn set_overlay_transformation_internal(
+        sys: &crate::openvr::VRSystem,
+        handle: openvr_sys2::VROverlayHandle_t,
+        transform: &Matrix4<f32>,
+    ) -> Result<(), OpenVrError> {
+        let vroverlay = sys.overlay();
         unsafe {
             vroverlay.pin_mut().SetOverlayTransformAbsolute(
-                self.handle,
+                handle,
                 openvr_sys2::ETrackingUniverseOrigin::TrackingUniverseStanding,
-                &(&transform).into(),
+                &transform.into(),
             )
         }
         .into_result()
         .map_err(Into::into)
     }
+    /// Show the overlay `handle` at `transform`, or hide it if `None`, see `OutOfView::place`.
+    /// It is hidden by making it transparent, so showing and hiding all overlays still works.
+    fn set_overlay_placement(
+        sys: &crate::openvr::VRSystem,
+        handle: openvr_sys2::VROverlayHandle_t,
+        placement: Option<Matrix4<f32>>,
+    ) -> Result<(), OpenVrError> {
+        let alpha = if placement.is_some() { 1.0 } else { 0.0 };
+        sys.overlay()
+            .pin_mut()
+            .SetOverlayAlpha(handle, alpha)
+            .into_result()?;
+        if let Some(transform) = placement {
+            Self::set_overlay_transformation_internal(sys, handle, &transform)?;
+        }
+        Ok(())
+    }
+    /// Place the extra overlays for the HMD pose at display time, and render the last
+    /// camera frame onto them after `after`, see `ExtraOverlays::render`. Once the returned
+    /// future is done, `submit_extra_overlays` submits them.
+    fn render_extra_overlays(
+        &mut self,
+        after: Box<dyn GpuFuture>,
+        display_hmd_transform: &Matrix4<f32>,
+        capture_hmd_transform: &Matrix4<f32>,
+        fov: &[[f32; 2]; 2],
+        new_frame: bool,
+    ) -> Result<Box<dyn GpuFuture>, OpenVrError> {
+        let view_transforms = self.eye_to_head().map(|m| display_hmd_transform * m);
+        let ipd = self.ipd()?;
+        let sys = &self.sys;
+        self.extra_overlays.render(
+            self.allocator.clone(),
+            self.cmdbuf_allocator.clone(),
+            after,
+            &self.queue,
+            fov,
+            &view_transforms,
+            display_hmd_transform,
+            capture_hmd_transform,
+            ipd,
+            new_frame,
+            |extra, _, placement| Self::set_overlay_placement(sys, extra.handle, placement),
+            |extra| {
+                let output = extra.double_buffer[(extra.texture_in_use ^ 1) as usize].clone();
+                extra.rendered = Some(output.clone());
+                Ok(output)
+            },
+        )
+    }
+    /// Submit the extra overlays rendered by `render_extra_overlays`.
+    fn submit_extra_overlays(&mut self) -> Result<(), OpenVrError> {
+        for (overlay, extra) in self.extra_overlays.iter_mut() {
+            let Some(output) = extra.rendered.take() else {
+                continue;
+            };
+            if !overlay.is_projected() {
+                transition_layout(
+                    ImageLayout::ColorAttachmentOptimal,
+                    &output,
+                    &self.queue,
+                    self.cmdbuf_allocator.clone(),
+                )?
+                .wait(None)?;
+            }
+            Self::set_overlay_texture_internal(
+                &self.sys,
+                &self.device,
+                &self.queue,
+                &self.instance,
+                extra.handle,
+                &output,
+            )?;
+            extra.texture_in_use ^= 1;
+        }
+        Ok(())
+    }
     fn eye_to_head(&self) -> [Matrix4<f32>; 2] {
         let left_eye: Matrix4<_> = self
             .sys
@@ -519,17 +1054,27 @@ pub(crate) enum OpenVrError {
     NoGraphicsQueue,
     #[error("projector error: {0}")]
     Projector(#[from] crate::projection::ProjectorError),
+    #[error("overlay mask error: {0}")]
+    Mask(#[from] crate::overlay_mask::MaskError),
+    #[error("extra overlay error: {0}")]
+    ExtraOverlay(#[from] crate::extra_overlay::ExtraOverlayError),
 }
 
 impl Drop for OpenVr {
     fn drop(&mut self) {
         log::info!("Dropping overlay handle");
         let vroverlay = self.sys.overlay();
-        if let Err(e) = unsafe { vroverlay.destroy_overlay_raw(self.handle) } {
-            eprintln!("{}", e);
+        let extra_handles = self.extra_overlays.iter().map(|(_, extra)| extra.handle);
+        for handle in std::iter::once(self.handle).chain(extra_handles) {
+            if let Err(e) = unsafe { vroverlay.destroy_overlay_raw(handle) } {
+                eprintln!("{}", e);
+            }
         }
     }
 }
+/// How long `OpenVr::refresh` waits for the compositor, in milliseconds
+const REFRESH_TIMEOUT_MS: u32 = 100;
+
 static VULKAN_LIBRARY: OnceLock<Arc<vulkano::VulkanLibrary>> = OnceLock::new();
 
 fn get_vulkan_library() -> &'static Arc<vulkano::VulkanLibrary> {
@@ -618,41 +1163,94 @@ fn transition_layout(
 impl Vr for OpenVr {
     type Error = OpenVrError;
     fn load_camera_paramter(&mut self) -> Option<StereoCamera> {
-        if let Some(cfg) = self.camera_config.as_ref() {
-            Some(*cfg)
-        } else {
-            // Load steam calibration data
-            let hmd_id = self.sys.find_hmd()?;
-            let mut serial_number = [0u8; 32];
-            let mut error = ETrackedPropertyError::TrackedProp_Success;
-            let serial_number_len = unsafe {
-                self.sys.pin_mut().GetStringTrackedDeviceProperty(
-                    hmd_id,
-                    openvr_sys2::ETrackedDeviceProperty::Prop_SerialNumber_String,
-                    serial_number.as_mut_ptr() as *mut _,
-                    32,
-                    &mut error,
-                )
-            };
-            if error != ETrackedPropertyError::TrackedProp_Success {
-                return None;
-            }
-            let lhcfg = crate::steam::load_steam_config(
-                std::str::from_utf8(&serial_number[..serial_number_len as usize - 1]).ok()?,
-            )
-            .ok()?;
-            log::info!(
-                "{}",
-                serde_json::to_string(&lhcfg).unwrap_or("invalid json".to_owned())
-            );
-            self.camera_config = Some(lhcfg);
-            Some(lhcfg)
-        }
+        self.camera_config
+    }
+    fn hmd_serial(&self) -> Option<String> {
+        self.sys.hmd_serial()
     }
     fn set_fallback_camera_config(&mut self, cfg: StereoCamera) {
-        log::warn!("Using fallback camera config");
         self.camera_config = Some(cfg);
     }
+    fn set_depth_map(&mut self, depth_map: Option<crate::depth::DepthMap>) {
+        self.depth_map = depth_map;
+    }
+    fn set_lens_remap(&mut self, lens_remap: Option<crate::distortion_correction::LensRemap>) {
+        self.lens_remap = lens_remap;
+    }
+    fn set_overlay_mask(
+        &mut self,
+        mask: Option<Arc<crate::overlay_mask::Mask>>,
+    ) -> Result<(), Self::Error> {
+        self.mask_pass = mask
+            .as_ref()
+            .map(|mask| {
+                crate::overlay_mask::OverlayMask::standalone(
+                    self.device.clone(),
+                    self.descriptor_set_allocator.clone(),
+                    mask,
+                )
+            })
+            .transpose()?;
+        self.overlay_mask = mask;
+        Ok(())
+    }
+    fn set_out_of_view(&mut self, cfg: crate::config::OutOfViewConfig) {
+        self.out_of_view = crate::out_of_view::OutOfView::new(cfg);
+    }
+    fn add_overlay(
+        &mut self,
+        name: &str,
+        cfg: &crate::config::ExtraOverlayConfig,
+        mask: Option<Arc<crate::overlay_mask::Mask>>,
+    ) -> Result<(), Self::Error> {
+        let camera_calib = self.load_camera_paramter();
+        let overlay = self.extra_overlays.create(
+            name,
+            cfg,
+            self.device.clone(),
+            self.allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+            &camera_calib,
+            self.depth_map.as_ref(),
+            self.lens_remap.as_ref(),
+            mask.as_ref(),
+            self.fixed_depth,
+            ImageLayout::TransferSrcOptimal,
+        )?;
+        let key = format!("{}.{name}\0", APP_KEY.trim_end_matches('\0'));
+        let title = format!("{} ({name})\0", APP_NAME.trim_end_matches('\0'));
+        let double_buffer =
+            [0, 1].try_map(|_| crate::create_submittable_image(self.device.clone()))?;
+        let handle = self.sys.overlay().create_overlay(&key, &title)?;
+        // Pushed before setting it up, so the handle is destroyed even if that fails
+        self.extra_overlays.push(
+            overlay,
+            OpenVrExtraOverlay {
+                handle,
+                double_buffer,
+                texture_in_use: 1,
+                rendered: None,
+            },
+        );
+        self.sys
+            .overlay()
+            .pin_mut()
+            .SetOverlayTextureColorSpace(handle, openvr_sys2::EColorSpace::ColorSpace_Linear)
+            .into_result()?;
+        Self::set_overlay_display_mode(&self.sys, handle, cfg.display_mode)?;
+        Self::set_overlay_geometry(&self.sys, handle, cfg.shape, cfg.size())?;
+        if self.overlay_visible {
+            self.sys
+                .overlay()
+                .pin_mut()
+                .ShowOverlay(handle)
+                .into_result()?;
+        }
+        Ok(())
+    }
+    fn set_reprojection(&mut self, enabled: bool) {
+        self.reprojection = enabled;
+    }
     fn get_render_texture(&mut self) -> Result<Option<Arc<Image>>, Self::Error> {
         // log::debug!("get_render_texture");
         if self.display_mode.projection_mode().is_none() {
@@ -668,100 +1266,109 @@ impl Vr for OpenVr {
         elapsed: Duration,
         fov: &[[f32; 2]; 2],
     ) -> Result<(), Self::Error> {
-        let hmd_transform = self.sys.hmd_transform(-elapsed.as_secs_f32()).cast::<f32>();
-        if self.reposition {
-            self.reposition = false;
-            self.position_mode.reposition(hmd_transform);
-
-            let transform: Matrix4<f32> = self.position_mode.transform(hmd_transform).into();
-            self.set_overlay_transformation(transform)?;
-        } else if matches!(self.position_mode, PositionMode::Hmd { .. }) {
-            let transform: Matrix4<f32> = self.position_mode.transform(hmd_transform).into();
-            self.set_overlay_transformation(transform)?;
-        }
+        self.record_pose()?;
+        let [capture_pose] = self
+            .pose_history
+            .at(Instant::now() - elapsed)
+            .expect("pose history is empty");
+        let capture_hmd_transform = pose_to_transform(&capture_pose);
+        self.saved_capture = Some((capture_hmd_transform, *fov));
+        // With reprojection, the overlay is placed using the head pose at display time
+        // instead, and the camera image is warped accordingly.
+        let display_hmd_transform = if self.reprojection {
+            self.predicted_hmd_transform()
+        } else {
+            capture_hmd_transform
+        };
+        self.place_overlay(display_hmd_transform)?;
+        let future = self.extra_overlays.copy_frame(
+            self.cmdbuf_allocator.clone(),
+            vulkano::sync::future::now(self.device.clone()).boxed(),
+            &self.queue,
+            self.render_texture.clone().unwrap(),
+        )?;
+        let mut future = self.render_extra_overlays(
+            future,
+            &display_hmd_transform,
+            &capture_hmd_transform,
+            fov,
+            true,
+        )?;
         let output = if self.display_mode.projection_mode().is_some() {
-            let new_texture = self.double_buffer[(self.texture_in_use ^ 1) as usize].clone();
-            let eye_to_head = self.eye_to_head();
-            let view_transforms = eye_to_head.map(|m| hmd_transform * m);
-            let ipd = self.ipd()?;
-            let projector = self.projector.as_mut().unwrap();
-            projector.update_mvps(
-                &self.overlay_transform,
-                fov,
-                &view_transforms,
-                &hmd_transform,
-            )?;
-            projector.set_ipd(ipd);
-            let future = projector.project(
-                self.allocator.clone(),
-                self.cmdbuf_allocator.clone(),
-                vulkano::sync::future::now(self.device.clone()),
-                &self.queue,
-                new_texture.clone(),
-            )?;
-            future.flush()?;
-            future.then_signal_fence().wait(None)?;
-            new_texture
+            self.project(&display_hmd_transform, &capture_hmd_transform, fov)?
         } else {
             let output = self.render_texture.take().unwrap();
-            transition_layout(
-                ImageLayout::ColorAttachmentOptimal,
-                &output,
-                &self.queue,
-                self.cmdbuf_allocator.clone(),
-            )?
-            .wait(None)?;
-            output
-        };
-        let texture = TextureState {
-            _image: output.clone() as Arc<_>,
-            _device: self.device.clone(),
-            _queue: self.queue.clone(),
-            _instance: self.instance.clone(),
-        };
-        self.texture.replace(texture);
-        let vroverlay = self.sys.overlay();
-        // Once we set a texture, the VRSystem starts to depend on Vulkan
-        // instance being alive.
-        self.sys.hold_vulkan_device(self.device.clone());
-        let mut vrimage = openvr_sys2::VRVulkanTextureData_t {
-            m_nWidth: crate::CAMERA_SIZE * 2,
-            m_nHeight: crate::CAMERA_SIZE,
-            m_nFormat: output.format() as u32,
-            m_nSampleCount: output.samples() as u32,
-            m_nImage: output.handle().as_raw(),
-            m_pPhysicalDevice: self.device.physical_device().handle().as_raw() as *mut _,
-            m_pDevice: self.device.handle().as_raw() as *mut _,
-            m_pQueue: self.queue.handle().as_raw() as *mut _,
-            m_pInstance: self.instance.handle().as_raw() as *mut _,
-            m_nQueueFamilyIndex: self.queue.queue_family_index(),
-        };
-        let vrtexture = openvr_sys2::Texture_t {
-            handle: &mut vrimage as *mut _ as *mut std::ffi::c_void,
-            eType: openvr_sys2::ETextureType::TextureType_Vulkan,
-            eColorSpace: openvr_sys2::EColorSpace::ColorSpace_Auto,
-        };
-        let ret = unsafe {
-            vroverlay
-                .pin_mut()
-                .SetOverlayTexture(self.handle, &vrtexture)
-                .into_result()
-                .map_err(Into::into)
+            if let Some(mask_pass) = &self.mask_pass {
+                future = mask_pass
+                    .apply(
+                        self.cmdbuf_allocator.clone(),
+                        future,
+                        &self.queue,
+                        output.clone(),
+                    )?
+                    .boxed();
+            }
+            Some(output)
         };
-        self.texture_in_use ^= 1;
-        ret
+        future.flush()?;
+        future.then_signal_fence().wait(None)?;
+        if let Some(output) = output {
+            if self.display_mode.projection_mode().is_none() {
+                transition_layout(
+                    ImageLayout::ColorAttachmentOptimal,
+                    &output,
+                    &self.queue,
+                    self.cmdbuf_allocator.clone(),
+                )?
+                .wait(None)?;
+            }
+            self.set_overlay_texture(output)?;
+        }
+        self.submit_extra_overlays()
     }
     fn is_synchronized(&self) -> bool {
-        false
+        self.reprojection
     }
     fn refresh(&mut self) -> Result<(), Self::Error> {
-        Ok(())
+        if !self.reprojection {
+            return Ok(());
+        }
+        self.sys
+            .overlay()
+            .pin_mut()
+            .WaitFrameSync(REFRESH_TIMEOUT_MS)
+            .into_result()?;
+        // Re-warp the last camera frame to the head pose at display time. Without
+        // projection there is nothing to warp, the overlay stays where it is.
+        let Some((capture_hmd_transform, fov)) = self.saved_capture else {
+            return Ok(());
+        };
+        let display_hmd_transform = self.predicted_hmd_transform();
+        if self.display_mode.projection_mode().is_some() {
+            self.place_overlay(display_hmd_transform)?;
+            if let Some(output) =
+                self.project(&display_hmd_transform, &capture_hmd_transform, &fov)?
+            {
+                self.set_overlay_texture(output)?;
+            }
+        }
+        let future = self.render_extra_overlays(
+            vulkano::sync::future::now(self.device.clone()).boxed(),
+            &display_hmd_transform,
+            &capture_hmd_transform,
+            &fov,
+            false,
+        )?;
+        future.flush()?;
+        future.then_signal_fence().wait(None)?;
+        self.submit_extra_overlays()
     }
     fn set_display_mode(&mut self, mode: DisplayMode) -> Result<(), Self::Error> {
         if self.display_mode == mode {
             return Ok(());
         }
         self.display_mode = mode;
+        self.saved_capture = None;
         if let Some(projection_mode) = self.display_mode.projection_mode() {
             let camera_calib = self.load_camera_paramter();
             if self.projector.is_none() {
@@ -771,63 +1378,56 @@ impl Vr for OpenVr {
                     self.allocator.clone(),
                     self.descriptor_set_allocator.clone(),
                     self.render_texture.as_ref().unwrap(),
-                    1.0,
+                    self.overlay_size,
                     &camera_calib,
+                    self.depth_map.as_ref(),
+                    self.lens_remap.as_ref(),
+                    self.overlay_mask.as_ref(),
                     ImageLayout::TransferSrcOptimal,
                 )?;
                 projector.set_mode(projection_mode);
+                projector.set_overlay_radius(self.overlay_shape.radius(self.overlay_size[0]));
+                projector.set_fixed_depth(self.fixed_depth);
+                if self.out_of_view.needs_indicator() {
+                    projector.enable_indicator(
+                        self.device.clone(),
+                        self.allocator.clone(),
+                        self.descriptor_set_allocator.clone(),
+                    )?;
+                }
                 self.projector = Some(projector);
             }
         } else {
             self.render_texture = None;
             self.projector = None;
+            // It may have been hidden or moved because the cameras couldn't see it
+            Self::set_overlay_placement(&self.sys, self.handle, Some(self.overlay_transform))?;
         }
-        self.sys
-            .overlay()
-            .pin_mut()
-            .SetOverlayFlag(
-                self.handle,
-                openvr_sys2::VROverlayFlags::VROverlayFlags_SideBySide_Parallel,
-                self.display_mode.is_stereo(),
-            )
-            .into_result()?;
-        let bounds = match mode {
-            DisplayMode::Flat { eye: Eye::Left } => crate::vrapi::Bounds {
-                umin: 0.0,
-                umax: 0.5,
-                vmin: 0.0,
-                vmax: 1.0,
-            },
-            DisplayMode::Flat { eye: Eye::Right } => crate::vrapi::Bounds {
-                umin: 0.5,
-                umax: 1.0,
-                vmin: 0.0,
-                vmax: 1.0,
-            },
-            DisplayMode::Stereo { .. } | DisplayMode::Direct => crate::vrapi::Bounds {
-                umin: 0.0,
-                umax: 1.0,
-                vmin: 0.0,
-                vmax: 1.0,
-            },
-        };
-        self.set_overlay_texture_bounds_internal(bounds)
+        Self::set_overlay_display_mode(&self.sys, self.handle, mode)
     }
     fn show_overlay(&mut self) -> Result<(), Self::Error> {
-        self.sys
-            .overlay()
-            .pin_mut()
-            .ShowOverlay(self.handle)
-            .into_result()
-            .map_err(Into::into)
+        self.overlay_visible = true;
+        let extra_handles = self.extra_overlays.iter().map(|(_, extra)| extra.handle);
+        for handle in std::iter::once(self.handle).chain(extra_handles) {
+            self.sys
+                .overlay()
+                .pin_mut()
+                .ShowOverlay(handle)
+                .into_result()?;
+        }
+        Ok(())
     }
     fn hide_overlay(&mut self) -> Result<(), Self::Error> {
-        self.sys
-            .overlay()
-            .pin_mut()
-            .HideOverlay(self.handle)
-            .into_result()
-            .map_err(Into::into)
+        self.overlay_visible = false;
+        let extra_handles = self.extra_overlays.iter().map(|(_, extra)| extra.handle);
+        for handle in std::iter::once(self.handle).chain(extra_handles) {
+            self.sys
+                .overlay()
+                .pin_mut()
+                .HideOverlay(handle)
+                .into_result()?;
+        }
+        Ok(())
     }
     fn set_position_mode(&mut self, mode: PositionMode) -> Result<(), Self::Error> {
         self.position_mode = mode;
@@ -843,6 +1443,37 @@ impl Vr for OpenVr {
         }
         Ok(())
     }
+    fn reposition_extra_overlays(&mut self) {
+        self.extra_overlays.reposition();
+    }
+    fn set_overlay_shape(&mut self, shape: OverlayShape) -> Result<(), Self::Error> {
+        self.overlay_shape = if shape == OverlayShape::FullView {
+            log::warn!("Full view is only supported with OpenXR, using a flat overlay");
+            OverlayShape::Flat
+        } else {
+            shape
+        };
+        Self::set_overlay_geometry(
+            &self.sys,
+            self.handle,
+            self.overlay_shape,
+            self.overlay_size,
+        )?;
+        if let Some(projector) = &mut self.projector {
+            projector.set_overlay_radius(self.overlay_shape.radius(self.overlay_size[0]));
+        }
+        Ok(())
+    }
+    fn set_overlay_size(&mut self, size: [f32; 2]) -> Result<(), Self::Error> {
+        self.overlay_size = size;
+        Self::set_overlay_geometry(&self.sys, self.handle, self.overlay_shape, size)?;
+        if let Some(projector) = &mut self.projector {
+            projector.set_overlay_size(size);
+            // The curvature depends on the width
+            projector.set_overlay_radius(self.overlay_shape.radius(size[0]));
+        }
+        Ok(())
+    }
     fn acknowledge_quit(&mut self) {
         self.sys.pin_mut().AcknowledgeQuit_Exiting();
     }
@@ -889,6 +1520,15 @@ impl Vr for OpenVr {
                 .map_err(Into::into)
         }
     }
+    fn record_pose(&mut self) -> Result<(), Self::Error> {
+        let hmd_transform = self.sys.hmd_transform(0.0).cast::<f32>();
+        self.pose_history
+            .push(Instant::now(), [pose_from_transform(&hmd_transform)]);
+        Ok(())
+    }
+    fn current_hmd_transform(&mut self) -> Result<Option<Matrix4<f32>>, Self::Error> {
+        Ok(Some(self.sys.hmd_transform(0.0).cast::<f32>()))
+    }
     fn get_action_state(&self, action: Action) -> Result<bool, Self::Error> {
         let action_handle = self.buttons[action as usize];
         // log::debug!("getting action {action:?}");
@@ -912,6 +1552,47 @@ impl Vr for OpenVr {
         // log::debug!("action_data: {}", action_data.bState);
         Ok(action_data.bState)
     }
+    fn get_adjust_state(&self) -> Result<f32, Self::Error> {
+        let vrinput = unsafe { Pin::new_unchecked(&mut *openvr_sys2::VRInput()) };
+        let action_data = unsafe {
+            let mut action_data = MaybeUninit::<openvr_sys2::InputAnalogActionData_t>::uninit();
+            let result = vrinput
+                .GetAnalogActionData(
+                    self.adjust,
+                    action_data.as_mut_ptr(),
+                    std::mem::size_of::<openvr_sys2::InputAnalogActionData_t>() as u32,
+                    openvr_sys2::vr::k_ulInvalidInputValueHandle,
+                )
+                .into_result();
+            if result.is_err() {
+                log::error!("GetAnalogActionData failed: {:?}", result);
+                return Ok(0.0);
+            }
+            action_data.assume_init()
+        };
+        Ok(if action_data.bActive {
+            action_data.y
+        } else {
+            0.0
+        })
+    }
+    fn set_fixed_depth(&mut self, distance: f32) {
+        self.fixed_depth = distance;
+        if let Some(projector) = &mut self.projector {
+            projector.set_fixed_depth(distance);
+        }
+        self.extra_overlays.set_fixed_depth(distance);
+    }
+}
+
+/// The OpenXR part of an extra overlay, with its own swapchain and composition layers
+struct OpenXrExtraOverlay {
+    swapchain: openxr::Swapchain<openxr::Vulkan>,
+    swapchain_images: Vec<Arc<Image>>,
+    saved_overlay_pose: Option<openxr::Posef>,
+    /// Whether a swapchain image is acquired for the GPU work of the current frame, it is
+    /// released once that is done
+    acquired: bool,
 }
 
 pub(crate) struct OpenXr {
@@ -920,6 +1601,11 @@ pub(crate) struct OpenXr {
     position_mode: PositionMode,
     reposition: bool,
     display_mode: DisplayMode,
+    overlay_shape: OverlayShape,
+    /// Width and height of the overlay, in meters
+    overlay_size: [f32; 2],
+    /// Whether the runtime supports XR_KHR_composition_layer_cylinder
+    cylinder_supported: bool,
     allocator: Arc<StandardMemoryAllocator>,
     descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
     cmdbuf_allocator: Arc<StandardCommandBufferAllocator>,
@@ -928,6 +1614,8 @@ pub(crate) struct OpenXr {
     action_button2: openxr::Action<bool>,
     action_debug: openxr::Action<bool>,
     action_reposition: openxr::Action<bool>,
+    action_cycle_filter: openxr::Action<bool>,
+    action_adjust: openxr::Action<f32>,
     camera_config: Option<StereoCamera>,
 
     session_state: openxr::SessionState,
@@ -940,12 +1628,29 @@ pub(crate) struct OpenXr {
     space: openxr::Space,
     saved_poses: [(UnitQuaternion<f32>, Vector3<f32>); 2],
     saved_overlay_pose: Option<openxr::Posef>,
+    /// Fields of view of the eyes, from the last located views
+    saved_fovs: [openxr::Fovf; 2],
+    /// Eye poses and fields of view the camera view was last projected for, if it is shown
+    /// as a projection layer
+    saved_full_view: Option<[(openxr::Posef, openxr::Fovf); 2]>,
 
     device: Arc<Device>,
     queue: Arc<Queue>,
     vk_instance: Arc<Instance>,
 
     projector: Option<crate::projection::Projection>,
+    out_of_view: crate::out_of_view::OutOfView,
+    depth_map: Option<crate::depth::DepthMap>,
+    lens_remap: Option<crate::distortion_correction::LensRemap>,
+    overlay_mask: Option<Arc<crate::overlay_mask::Mask>>,
+    /// Draws `overlay_mask` in display modes without projection
+    mask_pass: Option<crate::overlay_mask::OverlayMask>,
+    extra_overlays: crate::extra_overlay::ExtraOverlays<OpenXrExtraOverlay>,
+    reprojection: bool,
+    fixed_depth: f32,
+    /// HMD pose when the last camera frame was captured, and the fov used for it
+    saved_capture: Option<(Matrix4<f32>, [[f32; 2]; 2])>,
+    pose_history: PoseHistory<2>,
     render_texture: Option<Arc<Image>>,
 }
 fn affine_to_posef(t: Affine3<f32>) -> openxr::Posef {
@@ -1011,9 +1716,199 @@ pub(crate) enum OpenXrError {
     VersionNotSupported(vulkano::Version),
     #[error("no supported blend mode")]
     NoSupportedBlendMode,
+    #[error("overlay mask error: {0}")]
+    Mask(#[from] crate::overlay_mask::MaskError),
+    #[error("extra overlay error: {0}")]
+    ExtraOverlay(#[from] crate::extra_overlay::ExtraOverlayError),
 }
 
 impl OpenXr {
+    /// Whether the session is in a state where frames can be waited on and submitted
+    fn session_running(&self) -> bool {
+        matches!(
+            self.session_state,
+            openxr::SessionState::READY
+                | openxr::SessionState::SYNCHRONIZED
+                | openxr::SessionState::VISIBLE
+                | openxr::SessionState::FOCUSED
+        )
+    }
+    /// Eye poses at `time`. Falls back to the last known poses if tracking is lost.
+    fn view_poses(
+        &mut self,
+        time: openxr::Time,
+    ) -> Result<[(UnitQuaternion<f32>, Vector3<f32>); 2], OpenXrError> {
+        let (view_state_flags, views) =
+            self.session
+                .locate_views(ViewConfigurationType::PRIMARY_STEREO, time, &self.space)?;
+        if !view_state_flags.contains(ViewStateFlags::ORIENTATION_VALID)
+            || !view_state_flags.contains(ViewStateFlags::POSITION_VALID)
+        {
+            Ok(self.saved_poses)
+        } else {
+            let poses = [0, 1].map(|id| posef_to_nalgebra(views[id].pose));
+            self.saved_poses = poses;
+            self.saved_fovs = [views[0].fov, views[1].fov];
+            Ok(poses)
+        }
+    }
+    /// Whether the camera view is shown as a projection layer covering the whole view,
+    /// instead of on an overlay.
+    fn is_full_view(&self) -> bool {
+        self.overlay_shape == OverlayShape::FullView
+            && self.display_mode.projection_mode().is_some()
+    }
+    /// Transform in eye space that stretches an overlay of `size` to exactly cover the eye's
+    /// field of view, one meter in front of the eye.
+    fn full_view_transform(fov: &openxr::Fovf, [width, height]: [f32; 2]) -> Matrix4<f32> {
+        let [left, right, up, down] = [
+            fov.angle_left,
+            fov.angle_right,
+            fov.angle_up,
+            fov.angle_down,
+        ]
+        .map(f32::tan);
+        let scale = Vector3::new((right - left) / width, (up - down) / height, 1.0);
+        Translation3::new((left + right) / 2.0, (up + down) / 2.0, -1.0).to_homogeneous()
+            * Matrix4::new_nonuniform_scaling(&scale)
+    }
+    fn hmd_transform(view_poses: &[(UnitQuaternion<f32>, Vector3<f32>); 2]) -> Matrix4<f32> {
+        let rotation_center = UnitQuaternion::from_quaternion(
+            (view_poses[0].0.as_ref() + view_poses[1].0.as_ref()) / 2.0,
+        );
+        let center: Translation3<f32> = ((view_poses[0].1 + view_poses[1].1) / 2.0).into();
+        center.to_homogeneous() * rotation_center.to_homogeneous()
+    }
+    /// Place the overlay according to the eye poses at display time, and project the camera
+    /// image, captured when the HMD was at `capture_hmd_transform`, onto it. The overlay may
+    /// be moved or hidden if the cameras can't see where it is, see `OutOfView::place`.
+    ///
+    /// In projection modes this acquires a swapchain image, the caller must release it.
+    fn update_overlay(
+        &mut self,
+        display_poses: &[(UnitQuaternion<f32>, Vector3<f32>); 2],
+        capture_hmd_transform: &Matrix4<f32>,
+        fov: &[[f32; 2]; 2],
+    ) -> Result<(), OpenXrError> {
+        let hmd_transform = Self::hmd_transform(display_poses);
+        if self.reposition {
+            self.position_mode.reposition(hmd_transform);
+            self.reposition = false;
+        }
+        let transform = self.position_mode.transform(hmd_transform);
+        let overlay_posef = affine_to_posef(transform);
+        self.saved_overlay_pose = Some(overlay_posef);
+        self.saved_full_view = None;
+        if self.display_mode.projection_mode().is_some() {
+            // Apply projection
+            let image = self.swapchain.acquire_image()? as usize;
+            let view_transforms = display_poses.map(|(rotation, position)| {
+                Translation3::from(position).to_homogeneous() * rotation.to_homogeneous()
+            });
+            let ipd = display_poses[1].1.x - display_poses[0].1.x;
+            let is_full_view = self.is_full_view();
+            // In full view each eye gets its own overlay, filling its field of view
+            let overlay_transforms = if is_full_view {
+                self.saved_full_view = Some([0, 1].map(|i| {
+                    let view_pose =
+                        affine_to_posef(Affine3::from_matrix_unchecked(view_transforms[i]));
+                    (view_pose, self.saved_fovs[i])
+                }));
+                [0, 1].map(|i| {
+                    view_transforms[i]
+                        * Self::full_view_transform(&self.saved_fovs[i], self.overlay_size)
+                })
+            } else {
+                [*transform.matrix(); 2]
+            };
+            self.swapchain.wait_image(openxr::Duration::INFINITE)?;
+            let output = self.swapchain_images[image].clone();
+            let projector = self.projector.as_mut().unwrap();
+            // Full view always shows what the cameras see
+            if is_full_view {
+                projector.update_mvps(
+                    &overlay_transforms,
+                    fov,
+                    &view_transforms,
+                    capture_hmd_transform,
+                )?;
+            } else {
+                let placement = self.out_of_view.place(
+                    projector,
+                    *transform.matrix(),
+                    fov,
+                    &view_transforms,
+                    &hmd_transform,
+                    capture_hmd_transform,
+                )?;
+                self.saved_overlay_pose =
+                    placement.map(|t| affine_to_posef(Affine3::from_matrix_unchecked(t)));
+                if placement.is_none() {
+                    return Ok(());
+                }
+            }
+            projector.set_ipd(ipd);
+            let future = projector.project(
+                self.allocator.clone(),
+                self.cmdbuf_allocator.clone(),
+                vulkano::sync::future::now(self.device.clone()),
+                &self.queue,
+                output,
+            )?;
+            future.flush()?;
+            future.then_signal_fence().wait(None)?;
+        }
+        Ok(())
+    }
+    /// Place the extra overlays according to the eye poses at display time, and render the
+    /// last camera frame onto them after `after`, see `ExtraOverlays::render`. Once the
+    /// returned future is done, `release_extra_overlays` hands them to the runtime.
+    fn render_extra_overlays(
+        &mut self,
+        after: Box<dyn GpuFuture>,
+        display_poses: &[(UnitQuaternion<f32>, Vector3<f32>); 2],
+        capture_hmd_transform: &Matrix4<f32>,
+        fov: &[[f32; 2]; 2],
+        new_frame: bool,
+    ) -> Result<Box<dyn GpuFuture>, OpenXrError> {
+        let hmd_transform = Self::hmd_transform(display_poses);
+        let view_transforms = display_poses.map(|(rotation, position)| {
+            Translation3::from(position).to_homogeneous() * rotation.to_homogeneous()
+        });
+        let ipd = display_poses[1].1.x - display_poses[0].1.x;
+        self.extra_overlays.render(
+            self.allocator.clone(),
+            self.cmdbuf_allocator.clone(),
+            after,
+            &self.queue,
+            fov,
+            &view_transforms,
+            &hmd_transform,
+            capture_hmd_transform,
+            ipd,
+            new_frame,
+            |extra, _, placement| {
+                extra.saved_overlay_pose =
+                    placement.map(|t| affine_to_posef(Affine3::from_matrix_unchecked(t)));
+                Ok(())
+            },
+            |extra| {
+                let image = extra.swapchain.acquire_image()? as usize;
+                extra.swapchain.wait_image(openxr::Duration::INFINITE)?;
+                extra.acquired = true;
+                Ok(extra.swapchain_images[image].clone())
+            },
+        )
+    }
+    /// Release the swapchain images rendered by `render_extra_overlays`.
+    fn release_extra_overlays(&mut self) -> Result<(), OpenXrError> {
+        for (_, extra) in self.extra_overlays.iter_mut() {
+            if std::mem::take(&mut extra.acquired) {
+                extra.swapchain.release_image()?;
+            }
+        }
+        Ok(())
+    }
     fn create_vk_device(
         xr_instance: &openxr::Instance,
         xr_system: openxr::SystemId,
@@ -1124,48 +2019,22 @@ impl OpenXr {
         })
     }
 
-    fn composition_layers<'a>(
-        saved_overlay_pose: &'a Option<openxr::Posef>,
+    /// Projection views showing each eye's half of the swapchain, for full view.
+    fn projection_views<'a>(
+        full_view: &[(openxr::Posef, openxr::Fovf); 2],
         swapchain: &'a openxr::Swapchain<openxr::Vulkan>,
-        space: &'a openxr::Space,
-        is_stereo: bool,
-    ) -> Option<(
-        openxr::CompositionLayerQuad<'a, openxr::Vulkan>,
-        openxr::CompositionLayerQuad<'a, openxr::Vulkan>,
-    )> {
-        saved_overlay_pose.map(|overlay_posef| {
-            let left = openxr::CompositionLayerQuad::<openxr::Vulkan>::new()
-                .eye_visibility(EyeVisibility::LEFT)
-                .pose(overlay_posef)
-                .sub_image(
-                    SwapchainSubImage::new()
-                        .swapchain(swapchain)
-                        .image_rect(Rect2Di {
-                            offset: Offset2Di { x: 0, y: 0 },
-                            extent: Extent2Di {
-                                width: crate::CAMERA_SIZE as i32,
-                                height: crate::CAMERA_SIZE as i32,
-                            },
-                        }),
-                )
-                .space(space)
-                .size(Extent2Df {
-                    width: 1.0,
-                    height: 1.0,
-                });
-            let right = openxr::CompositionLayerQuad::<openxr::Vulkan>::new()
-                .eye_visibility(EyeVisibility::RIGHT)
-                .pose(overlay_posef)
+    ) -> [openxr::CompositionLayerProjectionView<'a, openxr::Vulkan>; 2] {
+        [0, 1].map(|i| {
+            let (pose, fov) = full_view[i];
+            openxr::CompositionLayerProjectionView::new()
+                .pose(pose)
+                .fov(fov)
                 .sub_image(
                     SwapchainSubImage::new()
                         .swapchain(swapchain)
                         .image_rect(Rect2Di {
                             offset: Offset2Di {
-                                x: if is_stereo {
-                                    crate::CAMERA_SIZE as i32
-                                } else {
-                                    0
-                                },
+                                x: i as i32 * crate::CAMERA_SIZE as i32,
                                 y: 0,
                             },
                             extent: Extent2Di {
@@ -1174,21 +2043,151 @@ impl OpenXr {
                             },
                         }),
                 )
-                .space(space)
-                .size(Extent2Df {
-                    width: 1.0,
-                    height: 1.0,
-                });
-            (left, right)
         })
     }
 
+    /// Composition layers to submit. A single projection layer if `full_view` is given,
+    /// otherwise one layer for each eye of the given `size`: a quad, or a cylinder if
+    /// `radius` is not 0.
+    #[allow(clippy::too_many_arguments)]
+    fn composition_layers<'a>(
+        saved_overlay_pose: &'a Option<openxr::Posef>,
+        full_view: Option<&'a [openxr::CompositionLayerProjectionView<'a, openxr::Vulkan>]>,
+        swapchain: &'a openxr::Swapchain<openxr::Vulkan>,
+        space: &'a openxr::Space,
+        is_stereo: bool,
+        is_masked: bool,
+        [width, height]: [f32; 2],
+        radius: f32,
+    ) -> Option<Vec<Box<dyn CompositionLayerBase<'a, openxr::Vulkan> + 'a>>> {
+        // Parts of the camera view hidden by the mask are transparent
+        let layer_flags = if is_masked {
+            CompositionLayerFlags::BLEND_TEXTURE_SOURCE_ALPHA
+        } else {
+            CompositionLayerFlags::EMPTY
+        };
+        if let Some(views) = full_view {
+            return Some(vec![Box::new(
+                openxr::CompositionLayerProjection::new()
+                    .layer_flags(layer_flags)
+                    .space(space)
+                    .views(views),
+            )]);
+        }
+        saved_overlay_pose.map(|overlay_posef| {
+            let layers = [EyeVisibility::LEFT, EyeVisibility::RIGHT].map(|eye| {
+                let sub_image = SwapchainSubImage::new()
+                    .swapchain(swapchain)
+                    .image_rect(Rect2Di {
+                        offset: Offset2Di {
+                            x: if is_stereo && eye == EyeVisibility::RIGHT {
+                                crate::CAMERA_SIZE as i32
+                            } else {
+                                0
+                            },
+                            y: 0,
+                        },
+                        extent: Extent2Di {
+                            width: crate::CAMERA_SIZE as i32,
+                            height: crate::CAMERA_SIZE as i32,
+                        },
+                    });
+                if radius > 0.0 {
+                    // The pose of a cylinder layer is the centre of the cylinder, which is
+                    // in front of the overlay.
+                    let (rotation, position) = posef_to_nalgebra(overlay_posef);
+                    let center = position + rotation * Vector3::new(0.0, 0.0, radius);
+                    let pose = openxr::Posef {
+                        orientation: overlay_posef.orientation,
+                        position: openxr::Vector3f {
+                            x: center.x,
+                            y: center.y,
+                            z: center.z,
+                        },
+                    };
+                    Box::new(
+                        openxr::CompositionLayerCylinderKHR::<openxr::Vulkan>::new()
+                            .layer_flags(layer_flags)
+                            .eye_visibility(eye)
+                            .pose(pose)
+                            .sub_image(sub_image)
+                            .space(space)
+                            .radius(radius)
+                            .central_angle(width / radius)
+                            .aspect_ratio(width / height),
+                    ) as Box<dyn CompositionLayerBase<'a, openxr::Vulkan> + 'a>
+                } else {
+                    Box::new(
+                        openxr::CompositionLayerQuad::<openxr::Vulkan>::new()
+                            .layer_flags(layer_flags)
+                            .eye_visibility(eye)
+                            .pose(overlay_posef)
+                            .sub_image(sub_image)
+                            .space(space)
+                            .size(Extent2Df { width, height }),
+                    )
+                }
+            });
+            Vec::from(layers)
+        })
+    }
+
+    /// Swapchain for an overlay texture, and its images. The camera frame is copied out of
+    /// it for the extra overlays, and into it for the extra overlays without projection.
+    fn create_swapchain(
+        session: &openxr::Session<openxr::Vulkan>,
+        device: &Arc<Device>,
+    ) -> Result<(openxr::Swapchain<openxr::Vulkan>, Vec<Arc<Image>>), OpenXrError> {
+        let swapchain = session.create_swapchain(&openxr::SwapchainCreateInfo {
+            array_size: 1,
+            face_count: 1,
+            create_flags: Default::default(),
+            usage_flags: openxr::SwapchainUsageFlags::COLOR_ATTACHMENT
+                | openxr::SwapchainUsageFlags::TRANSFER_DST
+                | openxr::SwapchainUsageFlags::TRANSFER_SRC,
+            format: vulkano::format::Format::R8G8B8A8_UNORM as u32,
+            sample_count: 1,
+            width: crate::CAMERA_SIZE * 2,
+            height: crate::CAMERA_SIZE,
+            mip_count: 1,
+        })?;
+        log::debug!("created swapchain");
+        let swapchain_images = swapchain
+            .enumerate_images()?
+            .into_iter()
+            .map(|handle| {
+                let handle = ash::vk::Image::from_raw(handle);
+                let raw_image = unsafe {
+                    vulkano::image::sys::RawImage::from_handle_borrowed(
+                        device.clone(),
+                        handle,
+                        ImageCreateInfo {
+                            format: vulkano::format::Format::R8G8B8A8_UNORM,
+                            extent: [CAMERA_SIZE * 2, CAMERA_SIZE, 1],
+                            usage: ImageUsage::COLOR_ATTACHMENT
+                                | ImageUsage::TRANSFER_DST
+                                | ImageUsage::TRANSFER_SRC,
+                            ..Default::default()
+                        },
+                    )?
+                };
+                // SAFETY: OpenXR guarantees that the image is a swapchain image, thus has memory backing it.
+                let image = unsafe { raw_image.assume_bound() };
+                Ok::<_, OpenXrError>(Arc::new(image))
+            })
+            .try_collect()?;
+        log::debug!("got swapchain images");
+        Ok((swapchain, swapchain_images))
+    }
+
     pub(crate) fn new(placement: u32) -> Result<Self, OpenXrError> {
         let entry = unsafe { openxr::Entry::load()? };
         let mut extension = openxr::ExtensionSet::default();
         extension.extx_overlay = true;
         extension.khr_vulkan_enable2 = true;
         extension.khr_convert_timespec_time = true;
+        let cylinder_supported = entry.enumerate_extensions()?.khr_composition_layer_cylinder;
+        extension.khr_composition_layer_cylinder = cylinder_supported;
         let instance = entry.create_instance(
             &ApplicationInfo {
                 application_name: crate::APP_NAME,
@@ -1256,46 +2255,15 @@ impl OpenXr {
         {
             return Err(OpenXrError::NoFormat);
         }
-        let swapchain = session.create_swapchain(&openxr::SwapchainCreateInfo {
-            array_size: 1,
-            face_count: 1,
-            create_flags: Default::default(),
-            usage_flags: openxr::SwapchainUsageFlags::COLOR_ATTACHMENT
-                | openxr::SwapchainUsageFlags::TRANSFER_DST,
-            format: vulkano::format::Format::R8G8B8A8_UNORM as u32,
-            sample_count: 1,
-            width: crate::CAMERA_SIZE * 2,
-            height: crate::CAMERA_SIZE,
-            mip_count: 1,
-        })?;
-        log::debug!("created swapchain");
-        let swapchain_images = swapchain
-            .enumerate_images()?
-            .into_iter()
-            .map(|handle| {
-                let handle = ash::vk::Image::from_raw(handle);
-                let raw_image = unsafe {
-                    vulkano::image::sys::RawImage::from_handle_borrowed(
-                        device.clone(),
-                        handle,
-                        ImageCreateInfo {
-                            format: vulkano::format::Format::R8G8B8A8_UNORM,
-                            extent: [CAMERA_SIZE * 2, CAMERA_SIZE, 1],
-                            usage: ImageUsage::COLOR_ATTACHMENT | ImageUsage::TRANSFER_DST,
-                            ..Default::default()
-                        },
-                    )?
-                };
-                // SAFETY: OpenXR guarantees that the image is a swapchain image, thus has memory backing it.
-                let image = unsafe { raw_image.assume_bound() };
-                Ok::<_, OpenXrError>(Arc::new(image))
-            })
-            .try_collect()?;
-        log::debug!("got swapchain images");
+        let (swapchain, swapchain_images) = Self::create_swapchain(&session, &device)?;
         let action_button1 = action_set.create_action("button1", "Button1", &[])?;
         let action_button2 = action_set.create_action("button2", "Button2", &[])?;
         let action_debug = action_set.create_action("debug", "Debug", &[])?;
         let action_reposition = action_set.create_action("reposition", "Reposition", &[])?;
+        let action_cycle_filter =
+            action_set.create_action("cycle_filter", "Cycle colour filter", &[])?;
+        let action_adjust =
+            action_set.create_action("adjust", "Adjust fixed depth or overlay size", &[])?;
         instance.suggest_interaction_profile_bindings(
             instance.string_to_path("/interaction_profiles/htc/vive_controller")?,
             &[
@@ -1315,6 +2283,14 @@ impl OpenXr {
                     &action_reposition,
                     instance.string_to_path("/user/hand/right/input/trigger/click")?,
                 ),
+                openxr::Binding::new(
+                    &action_cycle_filter,
+                    instance.string_to_path("/user/hand/left/input/trackpad/click")?,
+                ),
+                openxr::Binding::new(
+                    &action_adjust,
+                    instance.string_to_path("/user/hand/right/input/trackpad/y")?,
+                ),
             ],
         )?;
         instance.suggest_interaction_profile_bindings(
@@ -1336,6 +2312,14 @@ impl OpenXr {
                     &action_reposition,
                     instance.string_to_path("/user/hand/right/input/a/click")?,
                 ),
+                openxr::Binding::new(
+                    &action_cycle_filter,
+                    instance.string_to_path("/user/hand/left/input/a/click")?,
+                ),
+                openxr::Binding::new(
+                    &action_adjust,
+                    instance.string_to_path("/user/hand/right/input/thumbstick/y")?,
+                ),
             ],
         )?;
         instance.suggest_interaction_profile_bindings(
@@ -1371,10 +2355,15 @@ impl OpenXr {
             position_mode: PositionMode::default(),
             reposition: false,
             display_mode: DisplayMode::default(),
+            overlay_shape: OverlayShape::default(),
+            overlay_size: [crate::config::default_overlay_size(); 2],
+            cylinder_supported,
             action_button1,
             action_button2,
             action_debug,
             action_reposition,
+            action_cycle_filter,
+            action_adjust,
 
             action_set,
 
@@ -1388,6 +2377,8 @@ impl OpenXr {
             space,
             saved_poses: [Default::default(); 2],
             saved_overlay_pose: None,
+            saved_fovs: [Default::default(); 2],
+            saved_full_view: None,
 
             allocator,
             descriptor_set_allocator,
@@ -1397,6 +2388,16 @@ impl OpenXr {
             queue,
 
             projector: None,
+            out_of_view: Default::default(),
+            depth_map: None,
+            lens_remap: None,
+            overlay_mask: None,
+            mask_pass: None,
+            extra_overlays: Default::default(),
+            reprojection: false,
+            fixed_depth: crate::config::default_fixed_depth_distance(),
+            saved_capture: None,
+            pose_history: PoseHistory::default(),
             render_texture: None,
         })
     }
@@ -1411,6 +2412,29 @@ unsafe extern "system" fn get_instance_proc_addr(
     library.get_instance_proc_addr(instance, name)
 }
 
+impl OpenXrExtraOverlay {
+    /// Composition layers of `overlay`, see `OpenXr::composition_layers`. `frame_is_masked`
+    /// if the camera frame itself has transparent parts.
+    fn composition_layers<'a>(
+        &'a self,
+        overlay: &crate::extra_overlay::ExtraOverlay,
+        space: &'a openxr::Space,
+        frame_is_masked: bool,
+    ) -> Option<Vec<Box<dyn CompositionLayerBase<'a, openxr::Vulkan> + 'a>>> {
+        let size = overlay.size;
+        OpenXr::composition_layers(
+            &self.saved_overlay_pose,
+            None,
+            &self.swapchain,
+            space,
+            overlay.display_mode.is_stereo(),
+            frame_is_masked || overlay.has_mask(),
+            size,
+            overlay.shape.radius(size[0]),
+        )
+    }
+}
+
 impl VkContext for OpenXr {
     fn vk_device(&self, _instance: &Arc<Instance>) -> (Arc<Device>, Arc<Queue>) {
         (self.device.clone(), self.queue.clone())
@@ -1440,85 +2464,149 @@ impl Vr for OpenXr {
         self.camera_config
     }
 
+    fn hmd_serial(&self) -> Option<String> {
+        None
+    }
+
     fn set_fallback_camera_config(&mut self, cfg: StereoCamera) {
         self.camera_config = Some(cfg);
     }
 
+    fn set_depth_map(&mut self, depth_map: Option<crate::depth::DepthMap>) {
+        self.depth_map = depth_map;
+    }
+    fn set_lens_remap(&mut self, lens_remap: Option<crate::distortion_correction::LensRemap>) {
+        self.lens_remap = lens_remap;
+    }
+    fn set_overlay_mask(
+        &mut self,
+        mask: Option<Arc<crate::overlay_mask::Mask>>,
+    ) -> Result<(), Self::Error> {
+        self.mask_pass = mask
+            .as_ref()
+            .map(|mask| {
+                crate::overlay_mask::OverlayMask::standalone(
+                    self.device.clone(),
+                    self.descriptor_set_allocator.clone(),
+                    mask,
+                )
+            })
+            .transpose()?;
+        self.overlay_mask = mask;
+        Ok(())
+    }
+    fn set_out_of_view(&mut self, cfg: crate::config::OutOfViewConfig) {
+        self.out_of_view = crate::out_of_view::OutOfView::new(cfg);
+    }
+    fn add_overlay(
+        &mut self,
+        name: &str,
+        cfg: &crate::config::ExtraOverlayConfig,
+        mask: Option<Arc<crate::overlay_mask::Mask>>,
+    ) -> Result<(), Self::Error> {
+        let camera_calib = self.load_camera_paramter();
+        let mut overlay = self.extra_overlays.create(
+            name,
+            cfg,
+            self.device.clone(),
+            self.allocator.clone(),
+            self.descriptor_set_allocator.clone(),
+            &camera_calib,
+            self.depth_map.as_ref(),
+            self.lens_remap.as_ref(),
+            mask.as_ref(),
+            self.fixed_depth,
+            ImageLayout::ColorAttachmentOptimal,
+        )?;
+        if matches!(cfg.shape, OverlayShape::Cylinder { .. }) && !self.cylinder_supported {
+            log::warn!(
+                "Overlay {name}: cylinder layers are not supported by the OpenXR runtime, \
+                 using a flat overlay"
+            );
+            overlay.set_shape(OverlayShape::Flat);
+        }
+        let (swapchain, swapchain_images) = Self::create_swapchain(&self.session, &self.device)?;
+        self.extra_overlays.push(
+            overlay,
+            OpenXrExtraOverlay {
+                swapchain,
+                swapchain_images,
+                saved_overlay_pose: None,
+                acquired: false,
+            },
+        );
+        Ok(())
+    }
+    fn set_reprojection(&mut self, enabled: bool) {
+        self.reprojection = enabled;
+    }
+
     fn submit_texture(
         &mut self,
         elapsed: Duration,
         fov: &[[f32; 2]; 2],
     ) -> Result<(), Self::Error> {
         log::trace!("submit texture");
-        let frame_state = self.frame_state.as_ref().unwrap();
-        let now = self.instance.now()?;
-        let time_at_capture =
-            openxr::Time::from_nanos((now.as_nanos() as u128 - elapsed.as_nanos()) as i64);
-        let (view_state_flags, views) = self.session.locate_views(
-            ViewConfigurationType::PRIMARY_STEREO,
-            time_at_capture,
-            &self.space,
-        )?;
-        let view_poses = if !view_state_flags.contains(ViewStateFlags::ORIENTATION_VALID)
-            || !view_state_flags.contains(ViewStateFlags::POSITION_VALID)
-        {
-            self.saved_poses
+        let frame_state = self.frame_state.unwrap();
+        self.record_pose()?;
+        let capture_poses = self
+            .pose_history
+            .at(Instant::now() - elapsed)
+            .expect("pose history is empty");
+        let capture_hmd_transform = Self::hmd_transform(&capture_poses);
+        self.saved_capture = Some((capture_hmd_transform, *fov));
+        // With reprojection, the overlay is placed using the head pose at display time
+        // instead, and the camera image is warped accordingly.
+        let display_poses = if self.reprojection {
+            self.view_poses(frame_state.predicted_display_time)?
         } else {
-            let poses = [0, 1].map(|id| posef_to_nalgebra(views[id].pose));
-            self.saved_poses = poses;
-            poses
+            capture_poses
         };
-        let rotation_center = UnitQuaternion::from_quaternion(
-            (view_poses[0].0.as_ref() + view_poses[1].0.as_ref()) / 2.0,
-        );
-        let center: Translation3<f32> = ((view_poses[0].1 + view_poses[1].1) / 2.0).into();
-        let hmd_transform = center.to_homogeneous() * rotation_center.to_homogeneous();
-        if self.reposition {
-            self.position_mode.reposition(hmd_transform);
-            self.reposition = false;
-        }
-        let transform = self.position_mode.transform(hmd_transform);
-        let overlay_posef = affine_to_posef(transform);
-        self.saved_overlay_pose = Some(overlay_posef);
-        if self.display_mode.projection_mode().is_some() {
-            // Apply projection
-            let image = self.swapchain.acquire_image()? as usize;
-            let view_transforms = [
-                Translation3::from(view_poses[0].1).to_homogeneous()
-                    * view_poses[0].0.to_homogeneous(),
-                Translation3::from(view_poses[1].1).to_homogeneous()
-                    * view_poses[1].0.to_homogeneous(),
-            ];
-            let ipd = view_poses[1].1.x - view_poses[0].1.x;
-            self.swapchain.wait_image(openxr::Duration::INFINITE)?;
-            let output = self.swapchain_images[image].clone();
-            let projector = self.projector.as_mut().unwrap();
-            projector.update_mvps(transform.matrix(), fov, &view_transforms, &hmd_transform)?;
-            projector.set_ipd(ipd);
-            let future = projector.project(
-                self.allocator.clone(),
-                self.cmdbuf_allocator.clone(),
-                vulkano::sync::future::now(self.device.clone()),
-                &self.queue,
-                output,
-            )?;
-            future.flush()?;
-            future.then_signal_fence().wait(None)?;
-        } else {
-            self.render_texture.take();
+        let future = self.extra_overlays.copy_frame(
+            self.cmdbuf_allocator.clone(),
+            vulkano::sync::future::now(self.device.clone()).boxed(),
+            &self.queue,
+            self.render_texture.clone().unwrap(),
+        )?;
+        let mut future =
+            self.render_extra_overlays(future, &display_poses, &capture_hmd_transform, fov, true)?;
+        self.update_overlay(&display_poses, &capture_hmd_transform, fov)?;
+        if self.display_mode.projection_mode().is_none() {
+            let output = self.render_texture.take().unwrap();
+            if let Some(mask_pass) = &self.mask_pass {
+                future = mask_pass
+                    .apply(self.cmdbuf_allocator.clone(), future, &self.queue, output)?
+                    .boxed();
+            }
         }
+        future.flush()?;
+        future.then_signal_fence().wait(None)?;
         self.swapchain.release_image()?;
-        let (left, right) = Self::composition_layers(
+        self.release_extra_overlays()?;
+        let full_view = self
+            .saved_full_view
+            .as_ref()
+            .map(|full_view| Self::projection_views(full_view, &self.swapchain));
+        let mut layers = Self::composition_layers(
             &self.saved_overlay_pose,
+            full_view.as_ref().map(|views| &views[..]),
             &self.swapchain,
             &self.space,
             self.display_mode.is_stereo(),
+            self.display_mode.is_masked() || self.overlay_mask.is_some(),
+            self.overlay_size,
+            self.overlay_shape.radius(self.overlay_size[0]),
         )
-        .unwrap();
+        .unwrap_or_default();
+        layers.extend(self.extra_overlays.iter().flat_map(|(overlay, extra)| {
+            extra
+                .composition_layers(overlay, &self.space, self.display_mode.is_masked())
+                .unwrap_or_default()
+        }));
         self.frame_stream.end(
             frame_state.predicted_display_time,
             EnvironmentBlendMode::OPAQUE,
-            &[&left, &right],
+            &layers.iter().map(|layer| &**layer).collect::<Vec<_>>(),
         )?;
         Ok(())
     }
@@ -1528,43 +2616,63 @@ impl Vr for OpenXr {
     }
 
     fn refresh(&mut self) -> Result<(), Self::Error> {
-        if self.session_state != openxr::SessionState::SYNCHRONIZED
-            && self.session_state != openxr::SessionState::FOCUSED
-            && self.session_state != openxr::SessionState::VISIBLE
-            && self.session_state != openxr::SessionState::READY
-        {
+        if !self.session_running() {
             return Ok(());
         }
         log::trace!("refresh");
-        let frame_state = self.frame_state.insert(self.frame_waiter.wait()?);
+        let frame_state = *self.frame_state.insert(self.frame_waiter.wait()?);
         self.frame_stream.begin()?;
-        if let Some((left, right)) = Self::composition_layers(
+        self.record_pose()?;
+        // Re-warp the last camera frame to the head pose at display time
+        if let Some((capture_hmd_transform, fov)) = self.saved_capture {
+            if self.reprojection && frame_state.should_render {
+                let display_poses = self.view_poses(frame_state.predicted_display_time)?;
+                if self.display_mode.projection_mode().is_some() {
+                    self.update_overlay(&display_poses, &capture_hmd_transform, &fov)?;
+                    self.swapchain.release_image()?;
+                }
+                let future = self.render_extra_overlays(
+                    vulkano::sync::future::now(self.device.clone()).boxed(),
+                    &display_poses,
+                    &capture_hmd_transform,
+                    &fov,
+                    false,
+                )?;
+                future.flush()?;
+                future.then_signal_fence().wait(None)?;
+                self.release_extra_overlays()?;
+            }
+        }
+        let full_view = self
+            .saved_full_view
+            .as_ref()
+            .map(|full_view| Self::projection_views(full_view, &self.swapchain));
+        let mut layers = Self::composition_layers(
             &self.saved_overlay_pose,
+            full_view.as_ref().map(|views| &views[..]),
             &self.swapchain,
             &self.space,
             self.display_mode.is_stereo(),
-        ) {
-            self.frame_stream.end(
-                frame_state.predicted_display_time,
-                EnvironmentBlendMode::OPAQUE,
-                &[&left, &right],
-            )?;
-        } else {
-            self.frame_stream.end(
-                frame_state.predicted_display_time,
-                EnvironmentBlendMode::OPAQUE,
-                &[],
-            )?;
-        }
+            self.display_mode.is_masked() || self.overlay_mask.is_some(),
+            self.overlay_size,
+            self.overlay_shape.radius(self.overlay_size[0]),
+        )
+        .unwrap_or_default();
+        layers.extend(self.extra_overlays.iter().flat_map(|(overlay, extra)| {
+            extra
+                .composition_layers(overlay, &self.space, self.display_mode.is_masked())
+                .unwrap_or_default()
+        }));
+        self.frame_stream.end(
+            frame_state.predicted_display_time,
+            EnvironmentBlendMode::OPAQUE,
+            &layers.iter().map(|layer| &**layer).collect::<Vec<_>>(),
+        )?;
         Ok(())
     }
 
     fn get_render_texture(&mut self) -> Result<Option<Arc<Image>>, Self::Error> {
-        if self.session_state != openxr::SessionState::SYNCHRONIZED
-            && self.session_state != openxr::SessionState::FOCUSED
-            && self.session_state != openxr::SessionState::VISIBLE
-            && self.session_state != openxr::SessionState::READY
-        {
+        if !self.session_running() {
             return Ok(None);
         }
         let frame_state = self.frame_state.insert(self.frame_waiter.wait()?);
@@ -1588,6 +2696,9 @@ impl Vr for OpenXr {
     }
 
     fn set_display_mode(&mut self, mode: DisplayMode) -> Result<(), Self::Error> {
+        if self.display_mode != mode {
+            self.saved_capture = None;
+        }
         self.display_mode = mode;
         if let Some(projection_mode) = self.display_mode.projection_mode() {
             let camera_calib = self.load_camera_paramter();
@@ -1598,11 +2709,23 @@ impl Vr for OpenXr {
                     self.allocator.clone(),
                     self.descriptor_set_allocator.clone(),
                     self.render_texture.as_ref().unwrap(),
-                    1.0,
+                    self.overlay_size,
                     &camera_calib,
+                    self.depth_map.as_ref(),
+                    self.lens_remap.as_ref(),
+                    self.overlay_mask.as_ref(),
                     ImageLayout::ColorAttachmentOptimal,
                 )?;
                 projector.set_mode(projection_mode);
+                projector.set_overlay_radius(self.overlay_shape.radius(self.overlay_size[0]));
+                projector.set_fixed_depth(self.fixed_depth);
+                if self.out_of_view.needs_indicator() {
+                    projector.enable_indicator(
+                        self.device.clone(),
+                        self.allocator.clone(),
+                        self.descriptor_set_allocator.clone(),
+                    )?;
+                }
                 self.projector = Some(projector);
             }
         } else {
@@ -1672,6 +2795,34 @@ impl Vr for OpenXr {
         Ok(())
     }
 
+    fn reposition_extra_overlays(&mut self) {
+        self.extra_overlays.reposition();
+    }
+
+    fn set_overlay_shape(&mut self, shape: OverlayShape) -> Result<(), Self::Error> {
+        let is_cylinder = matches!(shape, OverlayShape::Cylinder { .. });
+        self.overlay_shape = if is_cylinder && !self.cylinder_supported {
+            log::warn!(
+                "Cylinder layers are not supported by the OpenXR runtime, using a flat overlay"
+            );
+            OverlayShape::Flat
+        } else {
+            shape
+        };
+        if let Some(projector) = &mut self.projector {
+            projector.set_overlay_radius(self.overlay_shape.radius(self.overlay_size[0]));
+        }
+        Ok(())
+    }
+    fn set_overlay_size(&mut self, size: [f32; 2]) -> Result<(), Self::Error> {
+        self.overlay_size = size;
+        if let Some(projector) = &mut self.projector {
+            projector.set_overlay_size(size);
+            projector.set_overlay_radius(self.overlay_shape.radius(size[0]));
+        }
+        Ok(())
+    }
+
     fn poll_next_event(&mut self) -> Result<Option<Event>, Self::Error> {
         let mut event = EventDataBuffer::default();
         let ret = loop {
@@ -1719,6 +2870,21 @@ impl Vr for OpenXr {
             .sync_actions(&[openxr::ActiveActionSet::new(&self.action_set)])?;
         Ok(())
     }
+    fn record_pose(&mut self) -> Result<(), Self::Error> {
+        if !self.session_running() {
+            return Ok(());
+        }
+        let poses = self.view_poses(self.instance.now()?)?;
+        self.pose_history.push(Instant::now(), poses);
+        Ok(())
+    }
+    fn current_hmd_transform(&mut self) -> Result<Option<Matrix4<f32>>, Self::Error> {
+        if !self.session_running() {
+            return Ok(None);
+        }
+        let poses = self.view_poses(self.instance.now()?)?;
+        Ok(Some(Self::hmd_transform(&poses)))
+    }
 
     fn get_action_state(&self, action: Action) -> Result<bool, Self::Error> {
         Ok(match action {
@@ -1732,7 +2898,29 @@ impl Vr for OpenXr {
             Action::Reposition => self
                 .action_reposition
                 .state(&self.session, openxr::Path::NULL)?,
+            Action::CycleFilter => self
+                .action_cycle_filter
+                .state(&self.session, openxr::Path::NULL)?,
         }
         .current_state)
     }
+
+    fn get_adjust_state(&self) -> Result<f32, Self::Error> {
+        let state = self
+            .action_adjust
+            .state(&self.session, openxr::Path::NULL)?;
+        Ok(if state.is_active {
+            state.current_state
+        } else {
+            0.0
+        })
+    }
+
+    fn set_fixed_depth(&mut self, distance: f32) {
+        self.fixed_depth = distance;
+        if let Some(projector) = &mut self.projector {
+            projector.set_fixed_depth(distance);
+        }
+        self.extra_overlays.set_fixed_depth(distance);
+    }
 }